@@ -0,0 +1,22 @@
+#!/bin/sh
+set -eu
+
+# Like gen_image.sh, but populates the filesystem with `mke2fs -d`, so needs neither root nor
+# loop devices. There's no partition table. Any extra arguments are passed to mke2fs.
+
+run="$1"
+output_name="$2"
+size="$3"
+shift 3
+
+D=$(mktemp -d .populating.XXXXXXXXX)
+trap 'rm -r "$D"' EXIT
+
+H=$(pwd)
+(cd "$D" && "$H/$run")
+
+rm -f "$output_name"
+E2FSPROGS_FAKE_TIME=1613672547 mke2fs -q \
+    -U 6eab9303-00e4-4d00-a85b-07aa78d99932 \
+    -E hash_seed=ec346b0c-6990-4fa1-b497-a9b06a208952 \
+    -d "$D" "$@" "$output_name" "$size"
@@ -0,0 +1,19 @@
+#!/bin/bash
+set -eux
+
+pattern() {
+    python3 -c "import sys; sys.stdout.buffer.write(bytes((i * 7 + 3) % 251 for i in range($1)))"
+}
+
+# with 1k blocks: 12 direct blocks, then 256 single indirect, then 65536 double indirect
+pattern 5000 > direct
+pattern 300000 > double-indirect
+truncate -s 70M triple-indirect
+pattern 5000 | dd of=triple-indirect bs=1024 seek=$((70 * 1024)) conv=notrunc status=none
+
+mkdir big-directory
+for i in $(seq 200); do
+    touch "big-directory/file-number-$i"
+done
+
+ln -s "$(printf 'long-target/%.0s' $(seq 10))" long-symlink
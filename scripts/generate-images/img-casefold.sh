@@ -0,0 +1,15 @@
+#!/bin/bash
+set -eu
+
+# the casefold flag is applied afterwards; `mke2fs -d` can't copy it
+mkdir small-directory big-directory case-sensitive
+touch small-directory/readme "small-directory/Ünïcödé-Straße"
+
+for i in $(seq 3000); do
+    touch "big-directory/File-Number-$i"
+done
+
+touch case-sensitive/readme
+
+# U+2C2F, from Unicode 14, which the kernel's 12.1 tables don't fold
+touch "small-directory/Ⱟ-Glagolitic" "big-directory/Ⱟ-Glagolitic"
@@ -0,0 +1,8 @@
+#!/bin/bash
+set -eu
+
+# with only 16 inodes per group, these are spread across most of the groups
+mkdir directory
+for i in $(seq 200); do
+    echo "file $i" > "directory/file-$i"
+done
@@ -0,0 +1,18 @@
+#!/bin/bash
+set -eu
+
+# 3000 directories, one inside the other; made 500 at a time, as the whole path is too long
+chunk="d$(printf '/d%.0s' $(seq 499))"
+mkdir deep
+(
+    cd deep
+    for i in $(seq 6); do
+        mkdir -p "$chunk"
+        cd "$chunk"
+    done
+    echo "bottom" > bottom
+)
+
+# entries for the tests to corrupt, into a link back to the root
+mkdir loop
+touch loop/ancestor loop/sibling
@@ -0,0 +1,7 @@
+#!/bin/bash
+set -eu
+
+# data in every other block, so each is its own extent, and they need a tree of leaves
+for i in $(seq 0 2 598); do
+    printf 'block %04d' "$i" | dd of=fragmented bs=1024 seek="$i" conv=notrunc status=none
+done
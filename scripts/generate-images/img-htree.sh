@@ -0,0 +1,9 @@
+#!/bin/bash
+set -eu
+
+# enough entries for the index to need a second level, on 1k blocks
+mkdir big-directory
+cd big-directory
+for i in $(seq 3000); do
+    touch "file-with-a-longer-name-$i" "ünïcödé-$i"
+done
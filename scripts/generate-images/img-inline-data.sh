@@ -0,0 +1,20 @@
+#!/bin/bash
+set -eux
+
+pattern() {
+    python3 -c "import sys; sys.stdout.buffer.write(bytes((i * 7 + 3) % 251 for i in range($1)))"
+}
+
+touch empty-file
+pattern 10 > in-core
+# more than the 60 bytes of the core, so continues into the xattr
+pattern 100 > in-xattr
+pattern 5000 > too-big
+
+mkdir -p tiny-directory/child
+mkdir huge-directory
+for i in $(seq 100); do
+    touch "huge-directory/file-number-$i"
+done
+
+ln -s "$(printf 'long-target/%.0s' $(seq 7))" long-symlink
@@ -0,0 +1,5 @@
+#!/bin/bash
+set -eu
+
+# the journal has a newer copy of its first block
+echo "old contents" > file
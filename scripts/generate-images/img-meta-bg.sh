@@ -0,0 +1,8 @@
+#!/bin/bash
+set -eu
+
+# with only 16 inodes per group, these end up spread through later meta groups
+mkdir directory
+for i in $(seq 400); do
+    echo "file $i" > "directory/file-$i"
+done
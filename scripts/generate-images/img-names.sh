@@ -0,0 +1,9 @@
+#!/bin/bash
+set -eu
+
+# names, and a link target, which aren't UTF-8, next to ones which are
+latin1="caf$(printf '\xe9')"
+mkdir odd-names
+echo "latin-1" > "odd-names/$latin1"
+echo "plain" > odd-names/plain
+ln -s "$latin1" odd-names/link
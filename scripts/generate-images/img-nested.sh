@@ -0,0 +1,9 @@
+#!/bin/bash
+set -eu
+
+# a whole filesystem, as a file
+mkdir inner
+echo "nested" > inner/file
+E2FSPROGS_FAKE_TIME=1613672547 mke2fs -q -t ext4 -b 1024 \
+    -U 2d1f1bb2-7d4e-4b4a-9c3e-36c2a6d54b1f -d inner inner.img 1M
+rm -r inner
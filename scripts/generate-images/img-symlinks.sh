@@ -0,0 +1,22 @@
+#!/bin/bash
+set -eu
+
+# links like a distribution's, which only make sense from the root of the filesystem
+mkdir -p usr/lib usr/bin etc/alternatives opt/java/bin
+echo "java" > opt/java/bin/java
+echo "library" > usr/lib/libc.so
+ln -s usr/lib lib
+ln -s /opt/java/bin/java etc/alternatives/java
+ln -s ../../etc/alternatives/java usr/bin/java
+ln -s /lib/libc.so libc-link
+ln -s missing dangling
+
+ln -s loop-b loop-a
+ln -s loop-a loop-b
+
+# which can't get out, when it's used as the root
+mkdir jail
+echo "inside" > jail/file
+echo "outside" > file
+ln -s / jail/escape
+ln -s ../../.. jail/up
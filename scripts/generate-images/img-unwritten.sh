@@ -0,0 +1,12 @@
+#!/bin/bash
+set -eu
+
+pattern() {
+    python3 -c "import sys; sys.stdout.buffer.write(bytes((i * 7 + 3) % 251 for i in range($1)))"
+}
+
+# deleted afterwards, so their blocks are reused by the unwritten extents, with data still in them
+pattern 40960 > stale-1
+pattern 20480 > stale-2
+
+pattern 40960 > mixed
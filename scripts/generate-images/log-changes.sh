@@ -0,0 +1,41 @@
+#!/bin/bash
+set -eu
+
+# Make changes to a copy of an image with a debugfs script, then log every block which changed in
+# the original's journal, as if the system had crashed before writing them back.
+
+image="$1"
+script="$2"
+
+D=$(mktemp -d .journalling.XXXXXXXXX)
+trap 'rm -r "$D"' EXIT
+
+cp --sparse=always "$image" "$D/changed"
+E2FSPROGS_FAKE_TIME=1613672547 debugfs -w -f "$script" "$D/changed"
+
+block_size=$(dumpe2fs -h "$image" 2>/dev/null | sed -n 's/^Block size: *//p')
+
+blocks=$(python3 - "$image" "$D/changed" "$block_size" "$D/blocks" <<'END'
+import sys
+
+before, after, size, out = sys.argv[1], sys.argv[2], int(sys.argv[3]), sys.argv[4]
+changed = []
+with open(before, 'rb') as b, open(after, 'rb') as a, open(out, 'wb') as o:
+    block = 0
+    while True:
+        old, new = b.read(size), a.read(size)
+        if not new:
+            break
+        if old != new:
+            changed.append(str(block))
+            o.write(new)
+        block += 1
+print(','.join(changed))
+END
+)
+
+E2FSPROGS_FAKE_TIME=1613672547 debugfs -w -f - "$image" <<END
+jo -c
+jw -b $blocks $D/blocks
+jc
+END
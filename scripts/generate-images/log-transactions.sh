@@ -0,0 +1,39 @@
+#!/bin/bash
+set -eu
+
+# Log transactions in an image's journal, without replaying them, as if the system had crashed.
+# Any extra arguments are passed to debugfs' journal_open.
+
+image="$1"
+shift
+
+block=$(debugfs -R 'bmap file 0' "$image" 2>/dev/null)
+
+D=$(mktemp -d .journalling.XXXXXXXXX)
+trap 'rm -r "$D"' EXIT
+
+pattern() {
+    python3 -c "import sys; sys.stdout.buffer.write(bytes((i * 7 + 3) % 251 for i in range($1)))"
+}
+
+# the file's block, a block starting with the journal's magic number, and one revoked later
+{
+    printf 'new contents\n'
+    head -c 1011 /dev/zero
+    printf '\xc0\x3b\x39\x98'
+    pattern 1020
+    pattern 1024
+} > "$D/committed"
+# debugfs only logs whole blocks
+{
+    printf 'bad contents\n'
+    head -c 1011 /dev/zero
+} > "$D/uncommitted"
+
+E2FSPROGS_FAKE_TIME=1613672547 debugfs -w -f - "$image" <<END
+jo $*
+jw -b $block,8000,8001 $D/committed
+jw -r 8001
+jw -b $block -c $D/uncommitted
+jc
+END
@@ -0,0 +1,161 @@
+use std::convert::TryFrom;
+use std::ops::Range;
+
+/// Which of a block group's inodes, or blocks, are in use.
+///
+/// Built from the group's on-disc bitmap, or from what the kernel would assume, for groups
+/// which have never had their bitmap initialised. Numbers are absolute inode or block numbers.
+#[derive(Clone, Debug)]
+pub struct AllocationBitmap {
+    /// The first number covered.
+    start: u64,
+    /// One past the last number covered.
+    end: u64,
+    /// How many numbers each bit stands for: the cluster size, in blocks, for block bitmaps.
+    unit: u64,
+    bits: Vec<u8>,
+}
+
+impl AllocationBitmap {
+    pub(crate) fn new(start: u64, end: u64, unit: u64, bits: Vec<u8>) -> AllocationBitmap {
+        debug_assert!(0 != unit && (end - start).div_ceil(unit) <= bits.len() as u64 * 8);
+        AllocationBitmap {
+            start,
+            end,
+            unit,
+            bits,
+        }
+    }
+
+    /// The inode or block numbers this bitmap describes.
+    pub fn range(&self) -> Range<u64> {
+        self.start..self.end
+    }
+
+    /// Whether `number` is in use, or `None` if it's outside of this group.
+    pub fn is_allocated(&self, number: u64) -> Option<bool> {
+        if number < self.start || number >= self.end {
+            return None;
+        }
+        Some(self.bit((number - self.start) / self.unit))
+    }
+
+    /// The runs of numbers which are in use, in order.
+    pub fn allocated(&self) -> Ranges<'_> {
+        Ranges {
+            bitmap: self,
+            next: 0,
+            wanted: true,
+        }
+    }
+
+    /// The runs of numbers which are free, in order.
+    pub fn free(&self) -> Ranges<'_> {
+        Ranges {
+            bitmap: self,
+            next: 0,
+            wanted: false,
+        }
+    }
+
+    fn bit(&self, index: u64) -> bool {
+        let byte = self.bits[usize::try_from(index / 8).expect("checked on construction")];
+        0 != byte & (1 << (index % 8))
+    }
+
+    fn bit_count(&self) -> u64 {
+        (self.end - self.start).div_ceil(self.unit)
+    }
+}
+
+/// Runs of allocated, or free, numbers in an [`AllocationBitmap`].
+pub struct Ranges<'a> {
+    bitmap: &'a AllocationBitmap,
+    next: u64,
+    wanted: bool,
+}
+
+impl<'a> Iterator for Ranges<'a> {
+    type Item = Range<u64>;
+
+    fn next(&mut self) -> Option<Range<u64>> {
+        let count = self.bitmap.bit_count();
+
+        let mut first = self.next;
+        while first < count && self.bitmap.bit(first) != self.wanted {
+            first += 1;
+        }
+
+        if first == count {
+            self.next = count;
+            return None;
+        }
+
+        let mut last = first + 1;
+        while last < count && self.bitmap.bit(last) == self.wanted {
+            last += 1;
+        }
+        self.next = last;
+
+        let start = self.bitmap.start + first * self.bitmap.unit;
+        let end = self.bitmap.start + last * self.bitmap.unit;
+        Some(start..end.min(self.bitmap.end))
+    }
+}
+
+/// Mark `index` as in use in a raw bitmap.
+pub(crate) fn set_bit(bits: &mut [u8], index: u64) {
+    if let Some(byte) = usize::try_from(index / 8)
+        .ok()
+        .and_then(|at| bits.get_mut(at))
+    {
+        *byte |= 1 << (index % 8);
+    }
+}
+
+/// Mark `index` as free in a raw bitmap.
+pub(crate) fn clear_bit(bits: &mut [u8], index: u64) {
+    if let Some(byte) = usize::try_from(index / 8)
+        .ok()
+        .and_then(|at| bits.get_mut(at))
+    {
+        *byte &= !(1 << (index % 8));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AllocationBitmap;
+
+    #[test]
+    fn ranges() {
+        // 0, 1, 2 and 9 are set, 4..8 and 10.. are clear
+        let bitmap = AllocationBitmap::new(100, 114, 1, vec![0b0000_0111, 0b0000_0010]);
+        assert_eq!(
+            vec![100..103, 109..110],
+            bitmap.allocated().collect::<Vec<_>>()
+        );
+        assert_eq!(vec![103..109, 110..114], bitmap.free().collect::<Vec<_>>());
+        assert_eq!(Some(true), bitmap.is_allocated(109));
+        assert_eq!(Some(false), bitmap.is_allocated(113));
+        assert_eq!(None, bitmap.is_allocated(114));
+        assert_eq!(None, bitmap.is_allocated(99));
+    }
+
+    #[test]
+    fn clusters() {
+        // four blocks to a cluster, with the group ending half way through the last one
+        let bitmap = AllocationBitmap::new(0, 10, 4, vec![0b0000_0101]);
+        assert_eq!(vec![0..4, 8..10], bitmap.allocated().collect::<Vec<_>>());
+        assert_eq!(vec![4..8], bitmap.free().collect::<Vec<_>>());
+        assert_eq!(Some(true), bitmap.is_allocated(9));
+        assert_eq!(Some(false), bitmap.is_allocated(7));
+    }
+
+    #[test]
+    fn empty() {
+        let bitmap = AllocationBitmap::new(1, 9, 1, vec![0]);
+        assert_eq!(0, bitmap.allocated().count());
+        assert_eq!(vec![1..9], bitmap.free().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// How well the block cache is doing, since the filesystem was opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// How many pages are held now.
+    pub pages: usize,
+}
+
+/// Pages of metadata, after decryption, evicting the least recently used beyond `capacity`.
+#[derive(Default)]
+pub(crate) struct BlockCache {
+    capacity: usize,
+    /// By address, with when each was last used.
+    pages: HashMap<u64, (u64, Vec<u8>)>,
+    /// Addresses, by when they were last used.
+    by_use: BTreeMap<u64, u64>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        0 != self.capacity
+    }
+
+    pub(crate) fn get(&mut self, address: u64) -> Option<&[u8]> {
+        if 0 == self.capacity {
+            return None;
+        }
+
+        let clock = self.tick();
+        match self.pages.get_mut(&address) {
+            Some((used, data)) => {
+                self.by_use.remove(used);
+                self.by_use.insert(clock, address);
+                *used = clock;
+                self.hits += 1;
+                Some(data)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub(crate) fn insert(&mut self, address: u64, data: &[u8]) {
+        if 0 == self.capacity {
+            return;
+        }
+
+        let clock = self.tick();
+        if let Some((used, _)) = self.pages.insert(address, (clock, data.to_vec())) {
+            self.by_use.remove(&used);
+        }
+        self.by_use.insert(clock, address);
+        self.evict();
+    }
+
+    /// Forget everything, like when what's cached was decrypted with a different key.
+    pub(crate) fn clear(&mut self) {
+        self.pages.clear();
+        self.by_use.clear();
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            pages: self.pages.len(),
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn evict(&mut self) {
+        while self.pages.len() > self.capacity {
+            let (_, address) = self.by_use.pop_first().expect("every page is in both maps");
+            self.pages.remove(&address);
+        }
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockCache, CacheStats};
+
+    #[test]
+    fn least_recently_used() {
+        let mut cache = BlockCache::default();
+        cache.insert(0, &[0]);
+        assert_eq!(None, cache.get(0));
+        assert_eq!(CacheStats::default(), cache.stats());
+
+        cache.set_capacity(2);
+        cache.insert(0, &[0]);
+        cache.insert(1, &[1]);
+        assert_eq!(Some(&[0u8][..]), cache.get(0));
+        cache.insert(2, &[2]);
+
+        // 1 was used least recently
+        assert_eq!(None, cache.get(1));
+        assert_eq!(Some(&[0u8][..]), cache.get(0));
+        assert_eq!(Some(&[2u8][..]), cache.get(2));
+        assert_eq!(
+            CacheStats {
+                hits: 3,
+                misses: 1,
+                pages: 2
+            },
+            cache.stats()
+        );
+
+        cache.set_capacity(1);
+        assert_eq!(None, cache.get(0));
+        assert_eq!(Some(&[2u8][..]), cache.get(2));
+    }
+}
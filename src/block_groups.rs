@@ -1,3 +1,5 @@
+use std::cmp::min;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io;
 
@@ -5,144 +7,472 @@ use anyhow::ensure;
 use anyhow::Error;
 use byteorder::{LittleEndian, ReadBytesExt};
 
+use crate::allocation::{clear_bit, set_bit, AllocationBitmap};
 use crate::assumption_failed;
+use crate::diagnostics::{Diagnostic, Diagnostics, Structure};
+use crate::error::{Locate, Location};
 use crate::not_found;
+use crate::parse::{ext4_style_crc16, ext4_style_crc32c_le};
+use crate::parse::{CompatibleFeature, CompatibleFeatureReadOnly, IncompatibleFeature};
+use crate::read_le16;
+use crate::{InnerReader, MetadataCrypto, ReadAt, SuperBlockInfo};
 
 const EXT4_BLOCK_GROUP_INODES_UNUSED: u16 = 0b1;
 const EXT4_BLOCK_GROUP_BLOCKS_UNUSED: u16 = 0b10;
 
+/// Where `bg_checksum` is, in each descriptor.
+const CHECKSUM_OFFSET: usize = 0x1E;
+
+/// How the descriptors, and the bitmaps, are protected.
 #[derive(Debug)]
+enum Checksums {
+    None,
+    /// `GDT_CSUM`: a crc16 of the uuid, then each descriptor.
+    Crc16 {
+        uuid: [u8; 16],
+    },
+    /// `METADATA_CSUM`: crc32c, seeded with the filesystem's usual checksum seed.
+    Crc32c {
+        seed: u32,
+    },
+}
+
+impl Checksums {
+    /// The on-disc and computed checksums of a descriptor, if they don't match.
+    fn descriptor_mismatch(&self, raw: &[u8], group: u32) -> Option<(u16, u16)> {
+        let le_group = group.to_le_bytes();
+        let expected = read_le16(&raw[CHECKSUM_OFFSET..]);
+        let after_checksum = &raw[CHECKSUM_OFFSET + 2..];
+
+        let computed = match self {
+            Checksums::None => return None,
+            Checksums::Crc16 { uuid } => {
+                let crc = ext4_style_crc16(!0, uuid);
+                let crc = ext4_style_crc16(crc, &le_group);
+                let crc = ext4_style_crc16(crc, &raw[..CHECKSUM_OFFSET]);
+                // only present (and covered) for 64-bit filesystems, which can have long descriptors
+                ext4_style_crc16(crc, after_checksum)
+            }
+            Checksums::Crc32c { seed } => {
+                let crc = ext4_style_crc32c_le(*seed, &le_group);
+                let crc = ext4_style_crc32c_le(crc, &raw[..CHECKSUM_OFFSET]);
+                let crc = ext4_style_crc32c_le(crc, &[0, 0]);
+                (ext4_style_crc32c_le(crc, after_checksum) & 0xFFFF) as u16
+            }
+        };
+
+        if computed != expected {
+            Some((expected, computed))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 struct Entry {
     inode_table_block: u64,
     max_inode_number: u32,
+    block_bitmap_block: u64,
+    inode_bitmap_block: u64,
+    flags: u16,
+    /// Inodes at the end of the table which have never been used; only trusted with checksums.
+    itable_unused: u32,
+    block_bitmap_checksum: u32,
+    inode_bitmap_checksum: u32,
+    /// Both bitmap checksums are cut down to 16 bits in short descriptors.
+    short_bitmap_checksums: bool,
+}
+
+impl Entry {
+    fn parse(raw: &[u8], s_inodes_per_group: u32) -> Result<Entry, Error> {
+        let mut inner = io::Cursor::new(raw);
+
+        let bg_block_bitmap_lo = inner.read_u32::<LittleEndian>()?; /* Blocks bitmap block */
+        let bg_inode_bitmap_lo = inner.read_u32::<LittleEndian>()?; /* Inodes bitmap block */
+        let bg_inode_table_lo = inner.read_u32::<LittleEndian>()?; /* Inodes table block */
+        //            let bg_free_blocks_count_lo =
+        inner.read_u16::<LittleEndian>()?; /* Free blocks count */
+        let bg_free_inodes_count_lo = inner.read_u16::<LittleEndian>()?; /* Free inodes count */
+        //            let bg_used_dirs_count_lo =
+        inner.read_u16::<LittleEndian>()?; /* Directories count */
+        let bg_flags = inner.read_u16::<LittleEndian>()?; /* EXT4_BG_flags (INODE_UNINIT, etc) */
+        //            let bg_exclude_bitmap_lo =
+        inner.read_u32::<LittleEndian>()?; /* Exclude bitmap for snapshots */
+        let bg_block_bitmap_csum_lo = inner.read_u16::<LittleEndian>()?; /* crc32c(s_uuid+grp_num+bbitmap) LE */
+        let bg_inode_bitmap_csum_lo = inner.read_u16::<LittleEndian>()?; /* crc32c(s_uuid+grp_num+ibitmap) LE */
+        let bg_itable_unused_lo = inner.read_u16::<LittleEndian>()?; /* Unused inodes count */
+        //            let bg_checksum =
+        inner.read_u16::<LittleEndian>()?; /* crc16(sb_uuid+group+desc) */
+
+        let long = raw.len() >= 64;
+
+        let mut hi = [0u32; 3];
+        let mut bg_free_inodes_count_hi = 0;
+        let mut bg_itable_unused_hi = 0;
+        let mut bg_block_bitmap_csum_hi = 0;
+        let mut bg_inode_bitmap_csum_hi = 0;
+
+        if long {
+            // Blocks bitmap block MSB, Inodes bitmap block MSB, Inodes table block MSB
+            inner.read_u32_into::<LittleEndian>(&mut hi)?;
+
+            //            let bg_free_blocks_count_hi =
+            inner.read_u16::<LittleEndian>()?; /* Free blocks count MSB */
+
+            bg_free_inodes_count_hi = inner.read_u16::<LittleEndian>()?; /* Free inodes count MSB */
+
+            //          let bg_used_dirs_count_hi =
+            inner.read_u16::<LittleEndian>()?; /* Directories count MSB */
+            bg_itable_unused_hi = inner.read_u16::<LittleEndian>()?; /* Unused inodes count MSB */
+            //          let bg_exclude_bitmap_hi =
+            inner.read_u32::<LittleEndian>()?; /* Exclude bitmap block MSB */
+            bg_block_bitmap_csum_hi = inner.read_u16::<LittleEndian>()?; /* crc32c(s_uuid+grp_num+bbitmap) BE */
+            bg_inode_bitmap_csum_hi = inner.read_u16::<LittleEndian>()?; /* crc32c(s_uuid+grp_num+ibitmap) BE */
+        }
+
+        let [bg_block_bitmap_hi, bg_inode_bitmap_hi, bg_inode_table_hi] = hi;
+
+        let inode_table_block =
+            u64::from(bg_inode_table_lo) | ((u64::from(bg_inode_table_hi)) << 32);
+        let free_inodes_count =
+            u32::from(bg_free_inodes_count_lo) | ((u32::from(bg_free_inodes_count_hi)) << 16);
+
+        // an uninitialised block bitmap says nothing about the inodes
+        let unallocated = bg_flags & EXT4_BLOCK_GROUP_INODES_UNUSED != 0;
+
+        if free_inodes_count > s_inodes_per_group {
+            return Err(crate::parse_error(format!(
+                "too many free inodes: {} > {}",
+                free_inodes_count, s_inodes_per_group
+            )));
+        }
+
+        let max_inode_number = if unallocated {
+            0
+        } else {
+            // can't use free inodes here, as there can be unallocated ranges in the middle;
+            // the bitmap knows, see `inode_allocation`, but the table can be read regardless
+            s_inodes_per_group
+        };
+
+        Ok(Entry {
+            inode_table_block,
+            max_inode_number,
+            block_bitmap_block: u64::from(bg_block_bitmap_lo)
+                | (u64::from(bg_block_bitmap_hi) << 32),
+            inode_bitmap_block: u64::from(bg_inode_bitmap_lo)
+                | (u64::from(bg_inode_bitmap_hi) << 32),
+            flags: bg_flags,
+            itable_unused: u32::from(bg_itable_unused_lo) | (u32::from(bg_itable_unused_hi) << 16),
+            block_bitmap_checksum: u32::from(bg_block_bitmap_csum_lo)
+                | (u32::from(bg_block_bitmap_csum_hi) << 16),
+            inode_bitmap_checksum: u32::from(bg_inode_bitmap_csum_lo)
+                | (u32::from(bg_inode_bitmap_csum_hi) << 16),
+            short_bitmap_checksums: !long,
+        })
+    }
+
+    /// Which of the blocks this points to is past the end of the filesystem, if any.
+    fn outside(&self, blocks_count: u64) -> Option<String> {
+        [
+            ("block bitmap", self.block_bitmap_block),
+            ("inode bitmap", self.inode_bitmap_block),
+            ("inode table", self.inode_table_block),
+        ]
+        .iter()
+        .find(|(_, block)| *block >= blocks_count)
+        .map(|(what, block)| {
+            format!(
+                "{} is at block {}, past the end of the filesystem, {}",
+                what, block, blocks_count
+            )
+        })
+    }
+}
+
+/// Where the group descriptors are.
+#[derive(Debug)]
+struct Layout {
+    groups_count: u64,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    block_size: u32,
+    desc_size: usize,
+    descriptors_per_block: u64,
+    /// With `META_BG`, the first descriptor block which is in a meta group.
+    first_meta_bg: Option<u64>,
+    /// Blocks after the descriptor table, kept for it to grow into, without `META_BG`.
+    reserved_gdt_blocks: u64,
+    backups: Backups,
+}
+
+/// Which groups start with a copy of the superblock.
+#[derive(Debug)]
+enum Backups {
+    Everywhere,
+    /// Group 0, 1, and powers of 3, 5 and 7.
+    Sparse,
+    /// Group 0, and the (up to) two listed groups.
+    Listed([u32; 2]),
+}
+
+impl Layout {
+    fn new(info: &SuperBlockInfo) -> Result<Layout, Error> {
+        ensure!(
+            0 != info.blocks_per_group && info.blocks_count > u64::from(info.first_data_block),
+            assumption_failed(format!(
+                "invalid group size: {} blocks, of {}, from {}",
+                info.blocks_per_group, info.blocks_count, info.first_data_block
+            ))
+        );
+
+        let desc_size = if info
+            .incompatible_features
+            .contains(IncompatibleFeature::SIXTY_FOUR_BIT)
+        {
+            usize::from(info.desc_size)
+        } else {
+            32
+        };
+
+        ensure!(
+            desc_size >= 32
+                && desc_size.is_power_of_two()
+                && desc_size <= usize::try_from(info.block_size)?,
+            assumption_failed(format!("invalid group descriptor size: {}", desc_size))
+        );
+
+        let first_data_block = u64::from(info.first_data_block);
+
+        Ok(Layout {
+            groups_count: (info.blocks_count - first_data_block)
+                .div_ceil(u64::from(info.blocks_per_group)),
+            first_data_block,
+            blocks_per_group: u64::from(info.blocks_per_group),
+            block_size: info.block_size,
+            desc_size,
+            descriptors_per_block: u64::from(info.block_size) / u64::try_from(desc_size)?,
+            first_meta_bg: if info
+                .incompatible_features
+                .contains(IncompatibleFeature::META_BG)
+            {
+                Some(u64::from(info.first_meta_bg))
+            } else {
+                None
+            },
+            reserved_gdt_blocks: u64::from(info.reserved_gdt_blocks),
+            backups: if info
+                .compatible_features
+                .contains(CompatibleFeature::SPARSE_SUPER2)
+            {
+                Backups::Listed(info.backup_block_groups)
+            } else if info
+                .compatible_features_read_only
+                .contains(CompatibleFeatureReadOnly::SPARSE_SUPER)
+            {
+                Backups::Sparse
+            } else {
+                Backups::Everywhere
+            },
+        })
+    }
+
+    /// The block holding the `nr`th block's worth of group descriptors.
+    fn descriptor_block(&self, nr: u64) -> u64 {
+        let in_meta_group = matches!(self.first_meta_bg, Some(first) if nr >= first);
+        if !in_meta_group {
+            // a contiguous table, straight after the superblock, which is always 1024 bytes in,
+            // even when `first_data_block` says the first group starts before it
+            let superblock = 1024 / u64::from(self.block_size);
+            return superblock + nr + 1;
+        }
+
+        // With META_BG, groups are split into "meta groups", each with as many groups as have
+        // their descriptors fit in one block. The first group of each holds that block, after
+        // the superblock backup, if it has one.
+        let group = self.descriptors_per_block * nr;
+        let mut has_super = u64::from(self.has_superblock(group));
+
+        // the superblock is in the first block, but with 1k blocks, that's a different block
+        if 1024 == self.block_size && 0 == nr && 0 == self.first_data_block {
+            has_super += 1;
+        }
+
+        self.first_data_block + group * self.blocks_per_group + has_super
+    }
+
+    /// How many blocks at the start of `group` hold the superblock and descriptors, or
+    /// their backups; like the kernel's `ext4_num_base_meta_blocks`.
+    fn metadata_blocks(&self, group: u64) -> u64 {
+        let has_super = u64::from(self.has_superblock(group));
+        let descriptor_blocks = self.groups_count.div_ceil(self.descriptors_per_block);
+
+        match self.first_meta_bg {
+            Some(first) if group >= first * self.descriptors_per_block => {
+                // the first, second and last groups of a meta group have its descriptors
+                let index = group % self.descriptors_per_block;
+                let has_descriptors =
+                    0 == index || 1 == index || self.descriptors_per_block - 1 == index;
+                has_super + u64::from(has_descriptors)
+            }
+            Some(first) if 0 != has_super => 1 + first + self.reserved_gdt_blocks,
+            None if 0 != has_super => 1 + descriptor_blocks + self.reserved_gdt_blocks,
+            _ => 0,
+        }
+    }
+
+    fn has_superblock(&self, group: u64) -> bool {
+        if 0 == group {
+            return true;
+        }
+
+        match self.backups {
+            Backups::Everywhere => true,
+            Backups::Sparse => {
+                1 == group
+                    || (!group.is_multiple_of(2)
+                        && (is_power_of(group, 3)
+                            || is_power_of(group, 5)
+                            || is_power_of(group, 7)))
+            }
+            Backups::Listed(listed) => listed.iter().any(|&backup| u64::from(backup) == group),
+        }
+    }
+}
+
+fn is_power_of(mut num: u64, base: u64) -> bool {
+    while num > 1 && num.is_multiple_of(base) {
+        num /= base;
+    }
+    1 == num
 }
 
 #[derive(Debug)]
 pub struct BlockGroups {
-    groups: Vec<Entry>,
+    /// Descriptors, a block's worth at a time, by which block of the table they're from.
+    /// Loaded as they're needed, so only ever as many as are used.
+    loaded: HashMap<u64, Vec<Entry>>,
+    layout: Layout,
+    checksums: Checksums,
+    verify_descriptors: bool,
+    verify_bitmaps: bool,
+    diagnostics: Diagnostics,
+    blocks_count: u64,
     inodes_per_group: u32,
+    clusters_per_group: u32,
     pub block_size: u32,
     pub inode_size: u16,
 }
 
 impl BlockGroups {
-    pub fn new<R>(
-        mut inner: R,
-        blocks_count: u64,
-        s_desc_size: u16,
-        s_inodes_per_group: u32,
-        block_size: u32,
-        inode_size: u16,
-        long_structs: bool,
-    ) -> Result<BlockGroups, Error>
-    where
-        R: io::Read + io::Seek,
-    {
-        let blocks_count = usize::try_from(blocks_count)?;
-
-        let mut groups = Vec::with_capacity(blocks_count);
-
-        for block in 0..blocks_count {
-            //            let bg_block_bitmap_lo =
-            inner.read_u32::<LittleEndian>()?; /* Blocks bitmap block */
-            //            let bg_inode_bitmap_lo =
-            inner.read_u32::<LittleEndian>()?; /* Inodes bitmap block */
-            let bg_inode_table_lo = inner.read_u32::<LittleEndian>()?; /* Inodes table block */
-            //            let bg_free_blocks_count_lo =
-            inner.read_u16::<LittleEndian>()?; /* Free blocks count */
-            let bg_free_inodes_count_lo = inner.read_u16::<LittleEndian>()?; /* Free inodes count */
-            //            let bg_used_dirs_count_lo =
-            inner.read_u16::<LittleEndian>()?; /* Directories count */
-            let bg_flags = inner.read_u16::<LittleEndian>()?; /* EXT4_BG_flags (INODE_UNINIT, etc) */
-            //            let bg_exclude_bitmap_lo =
-            inner.read_u32::<LittleEndian>()?; /* Exclude bitmap for snapshots */
-            //            let bg_block_bitmap_csum_lo =
-            inner.read_u16::<LittleEndian>()?; /* crc32c(s_uuid+grp_num+bbitmap) LE */
-            //            let bg_inode_bitmap_csum_lo =
-            inner.read_u16::<LittleEndian>()?; /* crc32c(s_uuid+grp_num+ibitmap) LE */
-            //            let bg_itable_unused_lo =
-            inner.read_u16::<LittleEndian>()?; /* Unused inodes count */
-            //            let bg_checksum =
-            inner.read_u16::<LittleEndian>()?; /* crc16(sb_uuid+group+desc) */
-
-            let (bg_inode_table_hi, bg_free_inodes_count_hi) = if long_structs && s_desc_size > 32 {
-                //            let bg_block_bitmap_hi =
-                inner.read_u32::<LittleEndian>()?; /* Blocks bitmap block MSB */
-
-                //            let bg_inode_bitmap_hi =
-                inner.read_u32::<LittleEndian>()?; /* Inodes bitmap block MSB */
-
-                let bg_inode_table_hi = inner.read_u32::<LittleEndian>()?; /* Inodes table block MSB */
-
-                //            let bg_free_blocks_count_hi =
-                inner.read_u16::<LittleEndian>()?; /* Free blocks count MSB */
-
-                let bg_free_inodes_count_hi = inner.read_u16::<LittleEndian>()?; /* Free inodes count MSB */
-
-                (bg_inode_table_hi, bg_free_inodes_count_hi)
-
-                //          let bg_used_dirs_count_hi =
-                //              inner.read_u16::<LittleEndian>()?; /* Directories count MSB */
-                //          let bg_itable_unused_hi =
-                //              inner.read_u16::<LittleEndian>()?; /* Unused inodes count MSB */
-                //          let bg_exclude_bitmap_hi =
-                //              inner.read_u32::<LittleEndian>()?; /* Exclude bitmap block MSB */
-                //          let bg_block_bitmap_csum_hi =
-                //              inner.read_u16::<LittleEndian>()?; /* crc32c(s_uuid+grp_num+bbitmap) BE */
-                //          let bg_inode_bitmap_csum_hi =
-                //              inner.read_u16::<LittleEndian>()?; /* crc32c(s_uuid+grp_num+ibitmap) BE */
-            } else {
-                (0, 0)
-            };
-
-            if s_desc_size > 16 + 32 {
-                inner.seek(io::SeekFrom::Current(i64::from(s_desc_size - 32 - 16)))?;
+    /// Find the group descriptors. Only the first block of them is read now, which has the
+    /// root's group; the rest are read when something in their groups is.
+    pub fn new<R: ReadAt, M: MetadataCrypto>(
+        reader: &mut InnerReader<R, M>,
+        info: &SuperBlockInfo,
+        uuid_checksum: Option<u32>,
+        options: &crate::Options,
+        diagnostics: &Diagnostics,
+    ) -> Result<BlockGroups, Error> {
+        let layout = Layout::new(info)?;
+        let checksums = match uuid_checksum {
+            Some(seed) => Checksums::Crc32c { seed },
+            None if info
+                .compatible_features_read_only
+                .contains(CompatibleFeatureReadOnly::GDT_CSUM) =>
+            {
+                Checksums::Crc16 { uuid: info.uuid }
             }
+            None => Checksums::None,
+        };
+
+        // the count came from a u32 in the superblock, divided down
+        ensure!(
+            layout.groups_count <= u64::from(u32::MAX),
+            assumption_failed(format!("too many groups: {}", layout.groups_count))
+        );
 
-            let inode_table_block =
-                u64::from(bg_inode_table_lo) | ((u64::from(bg_inode_table_hi)) << 32);
-            let free_inodes_count =
-                u32::from(bg_free_inodes_count_lo) | ((u32::from(bg_free_inodes_count_hi)) << 16);
+        let mut groups = BlockGroups {
+            loaded: HashMap::new(),
+            layout,
+            checksums,
+            verify_descriptors: options.verify_group_descriptor_checksums,
+            verify_bitmaps: options.verify_bitmap_checksums,
+            diagnostics: diagnostics.clone(),
+            blocks_count: info.blocks_count,
+            inodes_per_group: info.inodes_per_group,
+            clusters_per_group: info.clusters_per_group,
+            block_size: info.block_size,
+            inode_size: info.inode_size,
+        };
+        groups.entry(reader, 0)?;
+        Ok(groups)
+    }
 
-            let unallocated = bg_flags & EXT4_BLOCK_GROUP_INODES_UNUSED != 0
-                || bg_flags & EXT4_BLOCK_GROUP_BLOCKS_UNUSED != 0;
+    /// Read, and check, the `nr`th block of descriptors; all of them, or none.
+    fn load<R: ReadAt, M: MetadataCrypto>(
+        &self,
+        reader: &mut InnerReader<R, M>,
+        nr: u64,
+    ) -> Result<Vec<Entry>, Error> {
+        let layout = &self.layout;
+        let location = layout.descriptor_block(nr);
+        let mut block = vec![0u8; usize::try_from(self.block_size)?];
+        reader.read_exact_at(location * u64::from(self.block_size), &mut block)?;
 
-            if free_inodes_count > s_inodes_per_group {
-                return Err(crate::parse_error(format!(
-                    "too many free inodes in group {}: {} > {}",
-                    block, free_inodes_count, s_inodes_per_group
-                )));
-            }
+        let first = nr * layout.descriptors_per_block;
+        let count = min(layout.descriptors_per_block, layout.groups_count - first);
 
-            let max_inode_number = if unallocated {
-                0
-            } else {
-                // can't use free inodes here, as there can be unallocated ranges in the middle;
-                // would have to parse the bitmap to work that out and it doesn't seem worth
-                // the effort
-                s_inodes_per_group
+        let mut entries = Vec::with_capacity(usize::try_from(count)?);
+        for (raw, group) in block.chunks(layout.desc_size).zip(first..first + count) {
+            let group = u32::try_from(group)?;
+            let locate = || {
+                let message = format!("group {}, in block {}", group, location);
+                (Location::block(location), message)
             };
+            let verified = match self.checksums.descriptor_mismatch(raw, group) {
+                Some((on_disc, computed)) => {
+                    let diagnostic = Diagnostic::new(
+                        Structure::GroupDescriptor,
+                        u32::from(on_disc),
+                        u32::from(computed),
+                    )
+                    .group(group)
+                    .block(location);
+                    self.diagnostics.record(self.verify_descriptors, diagnostic)
+                }
+                None => Ok(()),
+            };
+            let entry = verified
+                .and_then(|()| Entry::parse(raw, self.inodes_per_group))
+                .locate(locate)?;
 
-            groups.push(Entry {
-                inode_table_block,
-                max_inode_number,
-            });
+            // kept, so only what's in this group fails, when it's used
+            if let Some(reason) = entry.outside(self.blocks_count) {
+                let diagnostic = Diagnostic::inconsistent(Structure::GroupDescriptor, reason)
+                    .group(group)
+                    .block(location);
+                self.diagnostics
+                    .record(self.verify_descriptors, diagnostic)
+                    .locate(locate)?;
+            }
+            entries.push(entry);
         }
 
-        Ok(BlockGroups {
-            groups,
-            inodes_per_group: s_inodes_per_group,
-            block_size,
-            inode_size,
-        })
+        Ok(entries)
     }
 
-    pub fn index_of(&self, inode: u32) -> Result<u64, Error> {
+    pub fn index_of<R: ReadAt, M: MetadataCrypto>(
+        &mut self,
+        reader: &mut InnerReader<R, M>,
+        inode: u32,
+    ) -> Result<u64, Error> {
         ensure!(0 != inode, not_found("there is no inode zero"));
 
         let inode = inode - 1;
         let group_number = inode / self.inodes_per_group;
-        let group = &self.groups[usize::try_from(group_number)?];
+        let group = self.entry(reader, group_number)?;
         let inode_index_in_group = inode % self.inodes_per_group;
         ensure!(
             inode_index_in_group < group.max_inode_number,
@@ -155,7 +485,332 @@ impl BlockGroups {
             ))
         );
         let block = group.inode_table_block;
+        ensure!(
+            block < self.blocks_count,
+            assumption_failed(format!(
+                "inode table for group {} is past the end of the filesystem, at block {}",
+                group_number, block
+            ))
+        );
         Ok(block * u64::from(self.block_size)
             + u64::from(inode_index_in_group) * u64::from(self.inode_size))
     }
+
+    /// Read, and check, the inode bitmap for a group; `None` if the group's is uninitialised.
+    pub fn inode_bitmap<R: ReadAt, M: MetadataCrypto>(
+        &mut self,
+        reader: &mut InnerReader<R, M>,
+        group: u32,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let entry = self.entry(reader, group)?;
+        if EXT4_BLOCK_GROUP_INODES_UNUSED & entry.flags != 0 {
+            return Ok(None);
+        }
+
+        self.bitmap(
+            reader,
+            group,
+            entry.inode_bitmap_block,
+            self.inodes_per_group,
+            entry.inode_bitmap_checksum,
+            entry.short_bitmap_checksums,
+        )
+        .map(Some)
+        .locate(|| {
+            let message = format!("inode bitmap for group {}", group);
+            (Location::block(entry.inode_bitmap_block), message)
+        })
+    }
+
+    /// Read, and check, the block (cluster) bitmap for a group; `None` if it's uninitialised.
+    pub fn block_bitmap<R: ReadAt, M: MetadataCrypto>(
+        &mut self,
+        reader: &mut InnerReader<R, M>,
+        group: u32,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let entry = self.entry(reader, group)?;
+        if EXT4_BLOCK_GROUP_BLOCKS_UNUSED & entry.flags != 0 {
+            return Ok(None);
+        }
+
+        self.bitmap(
+            reader,
+            group,
+            entry.block_bitmap_block,
+            self.clusters_per_group,
+            entry.block_bitmap_checksum,
+            entry.short_bitmap_checksums,
+        )
+        .map(Some)
+        .locate(|| {
+            let message = format!("block bitmap for group {}", group);
+            (Location::block(entry.block_bitmap_block), message)
+        })
+    }
+
+    /// Which inodes in the group are in use. Inodes in uninitialised groups, or past
+    /// `bg_itable_unused`, are free, whatever the bitmap block says.
+    pub fn inode_allocation<R: ReadAt, M: MetadataCrypto>(
+        &mut self,
+        reader: &mut InnerReader<R, M>,
+        group: u32,
+    ) -> Result<AllocationBitmap, Error> {
+        let entry = self.entry(reader, group)?;
+        let inodes = u64::from(self.inodes_per_group);
+        let mut bits = match self.inode_bitmap(reader, group)? {
+            Some(bits) => bits,
+            None => vec![0; self.bitmap_len(self.inodes_per_group)?],
+        };
+
+        // the unused count is only maintained alongside the group checksums
+        if !matches!(self.checksums, Checksums::None) {
+            let unused = u64::from(entry.itable_unused).min(inodes);
+            for index in inodes - unused..inodes {
+                clear_bit(&mut bits, index);
+            }
+        }
+
+        let start = u64::from(group) * inodes + 1;
+        Ok(AllocationBitmap::new(start, start + inodes, 1, bits))
+    }
+
+    /// Which blocks in the group are in use. For groups whose block bitmap was never
+    /// initialised, that's the group's own metadata, which the kernel would mark when it
+    /// first allocated from it.
+    pub fn block_allocation<R: ReadAt, M: MetadataCrypto>(
+        &mut self,
+        reader: &mut InnerReader<R, M>,
+        group: u32,
+    ) -> Result<AllocationBitmap, Error> {
+        let entry = self.entry(reader, group)?;
+        let blocks_per_group = self.layout.blocks_per_group;
+        let clusters_per_group = u64::from(self.clusters_per_group);
+        ensure!(
+            0 != clusters_per_group && blocks_per_group.is_multiple_of(clusters_per_group),
+            assumption_failed(format!(
+                "{} blocks per group don't make {} clusters",
+                blocks_per_group, clusters_per_group
+            ))
+        );
+        let cluster = blocks_per_group / clusters_per_group;
+
+        let start = self.layout.first_data_block + u64::from(group) * blocks_per_group;
+        let end = (start + blocks_per_group).min(self.blocks_count);
+
+        let bits = match self.block_bitmap(reader, group)? {
+            Some(bits) => bits,
+            None => {
+                let mut bits = vec![0; self.bitmap_len(self.clusters_per_group)?];
+                let mut mark = |block: u64| {
+                    if block >= start && block < end {
+                        set_bit(&mut bits, (block - start) / cluster);
+                    }
+                };
+
+                for block in start..start + self.layout.metadata_blocks(u64::from(group)) {
+                    mark(block);
+                }
+                mark(entry.block_bitmap_block);
+                mark(entry.inode_bitmap_block);
+
+                let table_bytes = u64::from(self.inodes_per_group) * u64::from(self.inode_size);
+                let table_blocks = table_bytes.div_ceil(u64::from(self.block_size));
+                for block in entry.inode_table_block..entry.inode_table_block + table_blocks {
+                    mark(block);
+                }
+
+                bits
+            }
+        };
+
+        Ok(AllocationBitmap::new(start, end, cluster, bits))
+    }
+
+    pub fn groups_count(&self) -> u32 {
+        // checked when opening
+        self.layout.groups_count as u32
+    }
+
+    /// The descriptor for `group`, reading its block of them, if it hasn't been already.
+    fn entry<R: ReadAt, M: MetadataCrypto>(
+        &mut self,
+        reader: &mut InnerReader<R, M>,
+        group: u32,
+    ) -> Result<Entry, Error> {
+        ensure!(
+            u64::from(group) < self.layout.groups_count,
+            not_found(format!(
+                "there is no group {}, only {}",
+                group, self.layout.groups_count
+            ))
+        );
+
+        let nr = u64::from(group) / self.layout.descriptors_per_block;
+        if !self.loaded.contains_key(&nr) {
+            let entries = self.load(reader, nr)?;
+            self.loaded.insert(nr, entries);
+        }
+
+        let index = u64::from(group) % self.layout.descriptors_per_block;
+        Ok(self.loaded[&nr][usize::try_from(index)?])
+    }
+
+    fn bitmap<R: ReadAt, M: MetadataCrypto>(
+        &self,
+        reader: &mut InnerReader<R, M>,
+        group: u32,
+        block: u64,
+        bits: u32,
+        expected: u32,
+        short_checksum: bool,
+    ) -> Result<Vec<u8>, Error> {
+        ensure!(
+            0 != block && block < self.blocks_count,
+            assumption_failed(format!("bitmap can't be in block {}", block))
+        );
+
+        let mut bitmap = vec![0u8; self.bitmap_len(bits)?];
+        reader.read_exact_at(block * u64::from(self.block_size), &mut bitmap)?;
+
+        if let Checksums::Crc32c { seed } = self.checksums {
+            let mut computed = ext4_style_crc32c_le(seed, &bitmap);
+            let mut expected = expected;
+            if short_checksum {
+                computed &= 0xFFFF;
+                expected &= 0xFFFF;
+            }
+
+            if computed != expected {
+                let diagnostic = Diagnostic::new(Structure::Bitmap, expected, computed)
+                    .group(group)
+                    .block(block);
+                self.diagnostics.record(self.verify_bitmaps, diagnostic)?;
+            }
+        }
+
+        Ok(bitmap)
+    }
+
+    fn bitmap_len(&self, bits: u32) -> Result<usize, Error> {
+        ensure!(
+            bits.is_multiple_of(8) && bits / 8 <= self.block_size,
+            assumption_failed(format!("invalid bitmap size: {} bits", bits))
+        );
+        Ok(usize::try_from(bits / 8)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backups, Entry, Layout};
+    use super::{EXT4_BLOCK_GROUP_BLOCKS_UNUSED, EXT4_BLOCK_GROUP_INODES_UNUSED};
+
+    fn layout(block_size: u32, first_meta_bg: Option<u64>) -> Layout {
+        let first_data_block = if 1024 == block_size { 1 } else { 0 };
+        Layout {
+            groups_count: 64,
+            first_data_block,
+            blocks_per_group: u64::from(block_size) * 8,
+            block_size,
+            desc_size: 64,
+            descriptors_per_block: u64::from(block_size) / 64,
+            first_meta_bg,
+            reserved_gdt_blocks: 0,
+            backups: Backups::Sparse,
+        }
+    }
+
+    #[test]
+    fn contiguous() {
+        assert_eq!(2, layout(1024, None).descriptor_block(0));
+        assert_eq!(5, layout(1024, None).descriptor_block(3));
+        assert_eq!(1, layout(4096, None).descriptor_block(0));
+        assert_eq!(4, layout(4096, None).descriptor_block(3));
+    }
+
+    #[test]
+    fn meta_groups() {
+        // sixteen groups of 8192 blocks per meta group
+        let small = layout(1024, Some(0));
+        assert_eq!(2, small.descriptor_block(0));
+        assert_eq!(1 + 16 * 8192, small.descriptor_block(1));
+
+        // group 3 * 16 has a backup superblock, so the descriptors are after it
+        let mut odd = layout(1024, Some(0));
+        odd.descriptors_per_block = 3;
+        assert_eq!(1 + 3 * 8192 + 1, odd.descriptor_block(1));
+        assert_eq!(1 + 6 * 8192, odd.descriptor_block(2));
+
+        // grown beyond the first descriptor block
+        let grown = layout(4096, Some(2));
+        assert_eq!(1, grown.descriptor_block(0));
+        assert_eq!(2, grown.descriptor_block(1));
+        assert_eq!(2 * 64 * 32768, grown.descriptor_block(2));
+    }
+
+    #[test]
+    fn one_k_blocks_starting_at_zero() {
+        let mut bigalloc = layout(1024, Some(0));
+        bigalloc.first_data_block = 0;
+        assert_eq!(2, bigalloc.descriptor_block(0));
+
+        let mut contiguous = layout(1024, None);
+        contiguous.first_data_block = 0;
+        assert_eq!(2, contiguous.descriptor_block(0));
+        assert_eq!(5, contiguous.descriptor_block(3));
+    }
+
+    #[test]
+    fn metadata_blocks() {
+        // one block of descriptors, then the reserved blocks
+        let mut plain = layout(4096, None);
+        plain.reserved_gdt_blocks = 15;
+        assert_eq!(17, plain.metadata_blocks(0));
+        assert_eq!(17, plain.metadata_blocks(3));
+        assert_eq!(0, plain.metadata_blocks(4));
+
+        // 64 groups per meta group, only the first of which has more than one descriptor block
+        let meta = layout(4096, Some(0));
+        assert_eq!(2, meta.metadata_blocks(0));
+        assert_eq!(2, meta.metadata_blocks(1));
+        assert_eq!(0, meta.metadata_blocks(2));
+        assert_eq!(1, meta.metadata_blocks(5));
+        assert_eq!(1, meta.metadata_blocks(63));
+        assert_eq!(1, meta.metadata_blocks(64));
+    }
+
+    #[test]
+    fn uninitialised_groups() {
+        // a short descriptor, with just the flags set
+        let descriptor = |flags: u16| {
+            let mut raw = [0u8; 32];
+            raw[0x12..0x14].copy_from_slice(&flags.to_le_bytes());
+            Entry::parse(&raw, 128).unwrap()
+        };
+
+        assert_eq!(128, descriptor(0).max_inode_number);
+        let inodes_unused = descriptor(EXT4_BLOCK_GROUP_INODES_UNUSED);
+        assert_eq!(0, inodes_unused.max_inode_number);
+
+        // the blocks were never allocated from, but the inodes still can have been
+        let blocks_unused = descriptor(EXT4_BLOCK_GROUP_BLOCKS_UNUSED);
+        assert_eq!(128, blocks_unused.max_inode_number);
+    }
+
+    #[test]
+    fn backups() {
+        let sparse = layout(4096, None);
+        for group in &[0, 1, 3, 5, 7, 9, 25, 27, 49, 125, 343] {
+            assert!(sparse.has_superblock(*group), "{}", group);
+        }
+        for group in &[2, 4, 6, 15, 21, 35, 63] {
+            assert!(!sparse.has_superblock(*group), "{}", group);
+        }
+
+        let mut listed = layout(4096, None);
+        listed.backups = Backups::Listed([1, 40]);
+        assert!(listed.has_superblock(0));
+        assert!(listed.has_superblock(40));
+        assert!(!listed.has_superblock(3));
+    }
 }
@@ -0,0 +1,154 @@
+use std::convert::TryFrom;
+
+use anyhow::ensure;
+use anyhow::Error;
+
+use crate::assumption_failed;
+use crate::extents::Extent;
+use crate::read_le32;
+use crate::Block;
+
+/// Direct pointers in the inode, before the single, double and triple indirect pointers.
+const DIRECT_BLOCKS: usize = 12;
+
+/// Find the data for an ext2/ext3-style inode, which doesn't use extents.
+///
+/// The inode's "core" holds twelve direct block numbers, then the numbers of a single, a double
+/// and a triple indirect block. An indirect block is full of block numbers for the next level
+/// down. A zero anywhere is a hole. Contiguous runs are merged, so the result looks like an
+/// extent tree would.
+pub fn load_block_map<F>(
+    load_block: &mut F,
+    core: [u8; crate::INODE_CORE_SIZE],
+    block_size: u32,
+    size: u64,
+) -> Result<Vec<Extent>, Error>
+where
+    F: FnMut(u64) -> Result<Block, Error>,
+{
+    let mut mapper = Mapper {
+        extents: Vec::new(),
+        pointers_per_block: u64::from(block_size / 4),
+        blocks_needed: size.div_ceil(u64::from(block_size)),
+    };
+
+    for (part, pointer) in core.chunks(4).take(DIRECT_BLOCKS).enumerate() {
+        mapper.add(load_block, u64::from(read_le32(pointer)), 0, part as u64)?;
+    }
+
+    let mut part = DIRECT_BLOCKS as u64;
+    for depth in 1..=3 {
+        let pointer = read_le32(&core[(DIRECT_BLOCKS + depth - 1) * 4..]);
+        mapper.add(load_block, u64::from(pointer), depth, part)?;
+        part += mapper.pointers_per_block.pow(u32::try_from(depth)?);
+    }
+
+    Ok(mapper.extents)
+}
+
+struct Mapper {
+    extents: Vec<Extent>,
+    pointers_per_block: u64,
+    blocks_needed: u64,
+}
+
+impl Mapper {
+    /// Record `block`, which covers `part` onwards; if `depth` is non-zero, it's an indirect block.
+    fn add<F>(
+        &mut self,
+        load_block: &mut F,
+        block: u64,
+        depth: usize,
+        part: u64,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u64) -> Result<Block, Error>,
+    {
+        if 0 == block || part >= self.blocks_needed {
+            return Ok(());
+        }
+
+        if 0 == depth {
+            let part = u32::try_from(part)
+                .map_err(|_| assumption_failed(format!("block {} is past the maximum", part)))?;
+            self.push(part, block);
+            return Ok(());
+        }
+
+        ensure!(
+            depth <= 3,
+            assumption_failed(format!("indirection too deep: {}", depth))
+        );
+
+        let data = load_block(block)?;
+        let span = self.pointers_per_block.pow(u32::try_from(depth - 1)?);
+
+        for (i, pointer) in data.chunks(4).enumerate() {
+            let child_part = part + i as u64 * span;
+            if child_part >= self.blocks_needed {
+                break;
+            }
+            self.add(
+                load_block,
+                u64::from(read_le32(pointer)),
+                depth - 1,
+                child_part,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, part: u32, start: u64) {
+        if let Some(last) = self.extents.last_mut() {
+            if last.part + u32::from(last.len) == part
+                && last.start + u64::from(last.len) == start
+                && last.len < u16::MAX
+            {
+                last.len += 1;
+                return;
+            }
+        }
+
+        self.extents.push(Extent {
+            part,
+            start,
+            len: 1,
+            unwritten: false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load_block_map;
+
+    #[test]
+    fn direct_and_indirect() {
+        // 16 byte blocks: four pointers per indirect block
+        let mut core = [0u8; crate::INODE_CORE_SIZE];
+        for (i, block) in [10u32, 11, 0, 13].iter().enumerate() {
+            core[i * 4..i * 4 + 4].copy_from_slice(&block.to_le_bytes());
+        }
+        core[12 * 4..12 * 4 + 4].copy_from_slice(&50u32.to_le_bytes());
+
+        let mut indirect = Vec::new();
+        for block in [14u32, 15, 0, 0] {
+            indirect.extend_from_slice(&block.to_le_bytes());
+        }
+
+        let extents = load_block_map(
+            &mut |block| {
+                assert_eq!(50, block);
+                Ok(indirect.clone().into())
+            },
+            core,
+            16,
+            16 * 14,
+        )
+        .unwrap();
+
+        let found: Vec<_> = extents.iter().map(|e| (e.part, e.start, e.len)).collect();
+        assert_eq!(vec![(0, 10, 2), (3, 13, 1), (12, 14, 2)], found);
+    }
+}
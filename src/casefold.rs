@@ -0,0 +1,70 @@
+use caseless::Caseless;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::casefold_data::ASSIGNED_12_1;
+
+/// A name in a casefolded directory, in the form which is compared, and hashed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Name {
+    /// Full case folding, then canonical decomposition, like the kernel's `utf8-12.1`
+    /// "nfdicf" tables.
+    Folded(Vec<u8>),
+    /// Not UTF-8, so it can't be folded; outside strict mode, the kernel compares, and hashes,
+    /// the name exactly.
+    Invalid,
+    /// Has characters which Unicode 12.1 didn't have. The kernel leaves those alone, but our
+    /// tables are newer, and would fold them; so these names are compared exactly, and the
+    /// hash the kernel gave them is unknown.
+    Unknown,
+}
+
+/// Fold a name which is UTF-8, and only uses characters Unicode 12.1 had; for those, our
+/// tables agree with the kernel's, as folding and decomposition are stable.
+fn fold(name: &str) -> String {
+    name.nfd().default_case_fold().nfd().collect()
+}
+
+/// How a name is compared, and hashed, in a casefolded directory.
+pub fn fold_bytes(name: &[u8]) -> Name {
+    match std::str::from_utf8(name) {
+        Ok(name) if name.chars().all(assigned_in_12_1) => Name::Folded(fold(name).into_bytes()),
+        Ok(_) => Name::Unknown,
+        Err(_) => Name::Invalid,
+    }
+}
+
+fn assigned_in_12_1(c: char) -> bool {
+    let c = u32::from(c);
+    1 == ASSIGNED_12_1.partition_point(|&start| start <= c) % 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold_bytes, Name};
+
+    fn folded(name: &str) -> Vec<u8> {
+        match fold_bytes(name.as_bytes()) {
+            Name::Folded(folded) => folded,
+            other => panic!("{:?} wasn't folded: {:?}", name, other),
+        }
+    }
+
+    #[test]
+    fn folding() {
+        assert_eq!(b"readme".to_vec(), folded("README"));
+        assert_eq!(folded("straße"), folded("STRASSE"));
+        assert_eq!(folded("\u{e9}"), folded("E\u{301}"));
+        assert_eq!(folded("Ünïcödé"), folded("üNÏCÖDÉ"));
+        assert_ne!(folded("a"), folded("b"));
+        assert_eq!(Name::Invalid, fold_bytes(b"R\xc9ADME"));
+    }
+
+    #[test]
+    fn newer_than_12_1() {
+        // U+32FF was the only addition in 12.1; U+2C2F, from 14.0, has a lower case
+        assert_eq!("\u{32ff}".as_bytes(), &folded("\u{32ff}")[..]);
+        assert_eq!(Name::Unknown, fold_bytes("\u{2c2f}".as_bytes()));
+        assert_eq!(Name::Unknown, fold_bytes("README-\u{1fae8}".as_bytes()));
+        assert_eq!(b"\xf4\x8f\xbf\xbd".to_vec(), folded("\u{10fffd}"));
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use anyhow::bail;
+use anyhow::Error;
+
+/// The kinds of structure which are checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Structure {
+    Superblock,
+    GroupDescriptor,
+    /// An inode or block allocation bitmap.
+    Bitmap,
+    Inode,
+    /// A block of an extent tree, below the root in the inode.
+    ExtentBlock,
+    /// A leaf block of a directory, which holds the names.
+    DirectoryBlock,
+    /// A block of a directory's hash tree index.
+    DirectoryIndex,
+    XattrBlock,
+    JournalSuperblock,
+    /// A descriptor or revoke block in the journal.
+    JournalBlock,
+    /// A copy of a filesystem block, logged in the journal.
+    JournalData,
+    JournalCommit,
+}
+
+/// What was wrong with a structure.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Problem {
+    ChecksumMismatch {
+        on_disc: u32,
+        computed: u32,
+    },
+    /// It doesn't make sense, like a record which doesn't fit in its block. Unless it's being
+    /// verified, the rest of the structure is skipped, and reading carries on without it.
+    Inconsistent {
+        reason: String,
+    },
+}
+
+/// A checksum which didn't match, or a structure which didn't make sense, and where it was.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub structure: Structure,
+    /// The inode the structure belongs to, if any.
+    pub inode: Option<u32>,
+    /// The block group the structure describes, if any.
+    pub group: Option<u32>,
+    /// The filesystem block the structure was read from, where it's known.
+    pub block: Option<u64>,
+    /// The byte offset into the inode's data, for directory blocks, which are read through it.
+    pub offset: Option<u64>,
+    pub problem: Problem,
+}
+
+impl Diagnostic {
+    pub(crate) fn new(structure: Structure, on_disc: u32, computed: u32) -> Diagnostic {
+        Self::with_problem(structure, Problem::ChecksumMismatch { on_disc, computed })
+    }
+
+    pub(crate) fn inconsistent<S: ToString>(structure: Structure, reason: S) -> Diagnostic {
+        let reason = reason.to_string();
+        Self::with_problem(structure, Problem::Inconsistent { reason })
+    }
+
+    fn with_problem(structure: Structure, problem: Problem) -> Diagnostic {
+        Diagnostic {
+            structure,
+            inode: None,
+            group: None,
+            block: None,
+            offset: None,
+            problem,
+        }
+    }
+
+    pub(crate) fn inode(mut self, inode: u32) -> Diagnostic {
+        self.inode = Some(inode);
+        self
+    }
+
+    pub(crate) fn group(mut self, group: u32) -> Diagnostic {
+        self.group = Some(group);
+        self
+    }
+
+    pub(crate) fn block(mut self, block: u64) -> Diagnostic {
+        self.block = Some(block);
+        self
+    }
+
+    pub(crate) fn offset(mut self, offset: u64) -> Diagnostic {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Which structure, where, and what kind of problem; the same one is often read, and
+    /// checked, more than once.
+    fn key(&self) -> Key {
+        (
+            self.structure,
+            self.inode,
+            self.group,
+            self.block,
+            self.offset,
+            std::mem::discriminant(&self.problem),
+        )
+    }
+}
+
+type Key = (
+    Structure,
+    Option<u32>,
+    Option<u32>,
+    Option<u64>,
+    Option<u64>,
+    std::mem::Discriminant<Problem>,
+);
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.problem {
+            Problem::ChecksumMismatch { .. } => {
+                write!(f, "{:?} checksum mismatch", self.structure)?
+            }
+            Problem::Inconsistent { .. } => write!(f, "{:?} inconsistent", self.structure)?,
+        }
+        if let Some(inode) = self.inode {
+            write!(f, " in inode <{}>", inode)?;
+        }
+        if let Some(group) = self.group {
+            write!(f, " for group {}", group)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        if let Some(block) = self.block {
+            write!(f, " in block {}", block)?;
+        }
+        match &self.problem {
+            Problem::ChecksumMismatch { on_disc, computed } => {
+                write!(f, ": on-disc: {:08x} computed: {:08x}", on_disc, computed)
+            }
+            Problem::Inconsistent { reason } => write!(f, ": {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// Everything which has been found to be wrong, shared between everything which checks.
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics(Arc<Mutex<Found>>);
+
+#[derive(Debug, Default)]
+struct Found {
+    list: Vec<Diagnostic>,
+    seen: HashSet<Key>,
+}
+
+impl Diagnostics {
+    /// Record a problem, unless it's already been recorded, then fail if it's being verified.
+    pub(crate) fn record(&self, verify: bool, diagnostic: Diagnostic) -> Result<(), Error> {
+        {
+            let mut found = self.found();
+            if found.seen.insert(diagnostic.key()) {
+                found.list.push(diagnostic.clone());
+            }
+        }
+
+        if verify {
+            bail!(diagnostic);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn list(&self) -> Vec<Diagnostic> {
+        self.found().list.clone()
+    }
+
+    /// Forget everything, so anything found again is recorded again.
+    pub(crate) fn take(&self) -> Vec<Diagnostic> {
+        let mut found = self.found();
+        found.seen.clear();
+        std::mem::take(&mut found.list)
+    }
+
+    /// Forget everything `keep` doesn't want.
+    pub(crate) fn retain<F: Fn(&Diagnostic) -> bool>(&self, keep: F) {
+        let mut found = self.found();
+        found.list.retain(|diagnostic| keep(diagnostic));
+        let kept = found.list.iter().map(Diagnostic::key).collect();
+        found.seen = kept;
+    }
+
+    fn found(&self) -> std::sync::MutexGuard<'_, Found> {
+        // a panic while pushing can't leave the list inconsistent
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostic, Diagnostics, Structure};
+
+    #[test]
+    fn record() {
+        let diagnostics = Diagnostics::default();
+        let found = Diagnostic::new(Structure::ExtentBlock, 0x1234, 0xabcd)
+            .inode(12)
+            .block(300);
+        assert_eq!(
+            "ExtentBlock checksum mismatch in inode <12> in block 300: \
+             on-disc: 00001234 computed: 0000abcd",
+            found.to_string()
+        );
+
+        // the same structure, found again, is still verified, but only recorded once
+        diagnostics.record(false, found.clone()).unwrap();
+        assert!(diagnostics.clone().record(true, found.clone()).is_err());
+        let elsewhere = found.clone().block(301);
+        diagnostics.record(false, elsewhere.clone()).unwrap();
+        assert_eq!(vec![found.clone(), elsewhere.clone()], diagnostics.list());
+
+        diagnostics.retain(|diagnostic| Some(301) == diagnostic.block);
+        assert_eq!(vec![elsewhere], diagnostics.list());
+        diagnostics.record(false, found.clone()).unwrap();
+        assert_eq!(2, diagnostics.take().len());
+        assert!(diagnostics.list().is_empty());
+        diagnostics.record(false, found.clone()).unwrap();
+        assert_eq!(vec![found.clone()], diagnostics.take());
+
+        // something else wrong with the same structure is recorded separately
+        let broken = Diagnostic::inconsistent(Structure::ExtentBlock, "invalid extent magic")
+            .inode(12)
+            .block(300);
+        assert_eq!(
+            "ExtentBlock inconsistent in inode <12> in block 300: invalid extent magic",
+            broken.to_string()
+        );
+        diagnostics.record(false, found.clone()).unwrap();
+        diagnostics.record(false, broken.clone()).unwrap();
+        diagnostics.record(false, broken.clone()).unwrap();
+        assert_eq!(vec![found, broken], diagnostics.take());
+    }
+}
@@ -0,0 +1,401 @@
+use std::fmt;
+use std::io;
+
+use crate::{Diagnostic, ParseError, Problem};
+
+/// Everything which can go wrong, from the public API.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The request is for something which we are sure is not there.
+    #[error("not found: {reason}{location}")]
+    NotFound { reason: String, location: Location },
+
+    /// The filesystem doesn't meet the code's expectations;
+    /// maybe the code is wrong, maybe the filesystem is corrupt.
+    #[error("assumption failed: {reason}{location}")]
+    Corrupt { reason: String, location: Location },
+
+    /// The filesystem is valid, but requests a feature the code doesn't support.
+    #[error("filesystem uses an unsupported feature: {reason}{location}")]
+    Unsupported { reason: String, location: Location },
+
+    /// A checksum didn't match, and it was being verified.
+    #[error("{diagnostic}{location}")]
+    ChecksumMismatch {
+        diagnostic: Box<Diagnostic>,
+        location: Location,
+    },
+
+    /// Resolving a path followed too many symbolic links; they probably form a loop.
+    #[error("too many levels of symbolic links{location}")]
+    SymlinkLoop { location: Location },
+
+    /// Reading from the underlying device failed.
+    #[error("i/o error: {source}{location}")]
+    Io {
+        source: io::Error,
+        location: Location,
+    },
+
+    /// Anything else, such as from a [`Crypto`](crate::Crypto) implementation, or a visitor.
+    #[error("{source}{location}")]
+    Other {
+        source: anyhow::Error,
+        location: Location,
+    },
+}
+
+/// Where an [`Error`] happened, as far as is known.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    pub inode: Option<u32>,
+    /// A filesystem block.
+    pub block: Option<u64>,
+    /// A byte offset into the inode's data.
+    pub offset: Option<u64>,
+    pub path: Option<String>,
+    /// What was being done, outermost first.
+    pub context: Vec<String>,
+}
+
+impl Error {
+    pub fn location(&self) -> &Location {
+        match self {
+            Error::NotFound { location, .. }
+            | Error::Corrupt { location, .. }
+            | Error::Unsupported { location, .. }
+            | Error::ChecksumMismatch { location, .. }
+            | Error::SymlinkLoop { location }
+            | Error::Io { location, .. }
+            | Error::Other { location, .. } => location,
+        }
+    }
+
+    fn location_mut(&mut self) -> &mut Location {
+        match self {
+            Error::NotFound { location, .. }
+            | Error::Corrupt { location, .. }
+            | Error::Unsupported { location, .. }
+            | Error::ChecksumMismatch { location, .. }
+            | Error::SymlinkLoop { location }
+            | Error::Io { location, .. }
+            | Error::Other { location, .. } => location,
+        }
+    }
+
+    /// A copy, for errors we can only see by reference. Only the message of a wrapped error
+    /// survives.
+    fn duplicate(&self) -> Error {
+        let location = self.location().clone();
+        match self {
+            Error::NotFound { reason, .. } => Error::NotFound {
+                reason: reason.clone(),
+                location,
+            },
+            Error::Corrupt { reason, .. } => Error::Corrupt {
+                reason: reason.clone(),
+                location,
+            },
+            Error::Unsupported { reason, .. } => Error::Unsupported {
+                reason: reason.clone(),
+                location,
+            },
+            Error::ChecksumMismatch { diagnostic, .. } => Error::ChecksumMismatch {
+                diagnostic: diagnostic.clone(),
+                location,
+            },
+            Error::SymlinkLoop { .. } => Error::SymlinkLoop { location },
+            Error::Io { source, .. } => Error::Io {
+                source: io::Error::new(source.kind(), source.to_string()),
+                location,
+            },
+            Error::Other { source, .. } => Error::Other {
+                source: anyhow::anyhow!("{:#}", source),
+                location,
+            },
+        }
+    }
+}
+
+impl Location {
+    pub(crate) fn inode(inode: u32) -> Location {
+        Location {
+            inode: Some(inode),
+            ..Location::default()
+        }
+    }
+
+    pub(crate) fn block(block: u64) -> Location {
+        Location {
+            block: Some(block),
+            ..Location::default()
+        }
+    }
+
+    pub(crate) fn path(path: &str) -> Location {
+        Location {
+            path: Some(path.to_string()),
+            ..Location::default()
+        }
+    }
+
+    pub(crate) fn offset(mut self, offset: u64) -> Location {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Add what's known from further in; it's more specific than what we had.
+    fn refine(&mut self, inner: &Location) {
+        self.inode = inner.inode.or(self.inode);
+        self.block = inner.block.or(self.block);
+        self.offset = inner.offset.or(self.offset);
+        self.path = inner.path.clone().or_else(|| self.path.take());
+        self.context.extend(inner.context.iter().cloned());
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(inode) = self.inode {
+            parts.push(format!("inode <{}>", inode));
+        }
+        if let Some(block) = self.block {
+            parts.push(format!("block {}", block));
+        }
+        if let Some(offset) = self.offset {
+            parts.push(format!("offset {}", offset));
+        }
+        if let Some(path) = &self.path {
+            parts.push(format!("path {:?}", path));
+        }
+
+        if !parts.is_empty() {
+            write!(f, " ({})", parts.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Error {
+        let mut location = Location::default();
+
+        for link in error.chain() {
+            if let Some(located) = link.downcast_ref::<Located>() {
+                location.refine(&located.location);
+                location.context.push(located.message.clone());
+                continue;
+            }
+
+            let ours = link.downcast_ref::<Error>().or_else(|| {
+                link.downcast_ref::<io::Error>()
+                    .and_then(|io| io.get_ref())
+                    .and_then(|inner| inner.downcast_ref::<Error>())
+            });
+            if let Some(ours) = ours {
+                let mut found = ours.duplicate();
+                location.refine(found.location());
+                *found.location_mut() = location;
+                return found;
+            }
+
+            if let Some(parsed) = link.downcast_ref::<ParseError>() {
+                return match parsed {
+                    ParseError::NotFound { reason } => Error::NotFound {
+                        reason: reason.clone(),
+                        location,
+                    },
+                    ParseError::AssumptionFailed { reason } => Error::Corrupt {
+                        reason: reason.clone(),
+                        location,
+                    },
+                    ParseError::UnsupportedFeature { reason } => Error::Unsupported {
+                        reason: reason.clone(),
+                        location,
+                    },
+                };
+            }
+
+            if let Some(diagnostic) = link.downcast_ref::<Diagnostic>() {
+                location.inode = diagnostic.inode.or(location.inode);
+                location.block = diagnostic.block.or(location.block);
+                location.offset = diagnostic.offset.or(location.offset);
+                return match &diagnostic.problem {
+                    Problem::ChecksumMismatch { .. } => Error::ChecksumMismatch {
+                        diagnostic: Box::new(diagnostic.clone()),
+                        location,
+                    },
+                    Problem::Inconsistent { reason } => Error::Corrupt {
+                        reason: reason.clone(),
+                        location,
+                    },
+                };
+            }
+
+            // anything with a source is just context
+            if link.source().is_some() {
+                location.context.push(link.to_string());
+            }
+        }
+
+        // the innermost error isn't one of ours
+        match error.downcast::<io::Error>() {
+            Ok(source) => Error::Io { source, location },
+            Err(error) => match error.root_cause().downcast_ref::<io::Error>() {
+                Some(io) => Error::Io {
+                    source: io::Error::new(io.kind(), io.to_string()),
+                    location,
+                },
+                None => Error::Other {
+                    source: error,
+                    location,
+                },
+            },
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Error {
+        anyhow::Error::from(error).into()
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Error {
+        match source
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Error>())
+        {
+            Some(ours) => ours.duplicate(),
+            None => Error::Io {
+                source,
+                location: Location::default(),
+            },
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        let kind = match &error {
+            Error::NotFound { .. } => io::ErrorKind::NotFound,
+            Error::Corrupt { .. } | Error::ChecksumMismatch { .. } => io::ErrorKind::InvalidData,
+            Error::Unsupported { .. } => io::ErrorKind::Unsupported,
+            Error::Io { source, .. } => source.kind(),
+            Error::SymlinkLoop { .. } | Error::Other { .. } => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// A step of what was being done, and where, wrapping the error from doing it.
+#[derive(Debug)]
+pub(crate) struct Located {
+    message: String,
+    location: Location,
+    source: anyhow::Error,
+}
+
+impl fmt::Display for Located {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Located {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// Like anyhow's `with_context`, but recording where, too.
+pub(crate) trait Locate<T> {
+    fn locate<F>(self, context: F) -> Result<T, anyhow::Error>
+    where
+        F: FnOnce() -> (Location, String);
+}
+
+impl<T, E: Into<anyhow::Error>> Locate<T> for Result<T, E> {
+    fn locate<F>(self, context: F) -> Result<T, anyhow::Error>
+    where
+        F: FnOnce() -> (Location, String),
+    {
+        self.map_err(|source| {
+            let (location, message) = context();
+            Located {
+                message,
+                location,
+                source: source.into(),
+            }
+            .into()
+        })
+    }
+}
+
+/// For errors which have to leave through `io::Read` and friends.
+pub(crate) fn to_io(error: anyhow::Error) -> io::Error {
+    Error::from(error).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use anyhow::Context;
+
+    use super::{Error, Locate, Location};
+    use crate::{assumption_failed, not_found};
+
+    #[test]
+    fn locations() {
+        let inner: Result<(), anyhow::Error> = Err(assumption_failed("bad magic").into());
+        let error = inner
+            .locate(|| (Location::block(7), "reading block 7".to_string()))
+            .context("loading things")
+            .locate(|| (Location::inode(12), "opening inode <12>".to_string()))
+            .map_err(Error::from)
+            .expect_err("failed");
+
+        match &error {
+            Error::Corrupt { reason, location } => {
+                assert_eq!("bad magic", reason);
+                assert_eq!(Some(12), location.inode);
+                assert_eq!(Some(7), location.block);
+                assert_eq!(
+                    vec!["opening inode <12>", "loading things", "reading block 7"],
+                    location.context
+                );
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            "assumption failed: bad magic (inode <12>, block 7)",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn through_io() {
+        let error = Error::from(not_found("nothing there"));
+        let io = io::Error::from(error);
+        assert_eq!(io::ErrorKind::NotFound, io.kind());
+
+        // e.g. from a file's reader, while reading a directory
+        let inner: Result<(), io::Error> = Err(io);
+        let error = inner
+            .locate(|| (Location::path("/a"), "reading /a".to_string()))
+            .map_err(Error::from)
+            .expect_err("failed");
+        match error {
+            Error::NotFound { location, .. } => {
+                assert_eq!(Some("/a"), location.path.as_deref())
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let error = Error::from(io::Error::new(io::ErrorKind::UnexpectedEof, "short"));
+        assert_eq!(io::ErrorKind::UnexpectedEof, io::Error::from(error).kind());
+    }
+}
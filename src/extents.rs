@@ -1,313 +1,859 @@
 use std::cmp::min;
 use std::convert::TryFrom;
 use std::io;
-use std::io::Write;
+use std::sync::{Mutex, PoisonError};
 
-use anyhow::bail;
 use anyhow::ensure;
 use anyhow::Error;
 
+use crate::diagnostics::{Diagnostic, Diagnostics, Structure};
+use crate::error::{to_io, Locate, Location};
 use crate::{
-    assumption_failed, map_lib_error_to_io, read_le16, read_le32, Crypto, InnerReader,
-    MetadataCrypto, ReadAt,
+    assumption_failed, map_lib_error_to_io, read_le16, read_le32, Block, Crypto, InnerReader,
+    Inode, InodeFlags, MetadataCrypto, ReadAt,
 };
 
-#[derive(Debug)]
-struct Extent {
+/// The longest an extent can be; a longer `ee_len` is an unwritten extent, minus this.
+const EXT_INIT_MAX_LEN: u16 = 32768;
+
+/// A run of blocks of a file, which are stored contiguously on disc.
+#[derive(Clone, Debug)]
+pub struct Extent {
     /// The docs call this 'block' (like everything else). I've invented a different name.
-    part: u32,
-    start: u64,
-    len: u16,
+    pub part: u32,
+    /// The block on disc holding the first block of the extent.
+    pub start: u64,
+    /// How many blocks long the extent is.
+    pub len: u16,
+    /// Space is allocated (e.g. by `fallocate`), but hasn't been written to, so reads as zeros.
+    pub unwritten: bool,
+}
+
+/// Where all of a file is on disc, like `FIEMAP` reports, in filesystem blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtentMap {
+    pub block_size: u32,
+    /// Every block of the file, in order, including holes, up to its size; then any blocks
+    /// allocated past the end, like by `fallocate --keep-size`.
+    pub mappings: Vec<Mapping>,
+    /// The blocks of the extent tree below the inode, or the indirect blocks of a block map,
+    /// in the order they were read.
+    pub tree_blocks: Vec<u64>,
+    /// The data is in the inode itself, so there's nothing else.
+    pub inline: bool,
+}
+
+/// A run of blocks of a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    /// The first block of the file covered.
+    pub logical: u64,
+    /// Where the first block is on disc; `None` for a hole, which reads as zeros.
+    pub physical: Option<u64>,
+    /// How many blocks are covered.
+    pub len: u64,
+    /// Space is allocated, but hasn't been written to, so reads as zeros.
+    pub unwritten: bool,
 }
 
 pub struct TreeReader<'a, R: ReadAt, C: Crypto, M: MetadataCrypto> {
     inner: &'a mut InnerReader<R, M>,
+    crypto: &'a C,
+    layout: Layout,
     pos: u64,
+}
+
+/// Where a file's data is, which is all that's needed to read it, given the disc.
+pub(crate) struct Layout {
     len: u64,
     block_size: u32,
-    extents: Vec<Extent>,
-    encryption_context: Option<&'a Vec<u8>>,
-    crypto: &'a C,
+    blocks: Blocks,
+    /// If the data is stored in the inode itself, there are no extents, just this.
+    inline_data: Option<Vec<u8>>,
+    encryption_context: Option<Vec<u8>>,
     ino: u32,
+    /// Blocks read to find the extents, once they all have been.
+    tree_blocks: Vec<u64>,
+    checks: TreeChecks,
+}
+
+/// Where the extents are.
+enum Blocks {
+    /// All of them, in order.
+    Listed(Vec<Extent>),
+    /// In an extent tree, which is only read as far as reads have needed.
+    Tree(Mutex<Node>),
+}
+
+/// How blocks of the extent tree are checked, as they're read.
+#[derive(Default)]
+struct TreeChecks {
+    checksum_prefix: Option<u32>,
+    verify: bool,
+    diagnostics: Diagnostics,
+}
+
+/// A node of an extent tree.
+enum Node {
+    Leaf(Vec<Extent>),
+    Index {
+        depth: u16,
+        /// Sorted by the part of the file they cover.
+        children: Vec<Child>,
+    },
+}
+
+/// An entry in an index node, and, once something has been looked up in it, what it points to.
+struct Child {
+    /// The first block of the file it covers; `ei_block`.
+    part: u32,
+    /// Where the node is on disc.
+    block: u64,
+    node: Option<Box<Node>>,
 }
 
 impl<'a, R: ReadAt, C: Crypto, M: MetadataCrypto> TreeReader<'a, R, C, M> {
-    pub fn new(
+    pub(crate) fn new(
         inner: &'a mut InnerReader<R, M>,
-        block_size: u32,
-        size: u64,
-        core: [u8; crate::INODE_CORE_SIZE],
-        checksum_prefix: Option<u32>,
-        encryption_context: Option<&'a Vec<u8>>,
+        layout: Layout,
         crypto: &'a C,
-        ino: u32,
-    ) -> Result<TreeReader<'a, R, C, M>, Error> {
-        let extents = load_extent_tree(
-            &mut |block| crate::load_disc_bytes(inner, block_size, block),
-            core,
-            checksum_prefix,
-        )?;
-
-        Ok(TreeReader::create(
+    ) -> TreeReader<'a, R, C, M> {
+        TreeReader {
             inner,
-            block_size,
-            size,
-            extents,
-            encryption_context,
             crypto,
-            ino,
-        ))
+            layout,
+            pos: 0,
+        }
     }
 
+    #[cfg(test)]
     fn create(
         inner: &'a mut InnerReader<R, M>,
         block_size: u32,
         size: u64,
         extents: Vec<Extent>,
-        encryption_context: Option<&'a Vec<u8>>,
+        encryption_context: Option<&Vec<u8>>,
         crypto: &'a C,
         ino: u32,
     ) -> TreeReader<'a, R, C, M> {
-        TreeReader {
-            pos: 0,
+        let layout = Layout::create(block_size, size, extents, encryption_context, ino);
+        TreeReader::new(inner, layout, crypto)
+    }
+
+    pub fn ref_inner(self) -> &'a R {
+        &self.inner.inner
+    }
+
+    /// Where the data is on disc, sorted by position in the file. Empty for inline data. This
+    /// reads the whole extent tree, which reading the data doesn't.
+    pub fn extents(&mut self) -> Result<&[Extent], crate::Error> {
+        let ino = self.layout.ino;
+        Ok(self
+            .layout
+            .extents(self.inner)
+            .locate(|| (Location::inode(ino), "loading extents".to_string()))?)
+    }
+
+    /// Read from `pos` in the file, like `Read::read`, but leaving the position alone; unlike
+    /// the [`ReadAt`] impl every `Read + Seek` gets, which seeks.
+    pub fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.layout.read_at(self.inner, self.crypto, pos, buf)
+    }
+
+    /// Like [`read_at`](Self::read_at), but fill the whole buffer, or fail.
+    pub fn read_exact_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.layout
+            .read_exact_at(&mut *self.inner, self.crypto, pos, buf)
+    }
+}
+
+impl Layout {
+    pub(crate) fn new<R: ReadAt, M: MetadataCrypto>(
+        inner: &mut InnerReader<R, M>,
+        inode: &Inode,
+        encryption_context: Option<&Vec<u8>>,
+    ) -> Result<Layout, Error> {
+        let block_size = inode.block_size;
+
+        if inode.flags.contains(InodeFlags::INLINE_DATA) {
+            let mut data = inode.inline_data();
+            let size = usize::try_from(inode.stat.size)?;
+            ensure!(
+                size <= data.len(),
+                assumption_failed(format!(
+                    "inline data is only {} bytes, but the file is {}",
+                    data.len(),
+                    size
+                ))
+            );
+            data.truncate(size);
+
+            let mut layout = Layout::create(
+                block_size,
+                inode.stat.size,
+                Vec::new(),
+                encryption_context,
+                inode.number,
+            );
+            layout.inline_data = Some(data);
+            return Ok(layout);
+        }
+
+        let checks = TreeChecks {
+            checksum_prefix: inode.checksum_prefix,
+            verify: inode.verify_extents,
+            diagnostics: inode.diagnostics.clone(),
+        };
+
+        let mut layout = if inode.flags.contains(InodeFlags::EXTENTS) {
+            let mut layout = Layout::create(
+                block_size,
+                inode.stat.size,
+                Vec::new(),
+                encryption_context,
+                inode.number,
+            );
+            layout.blocks = Blocks::Tree(Mutex::new(parse_root(&inode.core)?));
+            layout
+        } else {
+            let mut tree_blocks = Vec::new();
+            let mut load_block = |block| {
+                tree_blocks.push(block);
+                crate::load_disc_bytes(inner, block_size, block)
+            };
+            let extents = crate::block_map::load_block_map(
+                &mut load_block,
+                inode.core,
+                block_size,
+                inode.stat.size,
+            )?;
+
+            let mut layout = Layout::create(
+                block_size,
+                inode.stat.size,
+                extents,
+                encryption_context,
+                inode.number,
+            );
+            layout.tree_blocks = tree_blocks;
+            layout
+        };
+        layout.checks = checks;
+        Ok(layout)
+    }
+
+    fn create(
+        block_size: u32,
+        size: u64,
+        extents: Vec<Extent>,
+        encryption_context: Option<&Vec<u8>>,
+        ino: u32,
+    ) -> Layout {
+        Layout {
             len: size,
-            inner,
-            extents,
             block_size,
-            encryption_context,
-            crypto,
+            blocks: Blocks::Listed(extents),
+            inline_data: None,
+            encryption_context: encryption_context.cloned(),
             ino,
+            tree_blocks: Vec::new(),
+            checks: TreeChecks::default(),
         }
     }
 
-    pub fn ref_inner(self) -> &'a R {
-        &self.inner.inner
+    /// Every extent, reading whatever of the tree hasn't been read yet.
+    pub(crate) fn extents<R: ReadAt, M: MetadataCrypto>(
+        &mut self,
+        inner: &mut InnerReader<R, M>,
+    ) -> Result<&[Extent], Error> {
+        if let Blocks::Tree(root) = &mut self.blocks {
+            let root = root.get_mut().unwrap_or_else(PoisonError::into_inner);
+            let mut extents = Vec::new();
+            let mut tree_blocks = Vec::new();
+            let (checks, block_size, ino) = (&self.checks, self.block_size, self.ino);
+            root.collect(
+                &mut |block, depth| load_node(inner, checks, block_size, ino, block, depth),
+                &mut extents,
+                &mut tree_blocks,
+            )?;
+
+            extents.sort_by_key(|e| e.part);
+            self.blocks = Blocks::Listed(extents);
+            self.tree_blocks = tree_blocks;
+        }
+
+        match &self.blocks {
+            Blocks::Listed(extents) => Ok(extents),
+            Blocks::Tree(_) => unreachable!("the tree was just listed"),
+        }
+    }
+
+    pub(crate) fn map<R: ReadAt, M: MetadataCrypto>(
+        &mut self,
+        inner: &mut InnerReader<R, M>,
+    ) -> Result<ExtentMap, Error> {
+        let block_size = u64::from(self.block_size);
+        let blocks = self.len.div_ceil(block_size);
+        let extents = self.extents(inner)?;
+
+        let mut mappings = Vec::with_capacity(extents.len() * 2 + 1);
+        let mut next = 0;
+        for extent in extents {
+            let logical = u64::from(extent.part);
+            if logical > next && next < blocks {
+                mappings.push(Mapping {
+                    logical: next,
+                    physical: None,
+                    len: min(logical, blocks) - next,
+                    unwritten: false,
+                });
+            }
+
+            mappings.push(Mapping {
+                logical,
+                physical: Some(extent.start),
+                len: u64::from(extent.len),
+                unwritten: extent.unwritten,
+            });
+            next = logical + u64::from(extent.len);
+        }
+
+        if self.inline_data.is_none() && next < blocks {
+            mappings.push(Mapping {
+                logical: next,
+                physical: None,
+                len: blocks - next,
+                unwritten: false,
+            });
+        }
+
+        Ok(ExtentMap {
+            block_size: self.block_size,
+            mappings,
+            tree_blocks: self.tree_blocks.clone(),
+            inline: self.inline_data.is_some(),
+        })
+    }
+
+    /// Read from `pos` in the file, returning how much was read, like `Read::read`.
+    pub(crate) fn read_at<R: ReadAt, C: Crypto, M: MetadataCrypto>(
+        &self,
+        inner: &mut InnerReader<R, M>,
+        crypto: &C,
+        pos: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        if buf.is_empty() || pos >= self.len {
+            return Ok(0);
+        }
+
+        if let Some(data) = &self.inline_data {
+            let start = min(pos, data.len() as u64) as usize;
+            let read = min(data.len() - start, buf.len());
+            buf[..read].copy_from_slice(&data[start..start + read]);
+            return Ok(read);
+        }
+
+        let block_size = u64::from(self.block_size);
+        self.with_part(inner, pos, |inner, found| match found {
+            FoundPart::Actual(extents) => {
+                // as far as the disc is contiguous, it can be read in one go
+                let extent = &extents[0];
+                let into_run = pos - u64::from(extent.part) * block_size;
+                let run = contiguous_blocks(extents) * block_size - into_run;
+                let len = min(min(self.len - pos, buf.len() as u64), run) as usize;
+                let addr = extent.start * block_size + into_run;
+
+                match &self.encryption_context {
+                    Some(context) => {
+                        self.read_encrypted(inner, crypto, context, pos, addr, &mut buf[..len])?;
+                        Ok(len)
+                    }
+                    None => match inner.read_data_at(addr, &mut buf[..len])? {
+                        0 => Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("the image ends before the data at {}", addr),
+                        )),
+                        read => Ok(read),
+                    },
+                }
+            }
+            FoundPart::Sparse(max) => {
+                let max_bytes = u64::from(max) * block_size;
+                let read = min(max_bytes, buf.len() as u64) as usize;
+                let read = min(read as u64, self.len - pos) as usize;
+                zero(&mut buf[0..read]);
+                Ok(read)
+            }
+        })
+    }
+
+    /// Block `index` of the file, shared with the image, where it can be, when the data on
+    /// disc is the data; or read. The last block is only as long as the file.
+    pub(crate) fn block<R: ReadAt, C: Crypto, M: MetadataCrypto>(
+        &self,
+        inner: &mut InnerReader<R, M>,
+        crypto: &C,
+        index: u64,
+    ) -> io::Result<Block> {
+        let block_size = u64::from(self.block_size);
+        let pos = index * block_size;
+        let len = min(block_size, self.len.saturating_sub(pos)) as usize;
+
+        if self.encryption_context.is_none() && self.inline_data.is_none() && 0 != len {
+            let found = self.with_part(inner, pos, |inner, found| match found {
+                FoundPart::Actual(extents) => {
+                    let extent = &extents[0];
+                    let addr = (extent.start + (index - u64::from(extent.part))) * block_size;
+                    inner.block(addr, len).map(Some)
+                }
+                FoundPart::Sparse(_) => Ok(None),
+            })?;
+
+            if let Some(block) = found {
+                return Ok(block);
+            }
+        }
+
+        let mut data = vec![0u8; len];
+        self.read_exact_at(inner, crypto, pos, &mut data)?;
+        Ok(data.into())
+    }
+
+    /// Find the extent, or hole, which has `pos` in it, reading the tree as far as needed.
+    fn with_part<R: ReadAt, M: MetadataCrypto, T, F>(
+        &self,
+        inner: &mut InnerReader<R, M>,
+        pos: u64,
+        then: F,
+    ) -> io::Result<T>
+    where
+        F: FnOnce(&mut InnerReader<R, M>, FoundPart) -> io::Result<T>,
+    {
+        let block_index =
+            u32::try_from(pos / u64::from(self.block_size)).map_err(map_lib_error_to_io)?;
+
+        let mut root;
+        let found = match &self.blocks {
+            Blocks::Listed(extents) => find_part(block_index, extents, u32::MAX),
+            Blocks::Tree(tree) => {
+                root = tree.lock().unwrap_or_else(PoisonError::into_inner);
+                let (checks, ino) = (&self.checks, self.ino);
+                root.find(block_index, u32::MAX, &mut |block, depth| {
+                    load_node(inner, checks, self.block_size, ino, block, depth)
+                })
+                .locate(|| {
+                    let location = Location::inode(self.ino).offset(pos);
+                    (location, "finding extents".to_string())
+                })
+                .map_err(to_io)?
+            }
+        };
+
+        then(inner, found)
+    }
+
+    /// Fill `buf` from `addr` on disc, which is `pos` in the file, decrypting it. Whole blocks
+    /// are read, and decrypted, in place; partial ones go through the scratch buffer. A block
+    /// can't be decrypted without all of it, so a source which stops short is an error.
+    fn read_encrypted<R: ReadAt, C: Crypto, M: MetadataCrypto>(
+        &self,
+        inner: &mut InnerReader<R, M>,
+        crypto: &C,
+        context: &[u8],
+        pos: u64,
+        addr: u64,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let block_size = self.block_size as usize;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let offset = ((pos + done as u64) % u64::from(self.block_size)) as usize;
+            let page_offset = pos + done as u64 - offset as u64;
+            let page_addr = addr + done as u64 - offset as u64;
+            let remaining = buf.len() - done;
+
+            if 0 != offset || remaining < block_size {
+                let fits = min(block_size - offset, remaining);
+                let wanted = &mut buf[done..done + fits];
+                inner.with_scratch(block_size, |inner, page| -> io::Result<()> {
+                    fill_exact(inner, page_addr, page)?;
+                    self.decrypt_block(crypto, context, page, page_offset, page_addr)?;
+                    wanted.copy_from_slice(&page[offset..offset + fits]);
+                    Ok(())
+                })?;
+                done += fits;
+                continue;
+            }
+
+            let whole = remaining - remaining % block_size;
+            let pages = &mut buf[done..done + whole];
+            fill_exact(inner, page_addr, pages)?;
+            for (n, page) in pages.chunks_mut(block_size).enumerate() {
+                let into = (n * block_size) as u64;
+                self.decrypt_block(crypto, context, page, page_offset + into, page_addr + into)?;
+            }
+            done += whole;
+        }
+
+        Ok(())
+    }
+
+    fn decrypt_block<C: Crypto>(
+        &self,
+        crypto: &C,
+        context: &[u8],
+        page: &mut [u8],
+        page_offset: u64,
+        page_addr: u64,
+    ) -> io::Result<()> {
+        crypto
+            .decrypt_page(context, page, page_offset, page_addr, self.ino)
+            .locate(|| {
+                let location = Location::inode(self.ino).offset(page_offset);
+                (location, "decrypting".to_string())
+            })
+            .map_err(to_io)
+    }
+
+    /// Like `read_at`, but fill the whole buffer, or fail.
+    pub(crate) fn read_exact_at<R: ReadAt, C: Crypto, M: MetadataCrypto>(
+        &self,
+        inner: &mut InnerReader<R, M>,
+        crypto: &C,
+        mut pos: u64,
+        mut buf: &mut [u8],
+    ) -> io::Result<()> {
+        while !buf.is_empty() {
+            let read = self.read_at(inner, crypto, pos, buf)?;
+            if 0 == read {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ));
+            }
+            buf = &mut buf[read..];
+            pos += read as u64;
+        }
+        Ok(())
+    }
+
+    /// Where a seek from `current` ends up. Past the end is fine, and reads nothing, as for a
+    /// `File`; before the start isn't.
+    pub(crate) fn seek(&self, current: u64, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(set) => return Ok(set),
+            io::SeekFrom::Current(diff) => (current, diff),
+            io::SeekFrom::End(diff) => (self.len, diff),
+        };
+
+        let sought = if offset >= 0 {
+            base.checked_add(offset.unsigned_abs())
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+
+        sought.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid seek: {:?}, from {}", pos, current),
+            )
+        })
     }
 }
 
 enum FoundPart<'a> {
-    Actual(&'a Extent),
+    /// The extent holding the block, then every one after it.
+    Actual(&'a [Extent]),
     Sparse(u32),
 }
 
-fn find_part(part: u32, extents: &[Extent]) -> FoundPart {
-    for extent in extents {
+/// Find `part` in a leaf's `extents`, which cover the file up to `end`, at most.
+fn find_part(part: u32, extents: &[Extent], end: u32) -> FoundPart<'_> {
+    for (i, extent) in extents.iter().enumerate() {
         if part < extent.part {
             // we've gone past it
             return FoundPart::Sparse(extent.part - part);
         }
 
         if part >= extent.part && part < extent.part + u32::from(extent.len) {
+            if extent.unwritten {
+                // there's (probably stale) data on disc, but it reads as zeros
+                return FoundPart::Sparse(extent.part + u32::from(extent.len) - part);
+            }
+
             // we're inside it
-            return FoundPart::Actual(extent);
+            return FoundPart::Actual(&extents[i..]);
         }
     }
 
-    FoundPart::Sparse(std::u32::MAX)
+    FoundPart::Sparse(end.saturating_sub(part))
+}
+
+/// How many blocks, from the start of the first extent, follow on from each other on disc, as
+/// well as in the file; extents are at most 32768 blocks, so big files are split into many.
+fn contiguous_blocks(extents: &[Extent]) -> u64 {
+    let mut blocks = u64::from(extents[0].len);
+    for pair in extents.windows(2) {
+        let (prev, next) = (&pair[0], &pair[1]);
+        let follows = u64::from(prev.part) + u64::from(prev.len) == u64::from(next.part)
+            && prev.start + u64::from(prev.len) == next.start;
+        if !follows || next.unwritten {
+            break;
+        }
+        blocks += u64::from(next.len);
+    }
+    blocks
 }
 
 impl<'a, R: ReadAt, C: Crypto, M: MetadataCrypto> io::Read for TreeReader<'a, R, C, M> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if buf.is_empty() {
-            return Ok(0);
-        }
+        let read = self
+            .layout
+            .read_at(self.inner, self.crypto, self.pos, buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
 
-        let block_size = u64::from(self.block_size);
-        let mut block_index = u32::try_from(self.pos / block_size).map_err(map_lib_error_to_io)?;
-
-        match find_part(block_index, &self.extents) {
-            FoundPart::Actual(extent) => {
-                let output_len = min(self.len - self.pos, buf.len() as u64) as usize;
-                let mut output = io::Cursor::new(&mut buf[..output_len]);
-
-                let mut page = vec![0u8; block_size as usize];
-                let mut offset_in_page = (self.pos % block_size) as usize;
-
-                let max_block_index = extent.part + (extent.len as u32);
-                while block_index < max_block_index {
-                    let page_addr =
-                        (extent.start + (block_index - extent.part) as u64) * block_size;
-
-                    if let Some(context) = self.encryption_context {
-                        self.inner
-                            .read_at_without_decrypt(page_addr, page.as_mut_slice())?;
-
-                        let page_offset = (block_index as u64) * block_size;
-
-                        self.crypto
-                            .decrypt_page(
-                                context,
-                                page.as_mut_slice(),
-                                page_offset,
-                                page_addr,
-                                self.ino,
-                            )
-                            .map_err(map_lib_error_to_io)?;
-                    } else {
-                        self.inner.read_at(page_addr, page.as_mut_slice())?;
-                    }
+impl<'a, R: ReadAt, C: Crypto, M: MetadataCrypto> io::Seek for TreeReader<'a, R, C, M> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.pos = self.layout.seek(self.pos, pos)?;
+        Ok(self.pos)
+    }
+}
 
-                    output.write(&page[offset_in_page..])?;
-                    if output.position() == output_len as u64 {
-                        break;
-                    }
+impl Node {
+    /// Find `part` below this node, which covers the file up to `end`, reading the nodes on
+    /// the way, if they haven't been already.
+    fn find<F>(&mut self, part: u32, end: u32, load: &mut F) -> Result<FoundPart<'_>, Error>
+    where
+        F: FnMut(u64, u16) -> Result<Node, Error>,
+    {
+        let (depth, children) = match self {
+            Node::Leaf(extents) => return Ok(find_part(part, extents, end)),
+            Node::Index { depth, children } => (*depth, children),
+        };
 
-                    block_index += 1;
-                    offset_in_page = 0;
-                }
+        let after = children.partition_point(|child| child.part <= part);
+        if 0 == after {
+            // before the first child, if there are any
+            let next = children.first().map_or(end, |child| child.part);
+            return Ok(FoundPart::Sparse(next.saturating_sub(part)));
+        }
 
-                let read = output.position();
-                self.pos += read;
+        let end = children.get(after).map_or(end, |next| next.part);
+        children[after - 1].node(depth, load)?.find(part, end, load)
+    }
 
-                Ok(read as usize)
-            }
-            FoundPart::Sparse(max) => {
-                let max_bytes = u64::from(max) * block_size;
-                let read = min(max_bytes, buf.len() as u64) as usize;
-                let read = min(read as u64, self.len - self.pos) as usize;
-                zero(&mut buf[0..read]);
-                self.pos += u64::try_from(read).map_err(map_lib_error_to_io)?;
-                Ok(read)
+    /// Add every extent below this node to `extents`, and the blocks of the nodes below it to
+    /// `tree_blocks`, depth first, reading them if they haven't been already.
+    fn collect<F>(
+        &mut self,
+        load: &mut F,
+        extents: &mut Vec<Extent>,
+        tree_blocks: &mut Vec<u64>,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u64, u16) -> Result<Node, Error>,
+    {
+        match self {
+            Node::Leaf(found) => extents.extend(found.iter().cloned()),
+            Node::Index { depth, children } => {
+                for child in children {
+                    tree_blocks.push(child.block);
+                    child
+                        .node(*depth, load)?
+                        .collect(load, extents, tree_blocks)?;
+                }
             }
         }
+
+        Ok(())
     }
 }
 
-impl<'a, R: ReadAt, C: Crypto, M: MetadataCrypto> io::Seek for TreeReader<'a, R, C, M> {
-    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        match pos {
-            io::SeekFrom::Start(set) => self.pos = set,
-            io::SeekFrom::Current(diff) => self.pos = (self.pos as i64 + diff) as u64,
-            io::SeekFrom::End(set) => {
-                assert!(set >= 0);
-                self.pos = self.len - u64::try_from(set).map_err(map_lib_error_to_io)?;
-            }
+impl Child {
+    /// The node this points to, reading it the first time; `depth` is the parent's.
+    fn node<F>(&mut self, depth: u16, load: &mut F) -> Result<&mut Node, Error>
+    where
+        F: FnMut(u64, u16) -> Result<Node, Error>,
+    {
+        if self.node.is_none() {
+            self.node = Some(Box::new(load(self.block, depth - 1)?));
         }
+        Ok(self.node.as_mut().expect("just loaded"))
+    }
+}
 
-        assert!(self.pos <= self.len);
+/// Handles a checksum mismatch in a block of the tree, given the block, then the on-disc and
+/// computed checksums. It decides whether that's fatal.
+type Mismatch<'a> = dyn Fn(u64, u32, u32) -> Result<(), Error> + 'a;
 
-        Ok(self.pos)
+/// Read, check and parse the node of an extent tree at `block`, which should be at `depth`.
+fn load_node<R: ReadAt, M: MetadataCrypto>(
+    inner: &mut InnerReader<R, M>,
+    checks: &TreeChecks,
+    block_size: u32,
+    ino: u32,
+    block: u64,
+    depth: u16,
+) -> Result<Node, Error> {
+    let data = crate::load_disc_bytes(inner, block_size, block)?;
+
+    if let Err(reason) = check_header(&data, depth) {
+        // the entries can't be trusted, so this part of the file reads as a hole
+        let diagnostic = Diagnostic::inconsistent(Structure::ExtentBlock, reason)
+            .inode(ino)
+            .block(block);
+        checks.diagnostics.record(checks.verify, diagnostic)?;
+        return Ok(Node::Leaf(Vec::new()));
     }
+
+    let mismatch = |block, on_disc, computed| {
+        let diagnostic = Diagnostic::new(Structure::ExtentBlock, on_disc, computed)
+            .inode(ino)
+            .block(block);
+        checks.diagnostics.record(checks.verify, diagnostic)
+    };
+    let checksums = checks
+        .checksum_prefix
+        .map(|prefix| (prefix, &mismatch as _));
+
+    parse_node(&data, Some(block), depth, checksums)
 }
 
-fn add_found_extents<F>(
-    load_block: &mut F,
-    data: &[u8],
-    expected_depth: u16,
-    extents: &mut Vec<Extent>,
-    checksum_prefix_op: Option<u32>,
-    first_level: bool,
-) -> Result<(), Error>
-where
-    F: FnMut(u64) -> Result<Vec<u8>, Error>,
-{
+/// The root of the tree, which is in the inode, so needs no reading, or checking.
+fn parse_root(core: &[u8; crate::INODE_CORE_SIZE]) -> Result<Node, Error> {
     ensure!(
-        0x0a == data[0] && 0xf3 == data[1],
+        0x0a == core[0] && 0xf3 == core[1],
         assumption_failed("invalid extent magic")
     );
 
-    let extent_entries = read_le16(&data[2..]);
     // 4..: max; doesn't seem to be useful during read
-    let depth = read_le16(&data[6..]);
-    // 8..: generation, not used in standard ext4
+    let depth = read_le16(&core[6..]);
 
     ensure!(
-        expected_depth == depth,
-        assumption_failed(format!("depth incorrect: {} != {}", expected_depth, depth))
+        depth <= 5,
+        assumption_failed(format!("initial depth too high: {}", depth))
     );
 
-    if let (Some(checksum_prefix), false) = (checksum_prefix_op, first_level) {
+    parse_node(core, None, depth, None)
+}
+
+/// `block` is where `data` came from, or `None` for the root, in the inode.
+fn parse_node(
+    data: &[u8],
+    block: Option<u64>,
+    expected_depth: u16,
+    checksums: Option<(u32, &Mismatch)>,
+) -> Result<Node, Error> {
+    let extent_entries = check_header(data, expected_depth).map_err(assumption_failed)?;
+    let depth = expected_depth;
+
+    if let (Some((checksum_prefix, mismatch)), Some(block)) = (checksums, block) {
         let end_of_entries = data.len() - 4;
         let on_disc = read_le32(&data[end_of_entries..(end_of_entries + 4)]);
         let computed = crate::parse::ext4_style_crc32c_le(checksum_prefix, &data[..end_of_entries]);
 
         if computed != on_disc {
-            if cfg!(feature = "verify-checksums") {
-                bail!(assumption_failed(format!(
-                    "extent checksum mismatch: {:08x} != {:08x} @ {}",
-                    on_disc,
-                    computed,
-                    data.len()
-                )));
-            }
+            mismatch(block, on_disc, computed)?;
         }
     }
 
+    let entries = (0..usize::from(extent_entries)).map(|en| &data[12 + en * 12..24 + en * 12]);
+
     if 0 == depth {
-        for en in 0..extent_entries {
-            let raw_extent = &data[12 + usize::from(en) * 12..];
-            let ee_block = read_le32(raw_extent);
-            let ee_len = read_le16(&raw_extent[4..]);
-            let ee_start_hi = read_le16(&raw_extent[6..]);
-            let ee_start_lo = read_le32(&raw_extent[8..]);
-            let ee_start = u64::from(ee_start_lo) + 0x1000 * u64::from(ee_start_hi);
-
-            extents.push(Extent {
-                part: ee_block,
-                start: ee_start,
-                len: ee_len,
-            });
-        }
+        let mut extents = entries
+            .map(|raw_extent| {
+                let ee_block = read_le32(raw_extent);
+                let ee_len = read_le16(&raw_extent[4..]);
+                let ee_start_hi = read_le16(&raw_extent[6..]);
+                let ee_start_lo = read_le32(&raw_extent[8..]);
+                let ee_start = u64::from(ee_start_lo) | (u64::from(ee_start_hi) << 32);
 
-        return Ok(());
-    }
+                let (len, unwritten) = if ee_len > EXT_INIT_MAX_LEN {
+                    (ee_len - EXT_INIT_MAX_LEN, true)
+                } else {
+                    (ee_len, false)
+                };
 
-    for en in 0..extent_entries {
-        let extent_idx = &data[12 + usize::from(en) * 12..];
-        //            let ei_block = as_u32(extent_idx);
-        let ei_leaf_lo = read_le32(&extent_idx[4..]);
-        let ei_leaf_hi = read_le16(&extent_idx[8..]);
-        let ee_leaf: u64 = u64::from(ei_leaf_lo) + (u64::from(ei_leaf_hi) << 32);
-        let data = load_block(ee_leaf)?;
-        add_found_extents(
-            load_block,
-            &data,
-            depth - 1,
-            extents,
-            checksum_prefix_op,
-            false,
-        )?;
+                Extent {
+                    part: ee_block,
+                    start: ee_start,
+                    len,
+                    unwritten,
+                }
+            })
+            .collect::<Vec<_>>();
+        extents.sort_by_key(|e| e.part);
+        return Ok(Node::Leaf(extents));
     }
 
-    Ok(())
+    let mut children = entries
+        .map(|extent_idx| {
+            let ei_block = read_le32(extent_idx);
+            let ei_leaf_lo = read_le32(&extent_idx[4..]);
+            let ei_leaf_hi = read_le16(&extent_idx[8..]);
+            Child {
+                part: ei_block,
+                block: u64::from(ei_leaf_lo) | (u64::from(ei_leaf_hi) << 32),
+                node: None,
+            }
+        })
+        .collect::<Vec<_>>();
+    children.sort_by_key(|child| child.part);
+
+    Ok(Node::Index { depth, children })
 }
 
-fn load_extent_tree<F>(
-    load_block: &mut F,
-    core: [u8; crate::INODE_CORE_SIZE],
-    checksum_prefix: Option<u32>,
-) -> Result<Vec<Extent>, Error>
-where
-    F: FnMut(u64) -> Result<Vec<u8>, Error>,
-{
-    ensure!(
-        0x0a == core[0] && 0xf3 == core[1],
-        assumption_failed("invalid extent magic")
-    );
+/// Why the header of the node in `data`, which should be at `expected_depth`, is wrong, if it
+/// is; otherwise, how many entries follow it.
+fn check_header(data: &[u8], expected_depth: u16) -> Result<u16, String> {
+    if 0x0a != data[0] || 0xf3 != data[1] {
+        return Err("invalid extent magic".to_string());
+    }
 
-    let extent_entries = read_le16(&core[2..]);
+    let extent_entries = read_le16(&data[2..]);
     // 4..: max; doesn't seem to be useful during read
-    let depth = read_le16(&core[6..]);
-
-    ensure!(
-        depth <= 5,
-        assumption_failed(format!("initial depth too high: {}", depth))
-    );
+    let depth = read_le16(&data[6..]);
+    // 8..: generation, not used in standard ext4
 
-    let mut extents = Vec::with_capacity(usize::from(extent_entries) + usize::from(depth) * 200);
+    if expected_depth != depth {
+        return Err(format!("depth incorrect: {} != {}", expected_depth, depth));
+    }
 
-    add_found_extents(
-        load_block,
-        &core,
-        depth,
-        &mut extents,
-        checksum_prefix,
-        true,
-    )?;
+    if 12 + usize::from(extent_entries) * 12 > data.len() {
+        return Err(format!(
+            "{} extent entries don't fit in {} bytes",
+            extent_entries,
+            data.len()
+        ));
+    }
 
-    extents.sort_by_key(|e| e.part);
+    Ok(extent_entries)
+}
 
-    Ok(extents)
+/// Fill `buf` with raw bytes from `addr`, failing if the source doesn't have them all.
+fn fill_exact<R: ReadAt, M: MetadataCrypto>(
+    inner: &mut InnerReader<R, M>,
+    addr: u64,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let read = inner.read_at_without_decrypt(addr, buf)?;
+    if read < buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "only {} of {} bytes at {} to decrypt",
+                read,
+                buf.len(),
+                addr
+            ),
+        ));
+    }
+    Ok(())
 }
 
 fn zero(buf: &mut [u8]) {
@@ -316,12 +862,63 @@ fn zero(buf: &mut [u8]) {
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
     use std::convert::TryFrom;
+    use std::io;
     use std::io::Read;
 
+    use anyhow::Error;
+
     use crate::extents::Extent;
-    use crate::extents::TreeReader;
-    use crate::{InnerReader, NoneCrypto};
+    use crate::extents::{parse_node, Child, FoundPart, Layout, Node, TreeReader};
+    use crate::{Crypto, InnerReader, NoneCrypto, ReadAt};
+
+    /// Bytes which are their own address, counting reads.
+    struct Counting {
+        reads: usize,
+    }
+
+    impl ReadAt for Counting {
+        fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = (pos + i as u64) as u8;
+            }
+            Ok(buf.len())
+        }
+    }
+
+    /// Adds the position in the file to every byte, so decrypting subtracts it.
+    struct Offsetting {}
+
+    impl Crypto for Offsetting {
+        fn decrypt_filename(&self, _: &[u8], name: &[u8], _: u32) -> Result<Vec<u8>, Error> {
+            Ok(name.to_vec())
+        }
+
+        fn decrypt_page(
+            &self,
+            _context: &[u8],
+            page: &mut [u8],
+            page_offset: u64,
+            _page_addr: u64,
+            _ino: u32,
+        ) -> Result<(), Error> {
+            for (i, b) in page.iter_mut().enumerate() {
+                *b = b.wrapping_sub((page_offset + i as u64) as u8);
+            }
+            Ok(())
+        }
+    }
+
+    fn extent(part: u32, start: u64, len: u16) -> Extent {
+        Extent {
+            part,
+            start,
+            len,
+            unwritten: false,
+        }
+    }
 
     #[test]
     fn simple_tree() {
@@ -340,11 +937,13 @@ mod tests {
                     part: 0,
                     start: 10,
                     len: 1,
+                    unwritten: false,
                 },
                 Extent {
                     part: 1,
                     start: 20,
                     len: 2,
+                    unwritten: false,
                 },
             ],
             None,
@@ -358,6 +957,183 @@ mod tests {
         assert_eq!(vec![40, 41, 42, 43, 80, 81, 82, 83, 84, 85, 86, 87], res);
     }
 
+    #[test]
+    fn unwritten_extent() {
+        let crypto = NoneCrypto {};
+        let metadata_crypto = NoneCrypto {};
+
+        let cursor = std::io::Cursor::new((0..255u8).collect::<Vec<u8>>());
+        let mut data = InnerReader::new(cursor, metadata_crypto);
+        let mut reader = TreeReader::create(
+            &mut data,
+            4,
+            12,
+            vec![
+                Extent {
+                    part: 0,
+                    start: 10,
+                    len: 2,
+                    unwritten: true,
+                },
+                Extent {
+                    part: 2,
+                    start: 20,
+                    len: 1,
+                    unwritten: false,
+                },
+            ],
+            None,
+            &crypto,
+            0,
+        );
+
+        let mut res = Vec::new();
+        assert_eq!(12, reader.read_to_end(&mut res).unwrap());
+
+        assert_eq!(vec![0, 0, 0, 0, 0, 0, 0, 0, 80, 81, 82, 83], res);
+    }
+
+    #[test]
+    fn coalesced() {
+        let crypto = NoneCrypto {};
+        let mut data = InnerReader::new(Counting { reads: 0 }, NoneCrypto {});
+        // the first two are contiguous on disc, the third isn't
+        let extents = vec![extent(0, 10, 2), extent(2, 12, 3), extent(5, 30, 1)];
+        let mut reader = TreeReader::create(&mut data, 4, 4 * 6 - 1, extents, None, &crypto, 0);
+
+        let mut res = vec![0u8; 100];
+        assert_eq!(20, reader.read(&mut res).unwrap());
+        assert_eq!((40..60).collect::<Vec<u8>>()[..], res[..20]);
+        assert_eq!(3, reader.read(&mut res).unwrap());
+        assert_eq!([120, 121, 122], res[..3]);
+        drop(reader);
+        assert_eq!(2, data.inner.reads);
+    }
+
+    #[test]
+    fn encrypted() {
+        let crypto = Offsetting {};
+        let mut data = InnerReader::new(Counting { reads: 0 }, NoneCrypto {});
+        // on disc, a byte is its address, so the file is the address minus the position
+        let extents = vec![extent(0, 10, 4)];
+        let context = vec![];
+        let mut reader = TreeReader::create(&mut data, 4, 15, extents, Some(&context), &crypto, 0);
+
+        // partial blocks either side of whole ones
+        let mut res = [0u8; 11];
+        reader.read_exact_at(2, &mut res).unwrap();
+        assert_eq!([40; 11], res);
+
+        let mut res = Vec::new();
+        assert_eq!(15, reader.read_to_end(&mut res).unwrap());
+        assert_eq!(vec![40; 15], res);
+    }
+
+    #[test]
+    fn holes() {
+        // a hole at the start, one in the middle, and space allocated past the end
+        let mut layout = Layout::create(
+            4,
+            4 * 10,
+            vec![extent(2, 100, 3), extent(7, 200, 1), extent(12, 300, 2)],
+            None,
+            0,
+        );
+
+        let mut data = InnerReader::new(Counting { reads: 0 }, NoneCrypto {});
+        let runs: Vec<_> = layout
+            .map(&mut data)
+            .unwrap()
+            .mappings
+            .iter()
+            .map(|m| (m.logical, m.len, m.physical))
+            .collect();
+        assert_eq!(
+            vec![
+                (0, 2, None),
+                (2, 3, Some(100)),
+                (5, 2, None),
+                (7, 1, Some(200)),
+                (8, 2, None),
+                (12, 2, Some(300)),
+            ],
+            runs
+        );
+    }
+
+    #[test]
+    fn lazy_tree() {
+        let child = |part, block| Child {
+            part,
+            block,
+            node: None,
+        };
+        let mut root = Node::Index {
+            depth: 1,
+            children: vec![child(0, 1), child(10, 2), child(30, 3)],
+        };
+
+        let loaded = RefCell::new(Vec::new());
+        let mut load = |block, depth| {
+            assert_eq!(0, depth);
+            loaded.borrow_mut().push(block);
+            let extents = match block {
+                1 => vec![extent(0, 100, 4)],
+                2 => vec![extent(12, 200, 3)],
+                _ => vec![extent(30, 300, 1)],
+            };
+            Ok(Node::Leaf(extents))
+        };
+
+        let found = |found: FoundPart| match found {
+            FoundPart::Actual(extents) => Ok(extents[0].start),
+            FoundPart::Sparse(len) => Err(len),
+        };
+
+        // only the leaf covering the block is read, and only once
+        assert_eq!(Ok(200), found(root.find(13, u32::MAX, &mut load).unwrap()));
+        assert_eq!(Err(10), found(root.find(20, u32::MAX, &mut load).unwrap()));
+        assert_eq!(Err(5), found(root.find(5, u32::MAX, &mut load).unwrap()));
+        assert_eq!(vec![2, 1], *loaded.borrow());
+
+        let mut extents = Vec::new();
+        let mut tree_blocks = Vec::new();
+        root.collect(&mut load, &mut extents, &mut tree_blocks)
+            .unwrap();
+        assert_eq!(vec![2, 1, 3], *loaded.borrow());
+        assert_eq!(vec![1, 2, 3], tree_blocks);
+        assert_eq!(
+            vec![0, 12, 30],
+            extents.iter().map(|e| e.part).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn high_start_bits() {
+        // one entry, at depth 1 or 0, after the header
+        let node = |depth: u16, entry: [u8; 12]| {
+            let mut data = vec![0x0a, 0xf3, 1, 0, 4, 0];
+            data.extend_from_slice(&depth.to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(&entry);
+            data
+        };
+
+        // block 0x12_3456_7890: the high 16 bits, then the low 32
+        let leaf = node(0, [0, 0, 0, 0, 1, 0, 0x12, 0, 0x90, 0x78, 0x56, 0x34]);
+        match parse_node(&leaf, None, 0, None).unwrap() {
+            Node::Leaf(extents) => assert_eq!(0x12_3456_7890, extents[0].start),
+            _ => panic!("not a leaf"),
+        }
+
+        // the low 32 bits, then the high 16
+        let index = node(1, [0, 0, 0, 0, 0x90, 0x78, 0x56, 0x34, 0x12, 0, 0, 0]);
+        match parse_node(&index, None, 1, None).unwrap() {
+            Node::Index { children, .. } => assert_eq!(0x12_3456_7890, children[0].block),
+            _ => panic!("not an index"),
+        }
+    }
+
     #[test]
     fn zero_buf() {
         let mut buf = [7u8; 5];
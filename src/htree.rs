@@ -0,0 +1,488 @@
+use anyhow::ensure;
+use anyhow::Error;
+use siphasher::sip::SipHasher24;
+
+use crate::assumption_failed;
+use crate::parse::ext4_style_crc32c_le;
+use crate::read_le16;
+use crate::read_le32;
+use crate::Block;
+
+const DX_HASH_LEGACY: u8 = 0;
+const DX_HASH_HALF_MD4: u8 = 1;
+const DX_HASH_TEA: u8 = 2;
+const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+const DX_HASH_TEA_UNSIGNED: u8 = 5;
+/// For directories which are both encrypted and casefolded, with a key from the encryption.
+const DX_HASH_SIPHASH: u8 = 6;
+
+/// Only the bottom 28 bits of an index entry's block are the block; fsck uses the rest.
+const BLOCK_MASK: u32 = 0x0fff_ffff;
+
+/// Filesystem-wide settings for hashing names, from the superblock.
+#[derive(Debug, Clone)]
+pub struct Hashing {
+    /// `s_hash_seed`; all zeros means "use the default".
+    pub seed: [u32; 4],
+    /// `s_flags` says names were hashed as unsigned chars. Otherwise, they're signed, like on x86.
+    pub unsigned: bool,
+}
+
+/// The first block of an indexed ("htree") directory.
+///
+/// It starts with the "." and ".." entries, where ".." claims the rest of the block. The rest of
+/// the block is actually the root of a tree of `(hash, block)` pairs, sorted by hash. The leaves
+/// are normal directory blocks, holding the names with hashes from their pair's hash onwards.
+pub struct Root {
+    /// `dx_root_info.hash_version`, which was `s_def_hash_version` when the index was built.
+    hash_version: u8,
+    /// How many levels of interior nodes are between the root and the leaves.
+    levels: u8,
+    index: Index,
+}
+
+/// Handles a checksum mismatch in an index block, given the block's position in the directory,
+/// then the on-disc and computed checksums. It decides whether that's fatal.
+pub type Mismatch<'a> = dyn Fn(u32, u32, u32) -> Result<(), Error> + 'a;
+
+/// The checksum prefix, and what to do with mismatches.
+type Checksums<'a> = (u32, &'a Mismatch<'a>);
+
+/// The `(hash, block)` pairs from one index block. The first pair's hash is implicitly zero.
+struct Index {
+    entries: Vec<(u32, u32)>,
+}
+
+impl Root {
+    pub fn parse(
+        block: &[u8],
+        checksum_prefix: Option<u32>,
+        mismatch: &Mismatch,
+    ) -> Result<Root, Error> {
+        ensure!(
+            block.len() >= 40
+                && 12 == read_le16(&block[4..6])
+                && 1 == block[6]
+                && 2 == block[18]
+                && usize::from(read_le16(&block[16..18])) == block.len() - 12,
+            assumption_failed("index root doesn't start with '.' and '..' entries")
+        );
+
+        let info = &block[24..32];
+        let hash_version = info[4];
+        let info_length = info[5];
+        let levels = info[6];
+
+        ensure!(
+            0 == read_le32(&info[0..4]) && 8 == info_length,
+            assumption_failed(format!(
+                "unrecognised index root info, length {}",
+                info_length
+            ))
+        );
+
+        // two levels of interior nodes is only possible with `large_dir`
+        ensure!(
+            levels < 3,
+            assumption_failed(format!("index is too deep: {} levels", levels))
+        );
+
+        Ok(Root {
+            hash_version,
+            levels,
+            index: Index::parse(
+                block,
+                24 + usize::from(info_length),
+                checksum_prefix.map(|prefix| (prefix, 0, mismatch)),
+            )?,
+        })
+    }
+
+    /// Hash a name like the index did, or `None` if we don't know the algorithm, or don't have
+    /// the directory's `siphash_key`.
+    pub fn hash(
+        &self,
+        hashing: &Hashing,
+        siphash_key: Option<&[u8; 16]>,
+        name: &[u8],
+    ) -> Option<u32> {
+        let version = match self.hash_version {
+            version @ DX_HASH_LEGACY..=DX_HASH_TEA if hashing.unsigned => version + 3,
+            version => version,
+        };
+
+        hash(version, &hashing.seed, siphash_key, name)
+    }
+}
+
+/// An interior node is an empty directory block, with the index entries hidden after the header.
+pub fn is_node(block: &[u8]) -> bool {
+    block.len() >= 8
+        && 0 == read_le32(&block[0..4])
+        && usize::from(read_le16(&block[4..6])) == block.len()
+        && 0 == block[6]
+}
+
+impl Index {
+    fn parse_node(block: &[u8], checksums: Option<Checksums>, pos: u32) -> Result<Index, Error> {
+        ensure!(
+            is_node(block),
+            assumption_failed("index node doesn't start with an empty entry")
+        );
+
+        Index::parse(
+            block,
+            8,
+            checksums.map(|(prefix, mismatch)| (prefix, pos, mismatch)),
+        )
+    }
+
+    /// `limit` and `count` take the place of the first pair's hash, at `offset`.
+    /// With checksums, the prefix, and where the block is, for reporting mismatches.
+    fn parse(
+        block: &[u8],
+        offset: usize,
+        checksums: Option<(u32, u32, &Mismatch)>,
+    ) -> Result<Index, Error> {
+        ensure!(
+            block.len() >= offset + 8,
+            assumption_failed("index entries don't fit in their block")
+        );
+
+        let limit = usize::from(read_le16(&block[offset..]));
+        let count = usize::from(read_le16(&block[offset + 2..]));
+        let end = offset + limit * 8;
+
+        ensure!(
+            0 != count && count <= limit && end <= block.len(),
+            assumption_failed(format!(
+                "invalid index counts: {} of {} at {}",
+                count, limit, offset
+            ))
+        );
+
+        if let Some((checksum_prefix, pos, mismatch)) = checksums {
+            // a `dx_tail` follows the limit: a reserved field, then the checksum
+            ensure!(
+                end + 8 <= block.len(),
+                assumption_failed("directory checksums are enabled but index tail not found")
+            );
+
+            let expected = read_le32(&block[end + 4..]);
+            let computed = ext4_style_crc32c_le(checksum_prefix, &block[..offset + count * 8]);
+            let computed = ext4_style_crc32c_le(computed, &block[end..end + 4]);
+            let computed = ext4_style_crc32c_le(computed, &[0u8; 4]);
+
+            if computed != expected {
+                mismatch(pos, expected, computed)?;
+            }
+        }
+
+        let entries = block[offset..offset + count * 8]
+            .chunks(8)
+            .enumerate()
+            .map(|(i, entry)| {
+                let hash = if 0 == i { 0 } else { read_le32(&entry[0..4]) };
+                (hash, read_le32(&entry[4..8]) & BLOCK_MASK)
+            })
+            .collect();
+
+        Ok(Index { entries })
+    }
+
+    /// The last entry whose hash is not after `hash`; the first entry covers everything before.
+    fn find(&self, hash: u32) -> usize {
+        self.entries[1..].partition_point(|&(start, _)| start <= hash)
+    }
+
+    fn block(&self, pos: usize) -> u32 {
+        self.entries[pos].1
+    }
+}
+
+/// Walk from the root to the leaf blocks which could hold `hash`, giving each to `visit`, until
+/// it finds what it's looking for. `load_block` loads a block of the directory, by its position.
+pub fn search<T, L, V>(
+    root: Root,
+    hash: u32,
+    checksum_prefix: Option<u32>,
+    mismatch: &Mismatch,
+    load_block: &mut L,
+    visit: &mut V,
+) -> Result<Option<T>, Error>
+where
+    L: FnMut(u32) -> Result<Block, Error>,
+    V: FnMut(u32, &[u8]) -> Result<Option<T>, Error>,
+{
+    let checksums = checksum_prefix.map(|prefix| (prefix, mismatch));
+    let depth = usize::from(root.levels) + 1;
+    let pos = root.index.find(hash);
+    let mut path = vec![(root.index, pos)];
+
+    loop {
+        while path.len() < depth {
+            let (index, pos) = path.last().expect("never empty");
+            let block = index.block(*pos);
+            let node = Index::parse_node(&load_block(block)?, checksums, block)?;
+            let pos = node.find(hash);
+            path.push((node, pos));
+        }
+
+        let (index, pos) = path.last().expect("never empty");
+        let block = index.block(*pos);
+        if let Some(found) = visit(block, &load_block(block)?)? {
+            return Ok(Some(found));
+        }
+
+        // The following leaf continues with the same hash if there were too many collisions to
+        // fit in this one; its entry's hash has the bottom bit set. If so, we have to look there
+        // too, which may mean going back up the tree.
+        loop {
+            match path.last_mut() {
+                None => return Ok(None),
+                Some((index, pos)) if *pos + 1 < index.entries.len() => {
+                    *pos += 1;
+                    break;
+                }
+                Some(_) => {
+                    path.pop();
+                }
+            }
+        }
+
+        let (index, pos) = path.last().expect("just checked");
+        if index.entries[*pos].0 & !1 != hash {
+            return Ok(None);
+        }
+
+        // ...and back down the leftmost side of the following subtree
+        while path.len() < depth {
+            let (index, pos) = path.last().expect("never empty");
+            let block = index.block(*pos);
+            let node = Index::parse_node(&load_block(block)?, checksums, block)?;
+            path.push((node, 0));
+        }
+    }
+}
+
+/// The major hash of a name, as used in the index, or `None` if we don't know the algorithm.
+pub fn hash(
+    version: u8,
+    seed: &[u32; 4],
+    siphash_key: Option<&[u8; 16]>,
+    name: &[u8],
+) -> Option<u32> {
+    let mut buf = if seed.iter().any(|&word| 0 != word) {
+        *seed
+    } else {
+        [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476]
+    };
+
+    let hash = match version {
+        DX_HASH_LEGACY => legacy(name, true),
+        DX_HASH_LEGACY_UNSIGNED => legacy(name, false),
+        DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+            let signed = DX_HASH_HALF_MD4 == version;
+            for start in (0..name.len()).step_by(32) {
+                half_md4_transform(&mut buf, &str_to_hash_buf(&name[start..], 8, signed));
+            }
+            buf[1]
+        }
+        DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+            let signed = DX_HASH_TEA == version;
+            for start in (0..name.len()).step_by(16) {
+                tea_transform(&mut buf, &str_to_hash_buf(&name[start..], 4, signed));
+            }
+            buf[0]
+        }
+        DX_HASH_SIPHASH => {
+            // the minor hash is the bottom half
+            (SipHasher24::new_with_key(siphash_key?).hash(name) >> 32) as u32
+        }
+        _ => return None,
+    };
+
+    // the bottom bit is used to mark collisions, and the top value is reserved for end-of-directory
+    Some(match hash & !1 {
+        0xffff_fffe => 0xffff_fffc,
+        hash => hash,
+    })
+}
+
+/// The kernel does this arithmetic on `char`s, so the result depends on whether they're signed.
+fn char_value(c: u8, signed: bool) -> i32 {
+    if signed {
+        i32::from(c as i8)
+    } else {
+        i32::from(c)
+    }
+}
+
+fn legacy(name: &[u8], signed: bool) -> u32 {
+    let mut hash0: u32 = 0x12a3_fe2d;
+    let mut hash1: u32 = 0x37ab_e8f9;
+
+    for &c in name {
+        let mut hash =
+            hash1.wrapping_add(hash0 ^ char_value(c, signed).wrapping_mul(7_152_373) as u32);
+        if 0 != hash & 0x8000_0000 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+
+    hash0 << 1
+}
+
+/// Pack up to `num` words of the name, padding with a function of the remaining length.
+fn str_to_hash_buf(msg: &[u8], num: usize, signed: bool) -> Vec<u32> {
+    let len = msg.len() as u32;
+    let pad = len | (len << 8);
+    let pad = pad | (pad << 16);
+
+    let mut buf = Vec::with_capacity(num);
+    let mut val = pad;
+    for (i, &c) in msg.iter().take(num * 4).enumerate() {
+        val = (char_value(c, signed) as u32).wrapping_add(val << 8);
+        if 3 == i % 4 {
+            buf.push(val);
+            val = pad;
+        }
+    }
+
+    if buf.len() < num {
+        buf.push(val);
+    }
+
+    buf.resize(num, pad);
+    buf
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32]) {
+    let mut sum: u32 = 0;
+    let mut b0 = buf[0];
+    let mut b1 = buf[1];
+    let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+
+    for _ in 0..16 {
+        sum = sum.wrapping_add(0x9e37_79b9);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// MD4, with a reduced number of rounds.
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32]) {
+    const K1: u32 = 0;
+    const K2: u32 = 0o13_240_474_631;
+    const K3: u32 = 0o15_666_365_641;
+
+    fn f(x: u32, y: u32, z: u32) -> u32 {
+        z ^ (x & (y ^ z))
+    }
+
+    fn g(x: u32, y: u32, z: u32) -> u32 {
+        (x & y).wrapping_add((x ^ y) & z)
+    }
+
+    fn h(x: u32, y: u32, z: u32) -> u32 {
+        x ^ y ^ z
+    }
+
+    macro_rules! round {
+        ($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a
+                .wrapping_add($f($b, $c, $d))
+                .wrapping_add($x)
+                .rotate_left($s);
+        };
+    }
+
+    let [mut a, mut b, mut c, mut d] = *buf;
+    let x = |i: usize, k: u32| input[i].wrapping_add(k);
+
+    round!(f, a, b, c, d, x(0, K1), 3);
+    round!(f, d, a, b, c, x(1, K1), 7);
+    round!(f, c, d, a, b, x(2, K1), 11);
+    round!(f, b, c, d, a, x(3, K1), 19);
+    round!(f, a, b, c, d, x(4, K1), 3);
+    round!(f, d, a, b, c, x(5, K1), 7);
+    round!(f, c, d, a, b, x(6, K1), 11);
+    round!(f, b, c, d, a, x(7, K1), 19);
+
+    round!(g, a, b, c, d, x(1, K2), 3);
+    round!(g, d, a, b, c, x(3, K2), 5);
+    round!(g, c, d, a, b, x(5, K2), 9);
+    round!(g, b, c, d, a, x(7, K2), 13);
+    round!(g, a, b, c, d, x(0, K2), 3);
+    round!(g, d, a, b, c, x(2, K2), 5);
+    round!(g, c, d, a, b, x(4, K2), 9);
+    round!(g, b, c, d, a, x(6, K2), 13);
+
+    round!(h, a, b, c, d, x(3, K3), 3);
+    round!(h, d, a, b, c, x(7, K3), 9);
+    round!(h, c, d, a, b, x(2, K3), 11);
+    round!(h, b, c, d, a, x(6, K3), 15);
+    round!(h, a, b, c, d, x(1, K3), 3);
+    round!(h, d, a, b, c, x(5, K3), 9);
+    round!(h, c, d, a, b, x(0, K3), 11);
+    round!(h, b, c, d, a, x(4, K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hash;
+
+    /// `mke2fs -E hash_seed=ec346b0c-6990-4fa1-b497-a9b06a208952`
+    const SEED: [u32; 4] = [0x0c6b_34ec, 0xa14f_9069, 0xb0a9_97b4, 0x5289_206a];
+
+    #[test]
+    fn known_hashes() {
+        // from debugfs' `dx_hash -h $version -s $seed $name`
+        let long = b"a-name-longer-than-thirty-two-bytes-for-several-rounds";
+        let unicode = "ünïcödé-42".as_bytes();
+
+        let ascii = b"file-with-a-longer-name-17";
+        assert_eq!(Some(0x1808_2eea), hash(0, &SEED, None, ascii));
+        assert_eq!(Some(0x7651_e2d0), hash(0, &SEED, None, unicode));
+        assert_eq!(Some(0x39b2_2f32), hash(3, &SEED, None, unicode));
+        assert_eq!(Some(0xd303_50ce), hash(0, &SEED, None, long));
+
+        assert_eq!(Some(0xbcfd_fc48), hash(1, &SEED, None, ascii));
+        assert_eq!(Some(0x15df_f3e8), hash(1, &SEED, None, unicode));
+        assert_eq!(Some(0x331a_bdf0), hash(4, &SEED, None, unicode));
+        assert_eq!(Some(0x596b_a344), hash(1, &SEED, None, long));
+
+        assert_eq!(Some(0xdd45_500a), hash(2, &SEED, None, ascii));
+        assert_eq!(Some(0x289f_2436), hash(2, &SEED, None, unicode));
+        assert_eq!(Some(0xee49_4fba), hash(5, &SEED, None, unicode));
+        assert_eq!(Some(0xad3a_b7e6), hash(2, &SEED, None, long));
+
+        // no seed
+        assert_eq!(Some(0xb143_5ec4), hash(2, &[0; 4], None, b"abc"));
+
+        assert_eq!(None, hash(42, &SEED, None, b"abc"));
+
+        // the reference implementation's test key, and its output for an empty message
+        let mut key = [0u8; 16];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        assert_eq!(None, hash(6, &SEED, None, b""));
+        assert_eq!(Some(0x726f_db46), hash(6, &SEED, Some(&key), b""));
+    }
+}
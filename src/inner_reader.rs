@@ -1,85 +1,258 @@
+use std::fmt;
 use std::io;
-use std::io::ErrorKind;
 
 use anyhow::Error;
 
-use crate::ReadAt;
+use crate::block_cache::{BlockCache, CacheStats};
+use crate::journal::Overlay;
+use crate::{Block, ReadAt};
 
 pub trait MetadataCrypto {
     fn decrypt_page(&self, page: &mut [u8], page_addr: u64) -> Result<(), Error>;
+
+    /// Whether `decrypt_page` leaves every page as it is, so pages can be used straight from
+    /// a [`MemoryImage`](crate::MemoryImage).
+    fn is_plaintext(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug)]
 pub struct InnerReader<R: ReadAt, M: MetadataCrypto> {
     pub inner: R,
     pub metadata_crypto: M,
+    /// Blocks from the journal, when it's being replayed.
+    pub(crate) overlay: Overlay,
+    cache: BlockCache,
+    scratch: Scratch,
 }
 
+/// A buffer for blocks which are only partly wanted, kept to save allocating one every read.
+#[derive(Default)]
+struct Scratch(Vec<u8>);
+
 impl<R: ReadAt, M: MetadataCrypto> InnerReader<R, M> {
     pub fn new(inner: R, metadata_crypto: M) -> InnerReader<R, M> {
         Self {
             inner,
             metadata_crypto,
+            overlay: Overlay::default(),
+            cache: BlockCache::default(),
+            scratch: Scratch::default(),
+        }
+    }
+
+    /// Lend `f` the scratch buffer, `len` bytes long, with whatever was in it last.
+    pub(crate) fn with_scratch<T, F>(&mut self, len: usize, f: F) -> T
+    where
+        F: FnOnce(&mut Self, &mut [u8]) -> T,
+    {
+        let mut scratch = std::mem::take(&mut self.scratch.0);
+        scratch.resize(len, 0);
+        let result = f(self, &mut scratch);
+        self.scratch.0 = scratch;
+        result
+    }
+
+    /// Keep up to this many 4KiB pages of metadata, after decryption, to save reading them
+    /// again. Zero, the default, turns the cache off.
+    pub fn set_cache_capacity(&mut self, pages: usize) {
+        self.cache.set_capacity(pages);
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Forget every cached page, which has to happen if `metadata_crypto` changes.
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+    }
+
+    /// `len` bytes from `pos`, shared with the source, if it has them in memory, and they need
+    /// neither decrypting, nor replacing from the journal; otherwise read, like `read_exact_at`.
+    pub(crate) fn block(&mut self, pos: u64, len: usize) -> io::Result<Block> {
+        if self.metadata_crypto.is_plaintext() && !self.overlay.overlaps(pos, len) {
+            if let Some(block) = self.inner.block_at(pos, len) {
+                return Ok(block);
+            }
         }
+
+        let mut data = vec![0u8; len];
+        self.read_exact_at(pos, &mut data)?;
+        Ok(data.into())
     }
 
+    /// Read file contents which aren't encrypted as files, like `read_at`, but around the cache:
+    /// they'd push the metadata out, and are read in long runs, which the cache would break up.
+    pub(crate) fn read_data_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.decrypt(pos, buf, false, |reader, offset, buffer| {
+            fill_at(&mut reader.inner, offset, buffer)
+        })?;
+
+        self.overlay.apply(pos, &mut buf[..read]);
+        Ok(read)
+    }
+
+    /// Read straight from the source, for file contents which [`Crypto`](crate::Crypto)
+    /// decrypts instead. The journal's copies aren't laid over these: they've been through
+    /// `metadata_crypto`, so don't belong in raw reads, and ext4 never journals encrypted files'
+    /// contents anyway, using `data=ordered` for them even when mounted with `data=journal`.
     pub fn read_at_without_decrypt(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.read_at(pos, buf)
+        fill_at(&mut self.inner, pos, buf)
     }
 
+    /// Read `buf` through `read_fn`, a page at a time, decrypting each, and going through the
+    /// cache if `cached`. Whole pages go straight into `buf`, all at once unless the cache is in
+    /// use; only partial pages at either end go through the scratch buffer.
+    ///
+    /// Returns how much was read, which is short if the source is; a page which is only partly
+    /// there is decrypted with zeros in place of the rest, and isn't cached.
     fn decrypt<F: FnMut(&mut InnerReader<R, M>, u64, &mut [u8]) -> io::Result<usize>>(
         &mut self,
         pos: u64,
         buf: &mut [u8],
+        cached: bool,
         mut read_fn: F,
     ) -> io::Result<usize> {
-        let mut read_offset = 0;
         const CHUNK_SIZE: usize = 0x1000;
 
-        let aligned_address = (pos / CHUNK_SIZE as u64) * CHUNK_SIZE as u64;
-        let aligned_delta = (pos - aligned_address) as usize;
+        let mut done = 0;
+        while done < buf.len() {
+            let address = pos + done as u64;
+            let offset = (address % CHUNK_SIZE as u64) as usize;
+            let page_address = address - offset as u64;
+            let remaining = buf.len() - done;
 
-        let data_size = buf.len();
-        let to_read = data_size + aligned_delta;
+            if 0 != offset || remaining < CHUNK_SIZE {
+                let fits = std::cmp::min(CHUNK_SIZE - offset, remaining);
+                let wanted = &mut buf[done..done + fits];
+                let available = self.with_scratch(CHUNK_SIZE, |reader, page| {
+                    let filled = reader.load_page(page_address, page, cached, &mut read_fn)?;
+                    let available = std::cmp::min(fits, filled.saturating_sub(offset));
+                    wanted[..available].copy_from_slice(&page[offset..offset + available]);
+                    io::Result::Ok(available)
+                })?;
+                done += available;
+                if available < fits {
+                    break;
+                }
+                continue;
+            }
 
-        let to_read = if to_read % CHUNK_SIZE == 0 {
-            to_read
-        } else {
-            ((to_read / CHUNK_SIZE) * CHUNK_SIZE) + CHUNK_SIZE
-        };
+            // the cache is consulted, and filled, a page at a time
+            let cached = cached && self.cache.enabled();
+            let whole = if cached {
+                CHUNK_SIZE
+            } else {
+                remaining - remaining % CHUNK_SIZE
+            };
+            let pages = &mut buf[done..done + whole];
+            if let Some(page) = self.cache_get(cached, page_address) {
+                pages.copy_from_slice(page);
+            } else {
+                let filled = read_fn(self, page_address, pages)?;
+                let touched = filled.div_ceil(CHUNK_SIZE) * CHUNK_SIZE;
+                pages[filled..touched].fill(0);
+                for (page, address) in pages[..touched]
+                    .chunks_mut(CHUNK_SIZE)
+                    .zip((page_address..).step_by(CHUNK_SIZE))
+                {
+                    let complete = address + CHUNK_SIZE as u64 <= page_address + filled as u64;
+                    self.decrypt_page(page, address, cached && complete)?;
+                }
 
-        let mut buffer = vec![0u8; to_read];
+                if filled < whole {
+                    done += filled;
+                    break;
+                }
+            }
+            done += whole;
+        }
+
+        Ok(done)
+    }
 
-        for page in buffer.chunks_mut(CHUNK_SIZE) {
-            let page_address = aligned_address + read_offset as u64;
-            read_fn(self, page_address, page)?;
+    /// Fill a whole page, from the cache, or by reading and decrypting it, returning how much
+    /// of it the source had.
+    fn load_page<F: FnMut(&mut InnerReader<R, M>, u64, &mut [u8]) -> io::Result<usize>>(
+        &mut self,
+        page_address: u64,
+        page: &mut [u8],
+        cached: bool,
+        read_fn: &mut F,
+    ) -> io::Result<usize> {
+        if let Some(cached) = self.cache_get(cached, page_address) {
+            page.copy_from_slice(cached);
+            return Ok(page.len());
+        }
 
-            self.metadata_crypto
-                .decrypt_page(page, page_address)
-                .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
+        // the scratch buffer has whatever was read last in it
+        let filled = read_fn(self, page_address, page)?;
+        page[filled..].fill(0);
+        self.decrypt_page(page, page_address, cached && filled == page.len())?;
+        Ok(filled)
+    }
 
-            read_offset += CHUNK_SIZE;
+    fn cache_get(&mut self, cached: bool, page_address: u64) -> Option<&[u8]> {
+        if cached {
+            self.cache.get(page_address)
+        } else {
+            None
         }
+    }
 
-        buf.copy_from_slice(&buffer[aligned_delta..buf.len() + aligned_delta]);
+    fn decrypt_page(&mut self, page: &mut [u8], page_address: u64, cached: bool) -> io::Result<()> {
+        self.metadata_crypto
+            .decrypt_page(page, page_address)
+            .map_err(|error| io::Error::other(error.to_string()))?;
 
-        Ok(data_size)
+        if cached {
+            self.cache.insert(page_address, page);
+        }
+        Ok(())
     }
 }
 
 impl<R: ReadAt, M: MetadataCrypto> ReadAt for InnerReader<R, M> {
     fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
-        self.decrypt(pos, buf, |reader, offset, buffer| {
-            reader.read_at_without_decrypt(offset, buffer)
-        })
+        let read = self.decrypt(pos, buf, true, |reader, offset, buffer| {
+            fill_at(&mut reader.inner, offset, buffer)
+        })?;
+
+        // the journal's copies are already decrypted
+        self.overlay.apply(pos, &mut buf[..read]);
+        Ok(read)
     }
 
     fn read_exact_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
-        self.decrypt(pos, buf, |reader, offset, buffer| {
+        self.decrypt(pos, buf, true, |reader, offset, buffer| {
             reader.inner.read_exact_at(offset, buffer)?;
-            Ok(0)
+            Ok(buffer.len())
         })?;
 
+        self.overlay.apply(pos, buf);
         Ok(())
     }
 }
+
+/// Read as much as the source has, up to the whole buffer; some sources, like files in another
+/// image, stop short at their own boundaries.
+fn fill_at<R: ReadAt>(inner: &mut R, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match inner.read_at(pos + filled as u64, &mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+impl fmt::Debug for Scratch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Scratch({} bytes)", self.0.len())
+    }
+}
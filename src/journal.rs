@@ -0,0 +1,772 @@
+//! The jbd2 journal: a circular log of whole metadata blocks, grouped into transactions.
+//!
+//! Everything in the journal is big-endian, unlike the rest of the filesystem.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Error;
+use bitflags::bitflags;
+
+use crate::assumption_failed;
+use crate::diagnostics::{Diagnostic, Diagnostics, Structure};
+use crate::extents::Extent;
+use crate::parse::ext4_style_crc32c_le;
+use crate::unsupported_feature;
+use crate::Block;
+use crate::Time;
+
+const JBD2_MAGIC_NUMBER: u32 = 0xC03B_3998;
+
+const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+const JBD2_COMMIT_BLOCK: u32 = 2;
+const JBD2_SUPERBLOCK_V1: u32 = 3;
+const JBD2_SUPERBLOCK_V2: u32 = 4;
+const JBD2_REVOKE_BLOCK: u32 = 5;
+
+const JBD2_FLAG_ESCAPE: u32 = 1; /* on-disk block is escaped */
+const JBD2_FLAG_SAME_UUID: u32 = 2; /* block has same uuid as previous */
+const JBD2_FLAG_LAST_TAG: u32 = 8; /* last tag in this descriptor block */
+
+const JBD2_CRC32C_CHKSUM: u8 = 4;
+
+/// The block header, then `r_count`.
+const REVOKE_HEADER_LEN: usize = 16;
+
+bitflags! {
+    pub struct JournalCompatibleFeature: u32 {
+        const CHECKSUM = 0x0001;
+    }
+}
+
+bitflags! {
+    pub struct JournalIncompatibleFeature: u32 {
+        const REVOKE       = 0x0001;
+        const SIXTY_FOUR_BIT = 0x0002;
+        const ASYNC_COMMIT = 0x0004;
+        const CSUM_V2      = 0x0008;
+        const CSUM_V3      = 0x0010;
+        const FAST_COMMIT  = 0x0020;
+    }
+}
+
+/// The journal's superblock, and the transactions in its log.
+#[derive(Debug)]
+pub struct Journal {
+    pub block_size: u32,
+    /// The number of blocks in the journal, including the superblock.
+    pub max_len: u32,
+    /// The first block of the log area.
+    pub first: u32,
+    /// The sequence number of the first transaction expected in the log.
+    pub sequence: u32,
+    /// The block the log starts at; zero if there's nothing to recover.
+    pub start: u32,
+    /// An error recorded by the kernel, which it aborted the journal with.
+    pub errno: i32,
+    pub compatible_features: JournalCompatibleFeature,
+    pub incompatible_features: JournalIncompatibleFeature,
+    pub uuid: [u8; 16],
+    /// The transactions which were committed, but not checkpointed, oldest first. Transactions
+    /// which never finished committing aren't included.
+    pub transactions: Vec<Transaction>,
+}
+
+#[derive(Debug)]
+pub struct Transaction {
+    pub sequence: u32,
+    /// The journal block the transaction starts in.
+    pub start: u32,
+    /// When the commit block was written.
+    pub commit_time: Time,
+    /// New versions of filesystem blocks, in the order they were logged.
+    pub blocks: Vec<LoggedBlock>,
+    /// Filesystem blocks whose versions in earlier transactions must not be replayed.
+    pub revoked: Vec<u64>,
+}
+
+/// A copy of a filesystem block, in the journal.
+#[derive(Clone, Debug)]
+pub struct LoggedBlock {
+    /// The filesystem block this is a new version of.
+    pub target: u64,
+    /// Where the copy is, in the journal.
+    pub journal_block: u32,
+    /// Where the copy is, in the filesystem.
+    pub location: u64,
+    /// The block started with the journal's magic number, so the copy has zeros there instead.
+    pub escaped: bool,
+    /// The copy doesn't match the checksum in its tag, so, like the kernel, replaying skips it.
+    pub corrupt: bool,
+}
+
+impl LoggedBlock {
+    /// Turn the copy back into the block that was logged.
+    pub fn unescape(&self, data: &mut [u8]) {
+        if self.escaped && data.len() >= 4 {
+            data[..4].copy_from_slice(&JBD2_MAGIC_NUMBER.to_be_bytes());
+        }
+    }
+}
+
+/// Filesystem blocks as they'd be after replaying the journal, laid over what's on disc.
+#[derive(Default)]
+pub struct Overlay {
+    block_size: u64,
+    /// By their offset on disc.
+    blocks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl fmt::Debug for Overlay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Overlay {{ {} blocks }}", self.blocks.len())
+    }
+}
+
+impl Overlay {
+    /// Work out what recovery would write, like the kernel does: a later transaction's copy of
+    /// a block wins, and a block revoked in a transaction isn't replayed from that transaction,
+    /// or any before it.
+    pub fn replay<F>(journal: &Journal, mut read_logged: F) -> Result<Overlay, Error>
+    where
+        F: FnMut(&LoggedBlock) -> Result<Vec<u8>, Error>,
+    {
+        let mut revoked = HashMap::new();
+        for transaction in &journal.transactions {
+            for &block in &transaction.revoked {
+                revoked.insert(block, transaction.sequence);
+            }
+        }
+
+        let block_size = u64::from(journal.block_size);
+        let mut blocks = BTreeMap::new();
+        for transaction in &journal.transactions {
+            for logged in &transaction.blocks {
+                let is_revoked = matches!(revoked.get(&logged.target),
+                    Some(&revoked_in) if !tid_gt(transaction.sequence, revoked_in));
+                if is_revoked || logged.corrupt {
+                    continue;
+                }
+
+                let data = read_logged(logged)
+                    .with_context(|| anyhow!("replaying block {}", logged.target))?;
+                blocks.insert(logged.target * block_size, data);
+            }
+        }
+
+        Ok(Overlay { block_size, blocks })
+    }
+
+    /// The number of blocks replaced.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Whether any of the `len` bytes from `pos` on disc are replaced.
+    pub fn overlaps(&self, pos: u64, len: usize) -> bool {
+        if self.blocks.is_empty() {
+            return false;
+        }
+
+        let first = pos.saturating_sub(self.block_size - 1);
+        let end = pos + len as u64;
+        self.blocks
+            .range(first..end)
+            .any(|(&offset, data)| offset + data.len() as u64 > pos)
+    }
+
+    /// Replace whatever of `buf`, read from `pos` on disc, is covered.
+    pub fn apply(&self, pos: u64, buf: &mut [u8]) {
+        if self.blocks.is_empty() || buf.is_empty() {
+            return;
+        }
+
+        let end = pos + buf.len() as u64;
+        let first = pos.saturating_sub(self.block_size - 1);
+        for (&offset, data) in self.blocks.range(first..end) {
+            let from = pos.max(offset);
+            let to = end.min(offset + data.len() as u64);
+            if from >= to {
+                continue;
+            }
+
+            let (from, to) = ((from - pos) as usize, (to - pos) as usize);
+            let skip = (offset.max(pos) - offset) as usize;
+            buf[from..to].copy_from_slice(&data[skip..skip + to - from]);
+        }
+    }
+}
+
+/// Sequence numbers wrap, so compare them like the kernel's `tid_gt`.
+fn tid_gt(x: u32, y: u32) -> bool {
+    (x.wrapping_sub(y) as i32) > 0
+}
+
+/// Parse the journal, which lives in `extents`, reading filesystem blocks with `load_block`.
+pub fn load<F>(
+    extents: &[Extent],
+    block_size: u32,
+    verify_checksums: bool,
+    diagnostics: &Diagnostics,
+    mut load_block: F,
+) -> Result<Journal, Error>
+where
+    F: FnMut(u64) -> Result<Block, Error>,
+{
+    let locate = |block: u32| -> Result<u64, Error> {
+        extents
+            .iter()
+            .find(|e| e.part <= block && block - e.part < u32::from(e.len) && !e.unwritten)
+            .map(|e| e.start + u64::from(block - e.part))
+            .ok_or_else(|| {
+                assumption_failed(format!("journal block {} isn't mapped", block)).into()
+            })
+    };
+
+    let location = locate(0)?;
+    let raw = load_block(location)?;
+    let mut journal = parse_superblock(&raw, block_size, |on_disc, computed| {
+        let diagnostic =
+            Diagnostic::new(Structure::JournalSuperblock, on_disc, computed).block(location);
+        diagnostics.record(verify_checksums, diagnostic)
+    })
+    .context("journal superblock")?;
+
+    if 0 == journal.start {
+        return Ok(journal);
+    }
+
+    let log = Log::new(&journal, verify_checksums, diagnostics)?;
+
+    let mut block = journal.start;
+    let mut sequence = journal.sequence;
+    let mut blocks = Vec::new();
+    let mut revoked = Vec::new();
+    let mut transaction_start = block;
+
+    // every block in the log is visited at most once, unless it's corrupt
+    for _ in 0..journal.max_len {
+        let location = locate(block)?;
+        let data = load_block(location)?;
+        let (magic, block_type, header_sequence) = (
+            read_be32(&data[0..]),
+            read_be32(&data[4..]),
+            read_be32(&data[8..]),
+        );
+
+        // anything else was left over from before the log last wrapped
+        if JBD2_MAGIC_NUMBER != magic || sequence != header_sequence {
+            break;
+        }
+
+        match block_type {
+            JBD2_DESCRIPTOR_BLOCK => {
+                log.verify_tail(&data, location)
+                    .with_context(|| anyhow!("descriptor in journal block {}", block))?;
+
+                for tag in log.tags(&data)? {
+                    block = log.next(block);
+                    let location = locate(block)?;
+
+                    let corrupt = match tag.checksum {
+                        Some(expected) => {
+                            !log.data_matches(&load_block(location)?, location, sequence, expected)?
+                        }
+                        None => false,
+                    };
+
+                    blocks.push(LoggedBlock {
+                        target: tag.target,
+                        journal_block: block,
+                        location,
+                        escaped: 0 != tag.flags & JBD2_FLAG_ESCAPE,
+                        corrupt,
+                    });
+                }
+            }
+            JBD2_REVOKE_BLOCK => {
+                log.verify_tail(&data, location)
+                    .with_context(|| anyhow!("revoke block in journal block {}", block))?;
+                revoked.extend(log.revoked(&data)?);
+            }
+            JBD2_COMMIT_BLOCK => {
+                // like the kernel, a commit block which doesn't match was probably torn
+                // mid-write, so the transaction never committed
+                if !log.commit_matches(&data, location)? {
+                    break;
+                }
+
+                let commit_sec = (u64::from(read_be32(&data[0x30..])) << 32)
+                    | u64::from(read_be32(&data[0x34..]));
+                journal.transactions.push(Transaction {
+                    sequence,
+                    start: transaction_start,
+                    commit_time: Time {
+                        epoch_secs: commit_sec as i64,
+                        nanos: Some(read_be32(&data[0x38..])),
+                    },
+                    blocks: std::mem::take(&mut blocks),
+                    revoked: std::mem::take(&mut revoked),
+                });
+
+                sequence = sequence.wrapping_add(1);
+                transaction_start = log.next(block);
+            }
+            _ => break,
+        }
+
+        block = log.next(block);
+    }
+
+    Ok(journal)
+}
+
+/// `mismatch` is given the on-disc and computed checksums, if they differ, and decides whether
+/// that's fatal.
+fn parse_superblock<F>(raw: &[u8], fs_block_size: u32, mismatch: F) -> Result<Journal, Error>
+where
+    F: FnOnce(u32, u32) -> Result<(), Error>,
+{
+    ensure!(
+        raw.len() >= 1024 && JBD2_MAGIC_NUMBER == read_be32(raw),
+        assumption_failed("journal superblock magic number not found")
+    );
+
+    let block_type = read_be32(&raw[4..]);
+    ensure!(
+        JBD2_SUPERBLOCK_V1 == block_type || JBD2_SUPERBLOCK_V2 == block_type,
+        assumption_failed(format!(
+            "unexpected journal superblock type: {}",
+            block_type
+        ))
+    );
+
+    let block_size = read_be32(&raw[0x0C..]);
+    ensure!(
+        block_size == fs_block_size,
+        unsupported_feature(format!(
+            "journal block size {} isn't the filesystem's, {}",
+            block_size, fs_block_size
+        ))
+    );
+
+    let mut journal = Journal {
+        block_size,
+        max_len: read_be32(&raw[0x10..]),
+        first: read_be32(&raw[0x14..]),
+        sequence: read_be32(&raw[0x18..]),
+        start: read_be32(&raw[0x1C..]),
+        errno: read_be32(&raw[0x20..]) as i32,
+        compatible_features: JournalCompatibleFeature::empty(),
+        incompatible_features: JournalIncompatibleFeature::empty(),
+        uuid: [0; 16],
+        transactions: Vec::new(),
+    };
+    journal.uuid.copy_from_slice(&raw[0x30..0x40]);
+
+    // version 1 superblocks have no feature fields
+    if JBD2_SUPERBLOCK_V2 == block_type {
+        journal.compatible_features =
+            JournalCompatibleFeature::from_bits_truncate(read_be32(&raw[0x24..]));
+        let s_feature_incompat = read_be32(&raw[0x28..]);
+        journal.incompatible_features = JournalIncompatibleFeature::from_bits(s_feature_incompat)
+            .ok_or_else(|| {
+            unsupported_feature(format!(
+                "unrecognised journal incompatible features: {:x}",
+                s_feature_incompat
+            ))
+        })?;
+    }
+
+    ensure!(
+        0 != journal.first && journal.first < journal.max_len && journal.start < journal.max_len,
+        assumption_failed(format!(
+            "invalid journal log: {}..{}, starting at {}",
+            journal.first, journal.max_len, journal.start
+        ))
+    );
+
+    if has_checksums(journal.incompatible_features) {
+        let s_checksum_type = raw[0x50];
+        ensure!(
+            JBD2_CRC32C_CHKSUM == s_checksum_type,
+            unsupported_feature(format!("journal checksum type {}", s_checksum_type))
+        );
+
+        let expected = read_be32(&raw[0xFC..]);
+        let computed = ext4_style_crc32c_le(
+            ext4_style_crc32c_le(ext4_style_crc32c_le(!0, &raw[..0xFC]), &[0; 4]),
+            &raw[0x100..1024],
+        );
+        if computed != expected {
+            mismatch(expected, computed)?;
+        }
+    }
+
+    Ok(journal)
+}
+
+fn has_checksums(features: JournalIncompatibleFeature) -> bool {
+    features.intersects(JournalIncompatibleFeature::CSUM_V2 | JournalIncompatibleFeature::CSUM_V3)
+}
+
+/// How the log's blocks are laid out, which depends on the journal's features.
+struct Log {
+    first: u32,
+    max_len: u32,
+    block_size: usize,
+    /// `None` without v2 or v3 checksums; `CHECKSUM` (v1) journals' commits aren't verified.
+    checksum_seed: Option<u32>,
+    csum_v3: bool,
+    long_blocks: bool,
+    verify_checksums: bool,
+    diagnostics: Diagnostics,
+}
+
+struct Tag {
+    target: u64,
+    flags: u32,
+    checksum: Option<u32>,
+}
+
+impl Log {
+    fn new(
+        journal: &Journal,
+        verify_checksums: bool,
+        diagnostics: &Diagnostics,
+    ) -> Result<Log, Error> {
+        let features = journal.incompatible_features;
+        ensure!(
+            !features.contains(JournalIncompatibleFeature::FAST_COMMIT),
+            unsupported_feature("fast commits in the journal")
+        );
+
+        Ok(Log {
+            first: journal.first,
+            max_len: journal.max_len,
+            block_size: usize::try_from(journal.block_size)?,
+            checksum_seed: if has_checksums(features) {
+                Some(ext4_style_crc32c_le(!0, &journal.uuid))
+            } else {
+                None
+            },
+            csum_v3: features.contains(JournalIncompatibleFeature::CSUM_V3),
+            long_blocks: features.contains(JournalIncompatibleFeature::SIXTY_FOUR_BIT),
+            verify_checksums,
+            diagnostics: diagnostics.clone(),
+        })
+    }
+
+    /// The log is circular, skipping the superblock.
+    fn next(&self, block: u32) -> u32 {
+        if block + 1 >= self.max_len {
+            self.first
+        } else {
+            block + 1
+        }
+    }
+
+    /// Like the kernel's `journal_tag_bytes`.
+    fn tag_bytes(&self) -> usize {
+        if self.csum_v3 {
+            return 16;
+        }
+
+        // t_blocknr, t_checksum, t_flags, and t_blocknr_high, if it's used
+        let mut size = 8;
+        if self.checksum_seed.is_some() {
+            size += 2;
+        }
+        if self.long_blocks {
+            size += 4;
+        }
+        size
+    }
+
+    /// The end of the descriptor or revoke records, before any checksum tail.
+    fn usable(&self) -> usize {
+        if self.checksum_seed.is_some() {
+            self.block_size - 4
+        } else {
+            self.block_size
+        }
+    }
+
+    fn tags(&self, data: &[u8]) -> Result<Vec<Tag>, Error> {
+        let mut tags = Vec::new();
+        let mut offset = 12;
+
+        while offset + self.tag_bytes() <= self.usable() {
+            let raw = &data[offset..];
+            let (flags, high, checksum) = if self.csum_v3 {
+                (
+                    read_be32(&raw[4..]),
+                    read_be32(&raw[8..]),
+                    Some(read_be32(&raw[12..])),
+                )
+            } else {
+                (
+                    u32::from(read_be16(&raw[6..])),
+                    if self.long_blocks {
+                        read_be32(&raw[8..])
+                    } else {
+                        0
+                    },
+                    self.checksum_seed.map(|_| u32::from(read_be16(&raw[4..]))),
+                )
+            };
+
+            let high = if self.long_blocks { high } else { 0 };
+            tags.push(Tag {
+                target: u64::from(read_be32(raw)) | u64::from(high) << 32,
+                flags,
+                checksum,
+            });
+
+            offset += self.tag_bytes();
+            if 0 == flags & JBD2_FLAG_SAME_UUID {
+                offset += 16;
+            }
+
+            if 0 != flags & JBD2_FLAG_LAST_TAG {
+                return Ok(tags);
+            }
+        }
+
+        bail!(assumption_failed(
+            "journal descriptor block has no last tag"
+        ))
+    }
+
+    fn revoked(&self, data: &[u8]) -> Result<Vec<u64>, Error> {
+        let r_count = usize::try_from(read_be32(&data[12..]))?;
+        ensure!(
+            r_count >= REVOKE_HEADER_LEN && r_count <= self.usable(),
+            assumption_failed(format!("invalid journal revoke block length: {}", r_count))
+        );
+
+        let records = &data[REVOKE_HEADER_LEN..r_count];
+        Ok(if self.long_blocks {
+            records
+                .chunks_exact(8)
+                .map(|r| u64::from(read_be32(r)) << 32 | u64::from(read_be32(&r[4..])))
+                .collect()
+        } else {
+            records
+                .chunks_exact(4)
+                .map(|r| u64::from(read_be32(r)))
+                .collect()
+        })
+    }
+
+    /// Descriptor and revoke blocks end with a checksum of the rest of the block.
+    fn verify_tail(&self, data: &[u8], location: u64) -> Result<(), Error> {
+        let seed = match self.checksum_seed {
+            Some(seed) => seed,
+            None => return Ok(()),
+        };
+
+        let end = self.usable();
+        let expected = read_be32(&data[end..]);
+        let computed = ext4_style_crc32c_le(ext4_style_crc32c_le(seed, &data[..end]), &[0; 4]);
+        if computed != expected {
+            self.mismatch(Structure::JournalBlock, expected, computed, location)?;
+        }
+
+        Ok(())
+    }
+
+    /// A mismatch is recorded, and ends the log, whether or not checksums are being verified,
+    /// like the kernel's recovery, which stops at the first commit block which doesn't match.
+    fn commit_matches(&self, data: &[u8], location: u64) -> Result<bool, Error> {
+        let seed = match self.checksum_seed {
+            Some(seed) => seed,
+            None => return Ok(true),
+        };
+
+        // h_chksum[0], after h_chksum_type, h_chksum_size and padding
+        let expected = read_be32(&data[0x10..]);
+        let computed = ext4_style_crc32c_le(
+            ext4_style_crc32c_le(ext4_style_crc32c_le(seed, &data[..0x10]), &[0; 4]),
+            &data[0x14..self.block_size],
+        );
+        if computed != expected {
+            let diagnostic =
+                Diagnostic::new(Structure::JournalCommit, expected, computed).block(location);
+            self.diagnostics.record(false, diagnostic)?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Tags carry a checksum of the sequence number and the logged copy; v2 only has room for
+    /// the bottom half. A mismatch is recorded, but only costs that one copy, even when
+    /// verifying, as the kernel's recovery carries on without it.
+    fn data_matches(
+        &self,
+        data: &[u8],
+        location: u64,
+        sequence: u32,
+        expected: u32,
+    ) -> Result<bool, Error> {
+        let seed = match self.checksum_seed {
+            Some(seed) => seed,
+            None => return Ok(true),
+        };
+
+        let mut computed =
+            ext4_style_crc32c_le(ext4_style_crc32c_le(seed, &sequence.to_be_bytes()), data);
+        if !self.csum_v3 {
+            computed &= 0xFFFF;
+        }
+
+        if computed != expected {
+            let diagnostic =
+                Diagnostic::new(Structure::JournalData, expected, computed).block(location);
+            self.diagnostics.record(false, diagnostic)?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Record a mismatch in the block at `location`; fatal if checksums are being verified.
+    fn mismatch(
+        &self,
+        structure: Structure,
+        on_disc: u32,
+        computed: u32,
+        location: u64,
+    ) -> Result<(), Error> {
+        let diagnostic = Diagnostic::new(structure, on_disc, computed).block(location);
+        self.diagnostics.record(self.verify_checksums, diagnostic)
+    }
+}
+
+fn read_be32(from: &[u8]) -> u32 {
+    u32::from_be_bytes([from[0], from[1], from[2], from[3]])
+}
+
+fn read_be16(from: &[u8]) -> u16 {
+    u16::from_be_bytes([from[0], from[1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Journal, JournalCompatibleFeature, JournalIncompatibleFeature};
+    use super::{Log, JBD2_MAGIC_NUMBER};
+    use super::{LoggedBlock, Overlay, Transaction};
+    use crate::Time;
+
+    #[test]
+    fn tag_sizes() {
+        let log = |seed: Option<u32>, csum_v3, long_blocks| Log {
+            first: 1,
+            max_len: 1024,
+            block_size: 1024,
+            checksum_seed: seed,
+            csum_v3,
+            long_blocks,
+            verify_checksums: true,
+            diagnostics: Default::default(),
+        };
+
+        assert_eq!(8, log(None, false, false).tag_bytes());
+        assert_eq!(12, log(None, false, true).tag_bytes());
+        assert_eq!(14, log(Some(0), false, true).tag_bytes());
+        assert_eq!(16, log(Some(0), true, false).tag_bytes());
+        assert_eq!(16, log(Some(0), true, true).tag_bytes());
+
+        assert_eq!(1, log(None, false, false).next(1023));
+    }
+
+    fn transaction(sequence: u32, blocks: &[(u64, u32)], revoked: &[u64]) -> Transaction {
+        Transaction {
+            sequence,
+            start: 0,
+            commit_time: Time {
+                epoch_secs: 0,
+                nanos: None,
+            },
+            blocks: blocks
+                .iter()
+                .map(|&(target, journal_block)| LoggedBlock {
+                    target,
+                    journal_block,
+                    location: u64::from(journal_block),
+                    escaped: 7 == target,
+                    corrupt: false,
+                })
+                .collect(),
+            revoked: revoked.to_vec(),
+        }
+    }
+
+    #[test]
+    fn replay() {
+        let mut journal = Journal {
+            block_size: 4,
+            max_len: 100,
+            first: 1,
+            sequence: u32::MAX,
+            start: 1,
+            errno: 0,
+            compatible_features: JournalCompatibleFeature::empty(),
+            incompatible_features: JournalIncompatibleFeature::REVOKE,
+            uuid: [0; 16],
+            transactions: vec![
+                // sequence numbers wrap
+                transaction(u32::MAX, &[(1, 2), (2, 3), (3, 4), (7, 5)], &[]),
+                transaction(0, &[(1, 7)], &[2, 3]),
+                // logged again after it was revoked
+                transaction(1, &[(3, 8), (5, 10)], &[]),
+            ],
+        };
+
+        // a copy whose checksum failed; the second transaction's stays
+        journal.transactions[2].blocks.push(LoggedBlock {
+            target: 1,
+            journal_block: 11,
+            location: 11,
+            escaped: false,
+            corrupt: true,
+        });
+
+        let overlay = Overlay::replay(&journal, |logged| {
+            let mut data = vec![logged.journal_block as u8; 4];
+            logged.unescape(&mut data);
+            Ok(data)
+        })
+        .unwrap();
+
+        // 1 from the second transaction, 3 from the third, 2 not at all
+        assert_eq!(4, overlay.len());
+        let mut disc = vec![0xffu8; 40];
+        overlay.apply(0, &mut disc);
+        let mut expected = vec![0xff; 4];
+        expected.extend_from_slice(&[7; 4]);
+        expected.extend_from_slice(&[0xff; 4]);
+        expected.extend_from_slice(&[8; 4]);
+        expected.extend_from_slice(&[0xff; 4]);
+        expected.extend_from_slice(&[10; 4]);
+        expected.extend_from_slice(&[0xff; 4]);
+        expected.extend_from_slice(&JBD2_MAGIC_NUMBER.to_be_bytes());
+        expected.extend_from_slice(&[0xff; 8]);
+        assert_eq!(expected, disc);
+
+        // reads which only partly cover blocks
+        let mut part = vec![0xffu8; 6];
+        overlay.apply(6, &mut part);
+        assert_eq!(vec![7, 7, 0xff, 0xff, 0xff, 0xff], part);
+        let mut part = vec![0xffu8; 2];
+        overlay.apply(13, &mut part);
+        assert_eq!(vec![8, 8], part);
+    }
+}
@@ -23,25 +23,59 @@ use std::io;
 use std::io::{ErrorKind, Read};
 use std::io::{Seek, SeekFrom};
 
+use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::ensure;
 use anyhow::Context;
-use anyhow::Error;
-use anyhow::{anyhow, bail};
 use bitflags::bitflags;
 use byteorder::{LittleEndian, ReadBytesExt};
 
+mod allocation;
+mod block_cache;
 mod block_groups;
+mod block_map;
+mod casefold;
+mod casefold_data;
+mod diagnostics;
+mod error;
 mod extents;
+mod htree;
 
 mod inner_reader;
+mod journal;
+mod memory;
 mod none_crypto;
 /// Raw object parsing API. Not versioned / supported.
 pub mod parse;
-
-use crate::extents::TreeReader;
+mod resolve;
+mod shared;
+mod walker;
+
+pub use crate::allocation::{AllocationBitmap, Ranges};
+pub use crate::block_cache::CacheStats;
+use crate::casefold::Name;
+use crate::diagnostics::Diagnostics;
+pub use crate::diagnostics::{Diagnostic, Problem, Structure};
+use crate::error::Locate;
+pub use crate::error::{Error, Location};
+pub use crate::extents::{Extent, ExtentMap, Mapping};
+use crate::extents::{Layout, TreeReader};
+pub use crate::journal::{
+    Journal, JournalCompatibleFeature, JournalIncompatibleFeature, LoggedBlock, Transaction,
+};
+pub use crate::memory::{Block, MemoryImage};
 pub use crate::none_crypto::NoneCrypto;
+pub use crate::parse::{
+    CompatibleFeature, CompatibleFeatureReadOnly, EncodingFlags, ErrorPolicy, FilenameEncoding,
+    FilesystemState, IncompatibleFeature, SuperBlockFlags,
+};
+pub use crate::resolve::Resolve;
+pub use crate::shared::{FileReader, SharedSuperBlock};
+pub use crate::walker::{OnError, Order, Walker};
 pub use inner_reader::{InnerReader, MetadataCrypto};
 
+/// Somewhere to read a filesystem from. Anything `Read + Seek` is one, including a file opened
+/// from another filesystem, so images stored inside images can be read directly.
 pub trait ReadAt {
     /// Read bytes from an offset in this source into a buffer, returning how many bytes were read.
     ///
@@ -76,6 +110,12 @@ pub trait ReadAt {
             Ok(())
         }
     }
+
+    /// The bytes at `pos`, shared rather than copied, if this source already has them in
+    /// memory, like a [`MemoryImage`]. `None`, the default, means they have to be read.
+    fn block_at(&self, _pos: u64, _len: usize) -> Option<Block> {
+        None
+    }
 }
 
 impl<T> ReadAt for T
@@ -100,15 +140,12 @@ pub enum ParseError {
     UnsupportedFeature { reason: String },
 
     /// The request is for something which we are sure is not there.
-    #[error("filesystem uses an unsupported feature: {reason:?}")]
+    #[error("not found: {reason:?}")]
     NotFound { reason: String },
 }
 
 pub fn map_lib_error_to_io<E: ToString>(error: E) -> io::Error {
-    io::Error::new(
-        ErrorKind::Other,
-        format!("Ext4 error: {}", error.to_string()),
-    )
+    io::Error::other(format!("Ext4 error: {}", error.to_string()))
 }
 
 fn assumption_failed<S: ToString>(reason: S) -> ParseError {
@@ -163,7 +200,7 @@ bitflags! {
 }
 
 /// Flag indicating the type of file stored in this inode.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum FileType {
     RegularFile,     // S_IFREG (Regular file)
     SymbolicLink,    // S_IFLNK (Symbolic link)
@@ -172,14 +209,17 @@ pub enum FileType {
     Directory,       // S_IFDIR (Directory)
     Fifo,            // S_IFIFO (FIFO)
     Socket,          // S_IFSOCK (Socket)
+    /// Only in directory entries, which don't always say, like without the `filetype`
+    /// feature; the inode knows.
+    Unknown,
 }
 
 /// Extended, type-specific information read from an inode.
 #[derive(Debug)]
 pub enum Enhanced {
     RegularFile,
-    /// A symlink, with its decoded destination.
-    SymbolicLink(String),
+    /// A symlink, with its decoded destination, which is just bytes, like a name.
+    SymbolicLink(Vec<u8>),
     /// A 'c' device, with its major and minor numbers.
     CharacterDevice(u16, u32),
     /// A 'b' device, with its major and minor numbers.
@@ -219,15 +259,34 @@ impl FileType {
 }
 
 /// An entry in a directory, without its extra metadata.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DirEntry {
     pub inode: u32,
     pub file_type: FileType,
-    pub name: String,
+    /// The name, exactly as stored; Linux only forbids `/` and `\0`, so it may not be UTF-8.
+    pub name: Vec<u8>,
+}
+
+impl DirEntry {
+    /// The name, if it's UTF-8, as it almost always is.
+    pub fn name_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.name).ok()
+    }
+
+    /// The name, with anything which isn't UTF-8 replaced.
+    pub fn name_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
+    }
+
+    /// The name, as it would be on a Unix system with the filesystem mounted.
+    #[cfg(unix)]
+    pub fn name_os(&self) -> &std::ffi::OsStr {
+        std::os::unix::ffi::OsStrExt::from_bytes(&self.name)
+    }
 }
 
 /// Full information about a disc entry.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Stat {
     pub extracted_type: FileType,
     pub file_mode: u16,
@@ -250,7 +309,7 @@ pub trait Crypto {
         context: &[u8],
         encrypted_name: &[u8],
         ino: u32,
-    ) -> Result<Vec<u8>, Error>;
+    ) -> Result<Vec<u8>, anyhow::Error>;
 
     fn decrypt_page(
         &self,
@@ -259,16 +318,26 @@ pub trait Crypto {
         page_offset: u64,
         page_addr: u64,
         ino: u32,
-    ) -> Result<(), Error>;
+    ) -> Result<(), anyhow::Error>;
+
+    /// The key for hashing names in directories which are both encrypted and casefolded, if
+    /// it's available. Without it, finding a name means decrypting the whole directory.
+    fn dirhash_key(&self, _context: &[u8], _ino: u32) -> Result<Option<[u8; 16]>, anyhow::Error> {
+        Ok(None)
+    }
 }
 
 /// An actual disc metadata entry.
+#[derive(Clone)]
 pub struct Inode {
     pub stat: Stat,
     pub number: u32,
     flags: InodeFlags,
 
     checksum_prefix: Option<u32>,
+    verify_extents: bool,
+    verify_directories: bool,
+    diagnostics: Diagnostics,
 
     /// The other implementations call this the inode's "block", which is so unbelievably overloaded.
     /// I made up a new name.
@@ -280,16 +349,111 @@ pub struct Inode {
 #[derive(Debug)]
 pub struct SuperBlock<R: ReadAt, C: Crypto, M: MetadataCrypto> {
     inner: InnerReader<R, M>,
-    load_xattrs: bool,
     /// All* checksums are computed after concatenation with the UUID, so we keep that.
     uuid_checksum: Option<u32>,
-    uuid: [u8; 16],
     groups: block_groups::BlockGroups,
     crypto: C,
+    info: SuperBlockInfo,
+    options: Options,
+    diagnostics: Diagnostics,
 }
 
-/// A raw filesystem time.
+/// Filesystem-wide information, as recorded in the superblock.
+///
+/// Counts are as last written by the kernel, which only updates some of them lazily.
 #[derive(Debug)]
+pub struct SuperBlockInfo {
+    pub inodes_count: u32,
+    pub blocks_count: u64,
+    pub reserved_blocks_count: u64,
+    pub free_blocks_count: u64,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub block_size: u32,
+    pub cluster_size: u64,
+    pub blocks_per_group: u32,
+    pub clusters_per_group: u32,
+    pub inodes_per_group: u32,
+    pub mount_time: Time,
+    pub write_time: Time,
+    pub mount_count: u16,
+    /// Negative if mount-count based checking is disabled.
+    pub max_mount_count: i16,
+    pub state: FilesystemState,
+    pub errors: ErrorPolicy,
+    pub minor_rev_level: u16,
+    pub last_check_time: Time,
+    pub check_interval: u32,
+    pub creator_os: u32,
+    pub rev_level: u32,
+    pub default_reserved_uid: u16,
+    pub default_reserved_gid: u16,
+    /// The first inode number available for normal files.
+    pub first_inode: u32,
+    pub inode_size: u16,
+    /// The group this copy of the superblock lives in.
+    pub block_group_number: u16,
+    pub compatible_features: CompatibleFeature,
+    pub incompatible_features: IncompatibleFeature,
+    pub compatible_features_read_only: CompatibleFeatureReadOnly,
+    pub uuid: [u8; 16],
+    /// The label, up to the first NUL.
+    pub volume_name: String,
+    /// The directory this filesystem was last mounted on, up to the first NUL.
+    pub last_mounted: String,
+    pub algorithm_usage_bitmap: u32,
+    pub prealloc_blocks: u8,
+    pub prealloc_dir_blocks: u8,
+    pub reserved_gdt_blocks: u16,
+    pub journal_uuid: [u8; 16],
+    pub journal_inode: u32,
+    pub journal_device: u32,
+    /// Head of the list of inodes to delete.
+    pub last_orphan: u32,
+    pub hash_seed: [u32; 4],
+    pub default_hash_version: u8,
+    pub journal_backup_type: u8,
+    pub desc_size: u16,
+    pub default_mount_options: u32,
+    pub first_meta_bg: u32,
+    pub mkfs_time: Time,
+    /// A copy of the journal inode's `i_block` and `i_size` fields.
+    pub journal_blocks: [u32; 17],
+    pub min_extra_isize: u16,
+    pub want_extra_isize: u16,
+    pub flags: SuperBlockFlags,
+    pub raid_stride: u16,
+    pub mmp_update_interval: u16,
+    pub mmp_block: u64,
+    pub raid_stripe_width: u32,
+    pub log_groups_per_flex: u8,
+    pub checksum_type: u8,
+    /// Lifetime writes, in kilobytes.
+    pub kbytes_written: u64,
+    pub error_count: u32,
+    pub first_error_time: Option<Time>,
+    pub first_error_function: String,
+    pub last_error_time: Option<Time>,
+    pub last_error_function: String,
+    pub mount_options: String,
+    pub user_quota_inode: u32,
+    pub group_quota_inode: u32,
+    pub project_quota_inode: u32,
+    pub overhead_clusters: u32,
+    /// The groups holding backup superblocks, with `sparse_super2`.
+    pub backup_block_groups: [u32; 2],
+    pub encryption_algorithms: [u8; 4],
+    pub encryption_password_salt: [u8; 16],
+    pub lost_and_found_inode: u32,
+    pub checksum_seed: u32,
+    /// Only set with the `casefold` feature.
+    pub encoding: Option<FilenameEncoding>,
+    pub encoding_flags: EncodingFlags,
+    pub orphan_file_inode: u32,
+}
+
+/// A raw filesystem time.
+#[derive(Clone, Debug)]
 pub struct Time {
     pub epoch_secs: i64,
     pub nanos: Option<u32>,
@@ -328,21 +492,70 @@ impl Time {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
 pub enum Checksums {
+    #[default]
     Required,
     Enabled,
 }
 
-impl Default for Checksums {
-    fn default() -> Self {
-        Checksums::Required
-    }
-}
-
-#[derive(Debug, Default)]
+#[derive(Clone, Copy, Debug)]
 pub struct Options {
     pub checksums: Checksums,
+    /// If the filesystem needs recovery, read it as it will be after the journal is replayed,
+    /// by reading the journal's committed blocks in place of the stale ones. The journal is
+    /// read into memory, and nothing is ever written.
+    pub replay_journal: bool,
+
+    /// Which checksums to check, where the filesystem has them; a mismatch fails the read, as
+    /// does a structure which doesn't make sense. Otherwise, both are only recorded, in
+    /// `diagnostics`, and the read carries on past them. These all default to on with the `verify-checksums` feature, except this one, the
+    /// superblock's, which is always on by default.
+    pub verify_superblock_checksum: bool,
+    pub verify_group_descriptor_checksums: bool,
+    /// The allocation bitmaps' checksums, which are kept in the group descriptors.
+    pub verify_bitmap_checksums: bool,
+    pub verify_inode_checksums: bool,
+    /// The checksums of the blocks of the extent tree; the root is covered by the inode's.
+    pub verify_extent_checksums: bool,
+    /// Both leaf blocks and hash tree index blocks.
+    pub verify_directory_checksums: bool,
+    pub verify_xattr_checksums: bool,
+    pub verify_journal_checksums: bool,
+
+    /// Open filesystems which weren't cleanly unmounted. Off by default with the
+    /// `verify-clean-state` feature.
+    pub accept_unclean: bool,
+    /// Open filesystems which the kernel has found errors in. Off by default with the
+    /// `verify-clean-state` feature.
+    pub accept_errors: bool,
+
+    /// How many 4KiB pages of metadata to keep in memory, after decryption, in case they're
+    /// read again, like the inode tables and directories are, while walking. File contents
+    /// aren't kept. Off by default.
+    pub block_cache: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        let checksums = cfg!(feature = "verify-checksums");
+        let clean_state = cfg!(feature = "verify-clean-state");
+        Options {
+            checksums: Checksums::default(),
+            replay_journal: false,
+            verify_superblock_checksum: true,
+            verify_group_descriptor_checksums: checksums,
+            verify_bitmap_checksums: checksums,
+            verify_inode_checksums: checksums,
+            verify_extent_checksums: checksums,
+            verify_directory_checksums: checksums,
+            verify_xattr_checksums: checksums,
+            verify_journal_checksums: checksums,
+            accept_unclean: !clean_state,
+            accept_errors: !clean_state,
+            block_cache: 0,
+        }
+    }
 }
 
 impl<R: ReadAt> SuperBlock<R, NoneCrypto, NoneCrypto> {
@@ -356,6 +569,15 @@ impl<R: ReadAt> SuperBlock<R, NoneCrypto, NoneCrypto> {
     }
 }
 
+impl<'a> SuperBlock<io::Cursor<&'a [u8]>, NoneCrypto, NoneCrypto> {
+    /// Open an image which is only borrowed, like fuzz input, reading it where it is, rather
+    /// than copying all of it into a [`MemoryImage`] first. Blocks are copied out as they're
+    /// read, so for an image which is used for long, a `MemoryImage` is cheaper.
+    pub fn from_bytes(data: &'a [u8], options: &Options) -> Result<Self, Error> {
+        Self::new_with_options(io::Cursor::new(data), options)
+    }
+}
+
 impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
     pub fn new_with_crypto(
         inner: R,
@@ -366,7 +588,129 @@ impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
     }
 
     pub fn get_uuid(&self) -> &[u8; 16] {
-        &self.uuid
+        &self.info.uuid
+    }
+
+    /// Everything else the superblock has to say about the filesystem.
+    pub fn info(&self) -> &SuperBlockInfo {
+        &self.info
+    }
+
+    /// The number of block groups in the filesystem.
+    pub fn block_group_count(&self) -> u32 {
+        self.groups.groups_count()
+    }
+
+    /// The raw inode bitmap for a block group, one bit per inode in the group, after checking
+    /// its checksum. `None` if the group's inodes have never been initialised.
+    pub fn inode_bitmap(&mut self, group: u32) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.groups.inode_bitmap(&mut self.inner, group)?)
+    }
+
+    /// The raw block bitmap for a block group, one bit per cluster in the group, after checking
+    /// its checksum. `None` if the group's blocks have never been initialised.
+    pub fn block_bitmap(&mut self, group: u32) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.groups.block_bitmap(&mut self.inner, group)?)
+    }
+
+    /// Whether an inode is in use, according to its group's inode bitmap.
+    pub fn is_inode_allocated(&mut self, inode: u32) -> Result<bool, Error> {
+        if 0 == inode || inode > self.info.inodes_count {
+            return Err(not_found(format!("there is no inode <{}>", inode)).into());
+        }
+        let group = (inode - 1) / self.info.inodes_per_group;
+        let allocation = self.groups.inode_allocation(&mut self.inner, group)?;
+        Ok(allocation.is_allocated(u64::from(inode)) == Some(true))
+    }
+
+    /// Whether a block is in use, according to its group's block bitmap. Blocks before the
+    /// first group, like the boot block, always are.
+    pub fn is_block_allocated(&mut self, block: u64) -> Result<bool, Error> {
+        if block >= self.info.blocks_count {
+            return Err(not_found(format!("there is no block {}", block)).into());
+        }
+        let first_data_block = u64::from(self.info.first_data_block);
+        if block < first_data_block {
+            return Ok(true);
+        }
+        let group = (block - first_data_block) / u64::from(self.info.blocks_per_group);
+        let group = u32::try_from(group).map_err(anyhow::Error::from)?;
+        let allocation = self.groups.block_allocation(&mut self.inner, group)?;
+        Ok(allocation.is_allocated(block) == Some(true))
+    }
+
+    /// Which inodes in a block group are in use, as ranges of inode numbers.
+    pub fn inode_allocation(&mut self, group: u32) -> Result<AllocationBitmap, Error> {
+        Ok(self.groups.inode_allocation(&mut self.inner, group)?)
+    }
+
+    /// Which blocks in a block group are in use, as ranges of block numbers.
+    pub fn block_allocation(&mut self, group: u32) -> Result<AllocationBitmap, Error> {
+        Ok(self.groups.block_allocation(&mut self.inner, group)?)
+    }
+
+    /// Read the journal's superblock, and the transactions in its log; `None` if the
+    /// filesystem doesn't have a journal.
+    pub fn journal(&mut self) -> Result<Option<Journal>, Error> {
+        if !self
+            .info
+            .compatible_features
+            .contains(CompatibleFeature::HAS_JOURNAL)
+        {
+            return Ok(None);
+        }
+
+        if 0 == self.info.journal_inode {
+            return Err(unsupported_feature("journals on another device").into());
+        }
+
+        let inode = self.read_inode(self.info.journal_inode)?;
+        let extents = inode
+            .reader(&mut self.inner, &self.crypto)?
+            .extents()?
+            .to_vec();
+        let block_size = self.groups.block_size;
+        let verify_checksums = self.options.verify_journal_checksums;
+        let diagnostics = self.diagnostics.clone();
+        let journal = journal::load(
+            &extents,
+            block_size,
+            verify_checksums,
+            &diagnostics,
+            |block| self.load_disc_bytes(block),
+        )
+        .with_context(|| anyhow!("failed to load journal"))?;
+        Ok(Some(journal))
+    }
+
+    /// How the cache configured by `Options::block_cache` is doing.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.inner.cache_stats()
+    }
+
+    /// How many blocks are being read from the journal instead, with `Options::replay_journal`.
+    pub fn replayed_blocks(&self) -> usize {
+        self.inner.overlay.len()
+    }
+
+    /// The checksums which have been found not to match, so far, in the order they were read.
+    ///
+    /// Mismatches are recorded whether or not they're being verified; turn the `verify_*`
+    /// [`Options`] off to carry on reading past them, and collect them all.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.list()
+    }
+
+    /// Like [`diagnostics()`](Self::diagnostics), but forget them, too.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        self.diagnostics.take()
+    }
+
+    /// Read the version of a block which was logged in the journal.
+    pub fn read_logged_block(&mut self, logged: &LoggedBlock) -> Result<Vec<u8>, Error> {
+        let mut data = self.load_disc_bytes(logged.location)?.into_vec();
+        logged.unescape(&mut data);
+        Ok(data)
     }
 
     pub fn get_crypto_mut(&mut self) -> &mut C {
@@ -381,7 +725,9 @@ impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
         self.crypto = crypto;
     }
 
+    /// This forgets the block cache, as it may be changed.
     pub fn get_metadata_crypto_mut(&mut self) -> &mut M {
+        self.inner.clear_cache();
         &mut self.inner.metadata_crypto
     }
 
@@ -390,6 +736,7 @@ impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
     }
 
     pub fn set_metadata_crypto(&mut self, crypto: M) {
+        self.inner.clear_cache();
         self.inner.metadata_crypto = crypto;
     }
 
@@ -414,18 +761,30 @@ impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
 
     /// Load a filesystem entry by inode number.
     pub fn load_inode(&mut self, inode: u32) -> Result<Inode, Error> {
-        let data = self
-            .load_inode_bytes(inode)
-            .with_context(|| anyhow!("failed to find inode <{}> on disc", inode))?;
+        Ok(self.read_inode(inode)?)
+    }
+
+    fn read_inode(&mut self, inode: u32) -> Result<Inode, anyhow::Error> {
+        let data = self.load_inode_bytes(inode).locate(|| {
+            let message = format!("failed to find inode <{}> on disc", inode);
+            (Location::inode(inode), message)
+        })?;
 
         let uuid_checksum = self.uuid_checksum;
+        let options = self.options;
+        let diagnostics = self.diagnostics.clone();
         let parsed = parse::inode(
-            data,
+            &data,
             |block| self.load_disc_bytes(block),
             uuid_checksum,
+            &options,
+            &diagnostics,
             inode,
         )
-        .with_context(|| anyhow!("failed to parse inode <{}>", inode))?;
+        .locate(|| {
+            let message = format!("failed to parse inode <{}>", inode);
+            (Location::inode(inode), message)
+        })?;
 
         Ok(Inode {
             number: inode,
@@ -433,25 +792,27 @@ impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
             flags: parsed.flags,
             core: parsed.core,
             checksum_prefix: parsed.checksum_prefix,
+            verify_extents: self.options.verify_extent_checksums,
+            verify_directories: self.options.verify_directory_checksums,
+            diagnostics: self.diagnostics.clone(),
             block_size: self.groups.block_size,
         })
     }
 
-    fn load_inode_bytes(&mut self, inode: u32) -> Result<Vec<u8>, Error> {
-        let offset = self.groups.index_of(inode)?;
-        let mut data = vec![0u8; usize::try_from(self.groups.inode_size)?];
-        self.inner.read_exact_at(offset, &mut data)?;
-        Ok(data)
+    fn load_inode_bytes(&mut self, inode: u32) -> Result<Block, anyhow::Error> {
+        let offset = self.groups.index_of(&mut self.inner, inode)?;
+        let len = usize::from(self.groups.inode_size);
+        Ok(self.inner.block(offset, len)?)
     }
 
-    fn load_disc_bytes(&mut self, block: u64) -> Result<Vec<u8>, Error> {
+    fn load_disc_bytes(&mut self, block: u64) -> Result<Block, anyhow::Error> {
         load_disc_bytes(&mut self.inner, self.groups.block_size, block)
     }
 
     /// Load the root node of the filesystem (typically `/`).
     pub fn root(&mut self) -> Result<Inode, Error> {
         Ok(self
-            .load_inode(2)
+            .read_inode(2)
             .with_context(|| anyhow!("failed to load root inode"))?)
     }
 
@@ -462,7 +823,23 @@ impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
     where
         F: FnMut(&mut Self, &str, &Inode, &Enhanced) -> Result<bool, Error>,
     {
-        let enhanced = inode.enhance(&mut self.inner, &self.crypto)?;
+        Ok(self.walk_from(inode, path, visit)?)
+    }
+
+    fn walk_from<F>(
+        &mut self,
+        inode: &Inode,
+        path: &str,
+        visit: &mut F,
+    ) -> Result<bool, anyhow::Error>
+    where
+        F: FnMut(&mut Self, &str, &Inode, &Enhanced) -> Result<bool, Error>,
+    {
+        let enhanced = inode.enhance(&mut self.inner, &self.crypto).locate(|| {
+            let mut location = Location::path(path);
+            location.inode = Some(inode.number);
+            (location, format!("reading '{}'", path))
+        })?;
 
         if !visit(self, path, inode, &enhanced).with_context(|| anyhow!("user closure failed"))? {
             return Ok(false);
@@ -470,19 +847,22 @@ impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
 
         if let Enhanced::Directory(entries) = enhanced {
             for entry in entries {
-                if "." == entry.name || ".." == entry.name {
+                if b"." == &entry.name[..] || b".." == &entry.name[..] {
                     continue;
                 }
 
-                let child_node = self
-                    .load_inode(entry.inode)
-                    .with_context(|| anyhow!("loading {} ({:?})", entry.name, entry.file_type))?;
+                let name = entry.name_lossy();
+                let path = std::path::Path::new(path).join(&*name);
+                let path = path.to_string_lossy();
 
-                let path = std::path::Path::new(path).join(&entry.name);
+                let child_node = self.read_inode(entry.inode).locate(|| {
+                    let message = format!("loading {} ({:?})", name, entry.file_type);
+                    (Location::path(&path), message)
+                })?;
 
                 if !self
-                    .walk(&child_node, &path.to_string_lossy(), visit)
-                    .with_context(|| anyhow!("processing '{}'", entry.name))?
+                    .walk_from(&child_node, &path, visit)
+                    .with_context(|| anyhow!("processing '{}'", name))?
                 {
                     return Ok(false);
                 }
@@ -495,59 +875,122 @@ impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
         Ok(true)
     }
 
-    /// Parse a path, and find the directory entry it represents.
-    /// Note that "/foo/../bar" will be treated literally, not resolved to "/bar" then looked up.
-    pub fn resolve_path(&mut self, path: &str) -> Result<DirEntry, Error> {
-        let path = path.replace('\\', "/");
-        let path = path.trim_end_matches('/');
+    /// Parse a path, and find the directory entry it represents. The path is bytes, like the
+    /// names in it, though it's usually a `&str`.
+    ///
+    /// Every component is looked up literally, as a name in its directory: "/foo/../bar" is the
+    /// entry "bar" in the entry ".." in "foo", and symbolic links aren't followed. For what a
+    /// path means to the kernel, use [`resolve`](SuperBlock::resolve), or
+    /// [`stat`](SuperBlock::stat) and [`lstat`](SuperBlock::lstat).
+    pub fn resolve_path<P: AsRef<[u8]>>(&mut self, path: P) -> Result<DirEntry, Error> {
+        let path = path.as_ref();
+        Ok(self.find_path(path).locate(|| {
+            let path = String::from_utf8_lossy(path);
+            (Location::path(&path), format!("resolving '{}'", path))
+        })?)
+    }
+
+    fn find_path(&mut self, path: &[u8]) -> Result<DirEntry, anyhow::Error> {
+        let path = path
+            .iter()
+            .map(|&c| if b'\\' == c { b'/' } else { c })
+            .collect::<Vec<u8>>();
+        let end = path
+            .iter()
+            .rposition(|&c| b'/' != c)
+            .map_or(0, |last| last + 1);
+        let path = &path[..end];
 
         if path.is_empty() {
             // this is a bit of a lie, but it works..?
             return Ok(DirEntry {
                 inode: 2,
                 file_type: FileType::Directory,
-                name: "/".to_string(),
+                name: b"/".to_vec(),
             });
         }
 
-        let mut curr = self.root()?;
+        let mut curr = self.read_inode(2)?;
 
-        let mut parts = path.split('/').collect::<Vec<&str>>();
+        let mut parts = path.split(|&c| b'/' == c).collect::<Vec<&[u8]>>();
         let last = parts
             .pop()
-            .with_context(|| parse_error(format!("path separate failed")))?;
+            .with_context(|| parse_error("path separate failed".to_string()))?;
         for part in parts {
             if part.is_empty() {
                 continue;
             }
 
             let child_inode = self.dir_entry_named(&curr, part)?.inode;
-            curr = self.load_inode(child_inode)?;
+            curr = self.read_inode(child_inode)?;
         }
 
         self.dir_entry_named(&curr, last)
     }
 
-    fn dir_entry_named(&mut self, inode: &Inode, name: &str) -> Result<DirEntry, Error> {
-        if let Enhanced::Directory(entries) = self.enhance(inode)? {
-            if let Some(en) = entries.into_iter().find(|entry| entry.name == name) {
-                Ok(en)
-            } else {
-                Err(not_found(format!("component {} isn't there", name)).into())
-            }
+    fn dir_entry_named(&mut self, inode: &Inode, name: &[u8]) -> Result<DirEntry, anyhow::Error> {
+        ensure!(
+            FileType::Directory == inode.stat.extracted_type,
+            not_found(format!(
+                "component {} isn't a directory",
+                String::from_utf8_lossy(name)
+            ))
+        );
+
+        let hashing = if self
+            .info
+            .compatible_features
+            .contains(CompatibleFeature::DIR_INDEX)
+        {
+            Some(htree::Hashing {
+                seed: self.info.hash_seed,
+                unsigned: self.info.flags.contains(SuperBlockFlags::UNSIGNED_HASH),
+            })
         } else {
-            Err(not_found(format!("component {} isn't a directory", name)).into())
-        }
+            None
+        };
+
+        let casefold = if self
+            .info
+            .incompatible_features
+            .contains(IncompatibleFeature::CASEFOLD)
+        {
+            Some(self.info.encoding_flags)
+        } else {
+            None
+        };
+
+        inode
+            .find_entry(
+                &mut self.inner,
+                &self.crypto,
+                hashing.as_ref(),
+                casefold,
+                name,
+            )?
+            .ok_or_else(|| {
+                let name = String::from_utf8_lossy(name);
+                not_found(format!("component {} isn't there", name)).into()
+            })
     }
 
     /// Read the data from an inode. You might not want to call this on thigns that aren't regular files.
-    pub fn open<'a>(&'a mut self, inode: &'a Inode) -> Result<TreeReader<'a, R, C, M>, Error> {
-        inode.reader(&mut self.inner, &self.crypto)
+    pub fn open<'a>(&'a mut self, inode: &Inode) -> Result<TreeReader<'a, R, C, M>, Error> {
+        Ok(inode.reader(&mut self.inner, &self.crypto)?)
+    }
+
+    /// Where an inode's data is on disc, including holes, and the blocks of its extent tree.
+    pub fn extent_map(&mut self, inode: &Inode) -> Result<ExtentMap, Error> {
+        let mut layout = inode.layout(&mut self.inner)?;
+        Ok(layout.map(&mut self.inner).locate(|| {
+            let message = format!("mapping inode <{}>", inode.number);
+            (Location::inode(inode.number), message)
+        })?)
     }
 
     /// Load extra metadata about some types of entries.
     pub fn enhance(&mut self, inode: &Inode) -> Result<Enhanced, Error> {
-        inode.enhance(&mut self.inner, &self.crypto)
+        Ok(inode.enhance(&mut self.inner, &self.crypto)?)
     }
 }
 
@@ -555,54 +998,60 @@ fn load_disc_bytes<R: ReadAt, M: MetadataCrypto>(
     inner: &mut InnerReader<R, M>,
     block_size: u32,
     block: u64,
-) -> Result<Vec<u8>, Error> {
+) -> Result<Block, anyhow::Error> {
     let offset = block * u64::from(block_size);
-    let mut data = vec![0u8; usize::try_from(block_size)?];
-    inner.read_exact_at(offset, &mut data)?;
-    Ok(data)
+    Ok(inner.block(offset, usize::try_from(block_size)?)?)
 }
 
 impl Inode {
     fn reader<'a, R: ReadAt, C: Crypto, M: MetadataCrypto>(
-        &'a self,
+        &self,
         inner: &'a mut InnerReader<R, M>,
         crypto: &'a C,
-    ) -> Result<TreeReader<R, C, M>, Error> {
+    ) -> Result<TreeReader<'a, R, C, M>, anyhow::Error> {
+        let layout = self.layout(inner)?;
+        Ok(TreeReader::new(inner, layout, crypto))
+    }
+
+    /// Find where the data is, to read it.
+    fn layout<R: ReadAt, M: MetadataCrypto>(
+        &self,
+        inner: &mut InnerReader<R, M>,
+    ) -> Result<Layout, anyhow::Error> {
         let context = if matches!(self.stat.extracted_type, FileType::RegularFile) {
             self.get_encryption_context()
         } else {
             None
         };
 
-        Ok(TreeReader::new(
-            inner,
-            self.block_size,
-            self.stat.size,
-            self.core,
-            self.checksum_prefix,
-            context,
-            crypto,
-            self.number,
-        )
-        .with_context(|| anyhow!("opening inode <{}>", self.number))?)
+        Layout::new(inner, self, context).locate(|| {
+            let message = format!("opening inode <{}>", self.number);
+            (Location::inode(self.number), message)
+        })
     }
 
     fn enhance<R: ReadAt, C: Crypto, M: MetadataCrypto>(
         &self,
         inner: &mut InnerReader<R, M>,
         crypto: &C,
-    ) -> Result<Enhanced, Error> {
+    ) -> Result<Enhanced, anyhow::Error> {
         Ok(match self.stat.extracted_type {
             FileType::RegularFile => Enhanced::RegularFile,
             FileType::Socket => Enhanced::Socket,
             FileType::Fifo => Enhanced::Fifo,
 
             FileType::Directory => Enhanced::Directory(self.read_directory(inner, crypto)?),
+            FileType::Unknown => bail!(assumption_failed(format!(
+                "inode <{}> has no type",
+                self.number
+            ))),
             FileType::SymbolicLink => {
                 let allowed_flags = InodeFlags::ENCRYPT | InodeFlags::NOATIME;
                 let link_flags = self.flags & !allowed_flags;
 
                 let mut points_to = if self.stat.size < u64::try_from(INODE_CORE_SIZE)? {
+                    // the target is in the core, whether it's called "inline data" or not
+                    let link_flags = link_flags & !InodeFlags::INLINE_DATA;
                     ensure!(
                         link_flags.is_empty(),
                         unsupported_feature(format!(
@@ -617,9 +1066,9 @@ impl Inode {
                     points_to
                 } else {
                     ensure!(
-                        Self::only_relevant_flag_is_extents(link_flags),
+                        Self::data_layout_is_supported(link_flags),
                         unsupported_feature(format!(
-                            "symbolic links may not have non-extent flags: {:?}",
+                            "symbolic links may not have non-data flags: {:?}",
                             link_flags
                         ))
                     );
@@ -642,11 +1091,13 @@ impl Inode {
                         crypto.decrypt_filename(context, &encrypted_filename, self.number)?;
                 }
 
-                let points_to = std::str::from_utf8(&points_to)
-                    .with_context(|| anyhow!("symlink is invalid utf-8"))?
-                    .to_string();
+                let end = points_to
+                    .iter()
+                    .rposition(|&c| 0 != c)
+                    .map_or(0, |last| last + 1);
+                points_to.truncate(end);
 
-                Enhanced::SymbolicLink(points_to.trim_end_matches('\0').to_string())
+                Enhanced::SymbolicLink(points_to)
             }
             FileType::CharacterDevice => {
                 let (maj, min) = load_maj_min(self.core);
@@ -663,7 +1114,7 @@ impl Inode {
         &self,
         inner: &mut InnerReader<R, M>,
         crypto: &C,
-    ) -> Result<Vec<u8>, Error> {
+    ) -> Result<Vec<u8>, anyhow::Error> {
         let size = usize::try_from(self.stat.size)?;
         let mut ret = vec![0u8; size];
 
@@ -676,49 +1127,254 @@ impl Inode {
         self.stat.xattrs.get("encryption.c")
     }
 
+    /// With `INLINE_DATA`, the data is the whole core, then continues in the `system.data` xattr.
+    fn inline_data(&self) -> Vec<u8> {
+        let mut data = self.core.to_vec();
+        if let Some(rest) = self.stat.xattrs.get("system.data") {
+            data.extend_from_slice(rest);
+        }
+        data
+    }
+
+    /// Find one entry in a directory, using its index, if it has one we understand.
+    /// `casefold` is the filesystem's encoding flags, if it has casefolded directories.
+    fn find_entry<R: ReadAt, C: Crypto, M: MetadataCrypto>(
+        &self,
+        inner: &mut InnerReader<R, M>,
+        crypto: &C,
+        hashing: Option<&htree::Hashing>,
+        casefold: Option<EncodingFlags>,
+        name: &[u8],
+    ) -> Result<Option<DirEntry>, anyhow::Error> {
+        let casefold = casefold.filter(|_| self.flags.contains(InodeFlags::CASEFOLD));
+        let casefolded = casefold.is_some();
+        let strict = casefold.is_some_and(|flags| flags.contains(EncodingFlags::STRICT_MODE));
+        let wanted = casefold.map(|_| casefold::fold_bytes(name));
+
+        // strict mode stops such a name being created, like the kernel's `generic_ci_d_hash`
+        ensure!(
+            !(strict && Some(Name::Invalid) == wanted),
+            not_found(format!(
+                "{} isn't valid UTF-8, which this casefolded directory requires",
+                String::from_utf8_lossy(name)
+            ))
+        );
+
+        let matches = |entry: &DirEntry| -> Result<bool, anyhow::Error> {
+            let wanted = match &wanted {
+                Some(wanted) => wanted,
+                None => return Ok(entry.name == name),
+            };
+
+            Ok(match (wanted, casefold::fold_bytes(&entry.name)) {
+                (_, Name::Invalid) if strict => bail!(assumption_failed(format!(
+                    "name {} in casefolded directory <{}> isn't valid UTF-8, in strict mode",
+                    entry.name_lossy(),
+                    self.number
+                ))),
+                (Name::Folded(wanted), Name::Folded(found)) => found == *wanted,
+                // unlike the name wanted, it can't be folded, so it can't be the same
+                (Name::Folded(_), _) => false,
+                // can't be folded like the kernel would, so only the same bytes match
+                _ => entry.name == name,
+            })
+        };
+
+        // Folded names are hashed after folding, and unfoldable ones as they are; but names
+        // with characters newer than the kernel's tables were folded in a way we can't repeat.
+        let hashed = match &wanted {
+            None | Some(Name::Invalid) => Some(name),
+            Some(Name::Folded(folded)) => Some(&folded[..]),
+            Some(Name::Unknown) => None,
+        };
+
+        // Encrypted names are normally hashed after encryption, so we can't work out where to
+        // look. If they're also casefolded, the plain name is hashed, with a key we might have.
+        let siphash_key = match self.get_encryption_context() {
+            Some(context) if casefolded => crypto.dirhash_key(context, self.number)?,
+            _ => None,
+        };
+
+        let indexed = self.flags.contains(InodeFlags::INDEX)
+            && (self.get_encryption_context().is_none() || siphash_key.is_some());
+
+        if let (Some(hashing), Some(hashed), true) = (hashing, hashed, indexed) {
+            let block_size = u64::from(self.block_size);
+            let blocks = self.stat.size / block_size;
+            let layout = self.layout(inner)?;
+            let mut load_block = |block: u32| -> Result<Block, anyhow::Error> {
+                ensure!(
+                    u64::from(block) < blocks,
+                    assumption_failed(format!(
+                        "index points at block {}, past the end of the directory",
+                        block
+                    ))
+                );
+
+                Ok(layout.block(inner, crypto, u64::from(block))?)
+            };
+
+            let mismatch = |block: u32, on_disc, computed| {
+                let diagnostic = Diagnostic::new(Structure::DirectoryIndex, on_disc, computed)
+                    .inode(self.number)
+                    .offset(u64::from(block) * block_size);
+                self.diagnostics.record(self.verify_directories, diagnostic)
+            };
+
+            let root = htree::Root::parse(&load_block(0)?, self.checksum_prefix, &mismatch)
+                .with_context(|| anyhow!("reading index root of <{}>", self.number))?;
+
+            if let Some(hash) = root.hash(hashing, siphash_key.as_ref(), hashed) {
+                return htree::search(
+                    root,
+                    hash,
+                    self.checksum_prefix,
+                    &mismatch,
+                    &mut load_block,
+                    &mut |pos, leaf| {
+                        let mut entries = Vec::new();
+                        let offset = u64::from(pos) * block_size;
+                        self.read_directory_block(leaf, offset, &mut entries, crypto)?;
+                        find_matching(entries, &matches)
+                    },
+                )
+                .with_context(|| anyhow!("searching index of <{}>", self.number));
+            }
+        }
+
+        find_matching(self.read_directory(inner, crypto)?, &matches)
+    }
+
     fn read_directory<R: ReadAt, C: Crypto, M: MetadataCrypto>(
         &self,
         inner: &mut InnerReader<R, M>,
         crypto: &C,
-    ) -> Result<Vec<DirEntry>, Error> {
+    ) -> Result<Vec<DirEntry>, anyhow::Error> {
         let mut dirs = Vec::with_capacity(40);
 
-        let data = {
-            // if the flags, minus irrelevant flags, isn't just EXTENTS...
-            ensure!(
-                self.get_encryption_context().is_some()
-                    || Self::only_relevant_flag_is_extents(self.flags),
-                unsupported_feature(format!(
-                    "inode with unsupported flags: {0:x} {0:b}",
-                    self.flags
-                ))
-            );
-
-            self.load_all(inner, crypto)?
+        if self.flags.contains(InodeFlags::INLINE_DATA) {
+            // there's no room for "." and ".."; instead, the parent's inode number comes first
+            let data = self.inline_data();
+            dirs.push(DirEntry {
+                inode: self.number,
+                file_type: FileType::Directory,
+                name: b".".to_vec(),
+            });
+            dirs.push(DirEntry {
+                inode: read_le32(&data[0..4]),
+                file_type: FileType::Directory,
+                name: b"..".to_vec(),
+            });
+
+            self.read_directory_entries(&data[4..], 4, &mut dirs, crypto)?;
+
+            return Ok(dirs);
+        }
+
+        // if the flags, minus irrelevant flags, isn't just EXTENTS (or nothing)...
+        ensure!(
+            self.get_encryption_context().is_some() || Self::data_is_in_blocks(self.flags),
+            unsupported_feature(format!(
+                "inode with unsupported flags: {0:x} {0:b}",
+                self.flags
+            ))
+        );
+
+        let layout = self.layout(inner)?;
+
+        let indexed = self.flags.contains(InodeFlags::INDEX);
+
+        let block_size = u64::from(self.block_size);
+        for i in 0..self.stat.size.div_ceil(block_size) {
+            let block = layout.block(inner, crypto, i)?;
+            let block = &block[..];
+            if indexed && 0 == i {
+                // the index root hides after "..", which claims the rest of the block
+                self.read_directory_entries(block, 0, &mut dirs, crypto)?;
+            } else if indexed && htree::is_node(block) {
+                // an index node, which looks like an empty block; or an empty block
+            } else {
+                self.read_directory_block(block, i * block_size, &mut dirs, crypto)?;
+            }
+        }
+
+        Ok(dirs)
+    }
+
+    /// Read one block of a directory, from `offset` in it; with checksums, the block ends in a
+    /// special entry.
+    fn read_directory_block<C: Crypto>(
+        &self,
+        block: &[u8],
+        offset: u64,
+        dirs: &mut Vec<DirEntry>,
+        crypto: &C,
+    ) -> Result<(), anyhow::Error> {
+        const TAIL_LEN: usize = 12;
+
+        let checksum_prefix = match self.checksum_prefix {
+            Some(checksum_prefix) => checksum_prefix,
+            None => return self.read_directory_entries(block, offset, dirs, crypto),
         };
 
-        let total_len = data.len();
+        ensure!(
+            block.len() >= TAIL_LEN,
+            assumption_failed(format!("directory block too short: {}", block.len()))
+        );
+
+        let (entries, tail) = block.split_at(block.len() - TAIL_LEN);
+
+        // Magic entry representing the end of the list
+        ensure!(
+            0 == read_le32(&tail[0..4])
+                && TAIL_LEN == usize::from(read_le16(&tail[4..6]))
+                && 0 == tail[6]
+                && 0xDE == tail[7],
+            assumption_failed("directory checksums are enabled but checksum record not found")
+        );
+
+        let expected = read_le32(&tail[8..12]);
+        let computed = parse::ext4_style_crc32c_le(checksum_prefix, entries);
+
+        if computed != expected {
+            let diagnostic = Diagnostic::new(Structure::DirectoryBlock, expected, computed)
+                .inode(self.number)
+                .offset(offset);
+            self.diagnostics
+                .record(self.verify_directories, diagnostic)?;
+        }
 
-        let mut cursor = io::Cursor::new(data);
-        let mut read = 0usize;
-        loop {
-            let child_inode = cursor.read_u32::<LittleEndian>()?;
-            let rec_len = cursor.read_u16::<LittleEndian>()?;
+        self.read_directory_entries(entries, offset, dirs, crypto)
+    }
 
-            ensure!(
-                rec_len > 8,
-                unsupported_feature(format!(
-                    "directory record length is too short, {} must be > 8",
-                    rec_len
-                ))
-            );
+    /// Read the records in `data`, which is from `offset` in the directory. A record which
+    /// doesn't fit is recorded, and, unless directories are being verified, ends the block;
+    /// there's no telling where the next record would be.
+    fn read_directory_entries<C: Crypto>(
+        &self,
+        mut data: &[u8],
+        offset: u64,
+        dirs: &mut Vec<DirEntry>,
+        crypto: &C,
+    ) -> Result<(), anyhow::Error> {
+        let len = data.len();
+        while !data.is_empty() {
+            if let Some(reason) = bad_record(data) {
+                let diagnostic = Diagnostic::inconsistent(Structure::DirectoryBlock, reason)
+                    .inode(self.number)
+                    .offset(offset + (len - data.len()) as u64);
+                self.diagnostics
+                    .record(self.verify_directories, diagnostic)?;
+                break;
+            }
 
-            let name_len = cursor.read_u8()?;
-            let file_type = cursor.read_u8()?;
-            let mut name = vec![0u8; usize::try_from(name_len)?];
-            cursor.read_exact(&mut name)?;
+            let child_inode = read_le32(&data[0..4]);
+            let rec_len = usize::from(read_le16(&data[4..6]));
+            let name_len = usize::from(data[6]);
+            let file_type = data[7];
 
             if 0 != child_inode {
+                let name = data[8..8 + name_len].to_vec();
                 let name = if let (Some(context), false) = (
                     self.get_encryption_context(),
                     [b".".as_slice(), b"..".as_slice()].contains(&name.as_slice()),
@@ -728,68 +1384,39 @@ impl Inode {
                     name
                 };
 
-                let forbidden_chars: &[_] = &['\0'];
-                let name = std::str::from_utf8(&name)
-                    .map_err(|e| parse_error(format!("invalid utf-8 in file name: {}", e)))?
-                    .trim_end_matches(forbidden_chars);
+                let mut name = name;
+                let end = name
+                    .iter()
+                    .rposition(|&c| 0 != c)
+                    .map_or(0, |last| last + 1);
+                name.truncate(end);
 
                 dirs.push(DirEntry {
                     inode: child_inode,
-                    name: name.to_string(),
-                    file_type: FileType::from_dir_hint(file_type).ok_or_else(|| {
-                        unsupported_feature(format!(
-                            "unexpected file type in directory: {}",
-                            file_type
-                        ))
-                    })?,
+                    name,
+                    // the entry is still there, even if the hint is missing, or nonsense
+                    file_type: FileType::from_dir_hint(file_type).unwrap_or(FileType::Unknown),
                 });
-            } else if 12 == rec_len && 0 == name_len && 0xDE == file_type {
-                // Magic entry representing the end of the list
-
-                if let Some(checksum_prefix) = self.checksum_prefix {
-                    let expected = cursor.read_u32::<LittleEndian>()?;
-                    let computed =
-                        parse::ext4_style_crc32c_le(checksum_prefix, &cursor.into_inner()[0..read]);
-
-                    if computed != expected {
-                        if cfg!(feature = "verify-checksums") {
-                            bail!(assumption_failed(format!(
-                                "directory checksum mismatch: on-disk: {:08x}, computed: {:08x}",
-                                expected, computed
-                            )))
-                        }
-                    }
-                }
-
-                break;
             }
 
-            cursor.seek(io::SeekFrom::Current(
-                i64::from(rec_len) - i64::from(name_len) - 4 - 2 - 1 - 1,
-            ))?;
-
-            read += usize::try_from(rec_len)?;
-            if read >= total_len {
-                ensure!(
-                    read == total_len,
-                    assumption_failed(format!("short read, {} != {}", read, total_len))
-                );
+            data = &data[rec_len..];
+        }
 
-                ensure!(
-                    self.checksum_prefix.is_none(),
-                    assumption_failed(
-                        "directory checksums are enabled but checksum record not found"
-                    )
-                );
+        Ok(())
+    }
 
-                break;
-            }
-        }
+    /// The data is in an extent tree, or, with no relevant flags, an old-style block map.
+    fn data_is_in_blocks(flags: InodeFlags) -> bool {
+        let relevant = Self::layout_flags(flags);
+        relevant.is_empty() || relevant == InodeFlags::EXTENTS
+    }
 
-        Ok(dirs)
+    /// The data is somewhere we can read it from: in blocks, or inline in the inode.
+    fn data_layout_is_supported(flags: InodeFlags) -> bool {
+        Self::data_is_in_blocks(flags) || Self::layout_flags(flags) == InodeFlags::INLINE_DATA
     }
 
-    fn only_relevant_flag_is_extents(flags: InodeFlags) -> bool {
+    fn layout_flags(flags: InodeFlags) -> InodeFlags {
         flags
             & (InodeFlags::COMPR
                 | InodeFlags::DIRTY
@@ -803,7 +1430,6 @@ impl Inode {
                 | InodeFlags::EA_INODE
                 | InodeFlags::EOFBLOCKS
                 | InodeFlags::INLINE_DATA)
-            == InodeFlags::EXTENTS
     }
 }
 
@@ -821,6 +1447,44 @@ fn load_maj_min(core: [u8; INODE_CORE_SIZE]) -> (u16, u32) {
     }
 }
 
+/// The first of `entries` which `matches`, which can fail.
+fn find_matching<F>(entries: Vec<DirEntry>, matches: &F) -> Result<Option<DirEntry>, anyhow::Error>
+where
+    F: Fn(&DirEntry) -> Result<bool, anyhow::Error>,
+{
+    for entry in entries {
+        if matches(&entry)? {
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
+
+/// Why the directory record at the start of `data` can't be read, if it can't.
+fn bad_record(data: &[u8]) -> Option<String> {
+    if data.len() < 8 {
+        return Some(format!("short read, {} bytes left over", data.len()));
+    }
+
+    let rec_len = usize::from(read_le16(&data[4..6]));
+    let name_len = usize::from(data[6]);
+    if rec_len <= 8 {
+        Some(format!(
+            "directory record length is too short, {} must be > 8",
+            rec_len
+        ))
+    } else if 8 + name_len > rec_len || rec_len > data.len() {
+        Some(format!(
+            "directory record doesn't fit: {} byte name in {} byte record, {} available",
+            name_len,
+            rec_len,
+            data.len()
+        ))
+    } else {
+        None
+    }
+}
+
 #[inline]
 fn read_le16(from: &[u8]) -> u16 {
     use byteorder::ByteOrder;
@@ -839,6 +1503,6 @@ fn read_lei32(from: &[u8]) -> i32 {
     LittleEndian::read_i32(from)
 }
 
-fn parse_error(msg: String) -> Error {
+fn parse_error(msg: String) -> anyhow::Error {
     assumption_failed(msg).into()
 }
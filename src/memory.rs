@@ -0,0 +1,160 @@
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::ReadAt;
+
+/// An image which is already in memory, like a `Vec`, or a memory-mapped file. Metadata
+/// (inodes, extent tree nodes, directory and xattr blocks) is parsed where it is, rather than
+/// copied out a block at a time.
+///
+/// Anything which is `AsRef<[u8]>` will do, as long as it can be shared between threads, and
+/// outlive the filesystem; so a borrowed slice has to be copied in, once, unless it's `'static`.
+/// [`SuperBlock::from_bytes`](crate::SuperBlock::from_bytes) reads one where it is instead.
+///
+/// ```rust,no_run
+/// let image = ext4::MemoryImage::new(std::fs::read("disc.img").unwrap());
+/// let mut fs = ext4::SuperBlock::new(image).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct MemoryImage {
+    data: Arc<dyn AsRef<[u8]> + Send + Sync>,
+}
+
+/// Some bytes from the disc: read into a buffer, or shared with a [`MemoryImage`].
+#[derive(Clone)]
+pub struct Block(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Owned(Vec<u8>),
+    Shared {
+        image: MemoryImage,
+        start: usize,
+        end: usize,
+    },
+}
+
+impl MemoryImage {
+    pub fn new<D>(data: D) -> MemoryImage
+    where
+        D: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        MemoryImage {
+            data: Arc::new(data),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.data.as_ref().as_ref()
+    }
+}
+
+impl ReadAt for MemoryImage {
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.as_bytes();
+        let start = match usize::try_from(pos) {
+            Ok(start) if start < data.len() => start,
+            _ => return Ok(0),
+        };
+
+        let read = min(data.len() - start, buf.len());
+        buf[..read].copy_from_slice(&data[start..start + read]);
+        Ok(read)
+    }
+
+    fn block_at(&self, pos: u64, len: usize) -> Option<Block> {
+        let start = usize::try_from(pos).ok()?;
+        let end = start.checked_add(len)?;
+        if end > self.as_bytes().len() {
+            return None;
+        }
+
+        Some(Block(Repr::Shared {
+            image: self.clone(),
+            start,
+            end,
+        }))
+    }
+}
+
+impl From<Vec<u8>> for MemoryImage {
+    fn from(data: Vec<u8>) -> Self {
+        MemoryImage::new(data)
+    }
+}
+
+impl From<&'static [u8]> for MemoryImage {
+    fn from(data: &'static [u8]) -> Self {
+        MemoryImage::new(data)
+    }
+}
+
+impl fmt::Debug for MemoryImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MemoryImage({} bytes)", self.as_bytes().len())
+    }
+}
+
+impl Block {
+    /// The bytes, copying them only if they're shared.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.0 {
+            Repr::Owned(data) => data,
+            Repr::Shared { .. } => self.to_vec(),
+        }
+    }
+}
+
+impl Deref for Block {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Owned(data) => data,
+            Repr::Shared { image, start, end } => &image.as_bytes()[*start..*end],
+        }
+    }
+}
+
+impl AsRef<[u8]> for Block {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Vec<u8>> for Block {
+    fn from(data: Vec<u8>) -> Self {
+        Block(Repr::Owned(data))
+    }
+}
+
+impl fmt::Debug for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryImage;
+    use crate::ReadAt;
+
+    #[test]
+    fn shared() {
+        let mut image = MemoryImage::new((0..=255u8).collect::<Vec<u8>>());
+
+        let block = image.block_at(16, 4).expect("in range");
+        assert_eq!([16, 17, 18, 19], *block);
+        assert_eq!(image.as_bytes()[16..].as_ptr(), block.as_ptr());
+        assert!(image.block_at(254, 4).is_none());
+
+        let mut buf = [0u8; 4];
+        assert_eq!(2, image.read_at(254, &mut buf).unwrap());
+        assert_eq!([254, 255], buf[..2]);
+        assert_eq!(0, image.read_at(300, &mut buf).unwrap());
+    }
+}
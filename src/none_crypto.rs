@@ -7,6 +7,10 @@ impl MetadataCrypto for NoneCrypto {
     fn decrypt_page(&self, _page: &mut [u8], _page_addr: u64) -> Result<(), Error> {
         Ok(())
     }
+
+    fn is_plaintext(&self) -> bool {
+        true
+    }
 }
 
 impl Crypto for NoneCrypto {
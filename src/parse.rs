@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io;
-use std::io::Cursor;
 use std::io::Read;
 use std::io::Seek;
 
@@ -13,51 +12,66 @@ use anyhow::Error;
 use bitflags::bitflags;
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
 
+use crate::diagnostics::{Diagnostic, Diagnostics, Structure};
+use crate::error::{Locate, Location};
 use crate::unsupported_feature;
-use crate::ReadAt;
 use crate::Time;
 use crate::{assumption_failed, read_lei32};
 use crate::{map_lib_error_to_io, parse_error};
 use crate::{not_found, Crypto};
 use crate::{read_le16, MetadataCrypto};
 use crate::{read_le32, InnerReader};
+use crate::{Block, ReadAt};
 
 const EXT4_SUPER_MAGIC: u16 = 0xEF53;
 const INODE_BASE_LEN: usize = 128;
 const XATTR_MAGIC: u32 = 0xEA02_0000;
 
 bitflags! {
-    struct CompatibleFeature: u32 {
-        const DIR_PREALLOC  = 0x0001;
-        const IMAGIC_INODES = 0x0002;
-        const HAS_JOURNAL   = 0x0004;
-        const EXT_ATTR      = 0x0008;
-        const RESIZE_INODE  = 0x0010;
-        const DIR_INDEX     = 0x0020;
-        const SPARSE_SUPER2 = 0x0200;
+    /// Features which older implementations can safely ignore (`s_feature_compat`).
+    pub struct CompatibleFeature: u32 {
+        const DIR_PREALLOC   = 0x0001;
+        const IMAGIC_INODES  = 0x0002;
+        const HAS_JOURNAL    = 0x0004;
+        const EXT_ATTR       = 0x0008;
+        const RESIZE_INODE   = 0x0010;
+        const DIR_INDEX      = 0x0020;
+        const LAZY_BG        = 0x0040;
+        const EXCLUDE_INODE  = 0x0080;
+        const EXCLUDE_BITMAP = 0x0100;
+        const SPARSE_SUPER2  = 0x0200;
+        const FAST_COMMIT    = 0x0400;
+        const STABLE_INODES  = 0x0800;
+        const ORPHAN_FILE    = 0x1000; /* Orphan file exists */
     }
 }
 
 bitflags! {
-    struct CompatibleFeatureReadOnly: u32 {
-        const SPARSE_SUPER  = 0x0001;
-        const LARGE_FILE    = 0x0002;
-        const BTREE_DIR     = 0x0004;
-        const HUGE_FILE     = 0x0008;
-        const GDT_CSUM      = 0x0010;
-        const DIR_NLINK     = 0x0020;
-        const EXTRA_ISIZE   = 0x0040;
-        const QUOTA         = 0x0100;
-        const BIGALLOC      = 0x0200;
-        const METADATA_CSUM = 0x0400;
-        const READONLY      = 0x1000;
-        const PROJECT       = 0x2000;
-
+    /// Features which older implementations may only read (`s_feature_ro_compat`).
+    pub struct CompatibleFeatureReadOnly: u32 {
+        const SPARSE_SUPER   = 0x0001;
+        const LARGE_FILE     = 0x0002;
+        const BTREE_DIR      = 0x0004;
+        const HUGE_FILE      = 0x0008;
+        const GDT_CSUM       = 0x0010;
+        const DIR_NLINK      = 0x0020;
+        const EXTRA_ISIZE    = 0x0040;
+        const HAS_SNAPSHOT   = 0x0080;
+        const QUOTA          = 0x0100;
+        const BIGALLOC       = 0x0200;
+        const METADATA_CSUM  = 0x0400;
+        const REPLICA        = 0x0800;
+        const READONLY       = 0x1000;
+        const PROJECT        = 0x2000;
+        const SHARED_BLOCKS  = 0x4000;
+        const VERITY         = 0x8000;
+        const ORPHAN_PRESENT = 0x10000; /* Orphan file may be non-empty */
     }
 }
 
 bitflags! {
-    struct IncompatibleFeature: u32 {
+    /// Features which must be understood to read the filesystem at all (`s_feature_incompat`).
+    pub struct IncompatibleFeature: u32 {
        const COMPRESSION    = 0x0001;
        const FILETYPE       = 0x0002;
        const RECOVER        = 0x0004; /* Needs recovery */
@@ -77,6 +91,48 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The `s_state` field: how the filesystem was left.
+    pub struct FilesystemState: u16 {
+        const VALID     = 0x0001; /* Unmounted cleanly */
+        const ERROR     = 0x0002; /* Errors detected */
+        const ORPHAN    = 0x0004; /* Orphans being recovered */
+        const FC_REPLAY = 0x0020; /* Fast commit replay ongoing */
+    }
+}
+
+bitflags! {
+    /// The `s_flags` field.
+    pub struct SuperBlockFlags: u32 {
+        const SIGNED_HASH   = 0x0001; /* Signed dirhash in use */
+        const UNSIGNED_HASH = 0x0002; /* Unsigned dirhash in use */
+        const TEST_FILESYS  = 0x0004; /* to test development code */
+    }
+}
+
+bitflags! {
+    /// The `s_encoding_flags` field.
+    pub struct EncodingFlags: u16 {
+        const STRICT_MODE = 0x0001; /* Reject invalid names in casefolded directories */
+    }
+}
+
+/// How names in casefolded directories are compared (`s_encoding`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilenameEncoding {
+    Utf8_12_1,
+    Unknown(u16),
+}
+
+/// What the kernel should do when it detects an error (`s_errors`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    Continue,
+    RemountReadOnly,
+    Panic,
+    Unknown(u16),
+}
+
 pub fn superblock<R: ReadAt, C: Crypto, M: MetadataCrypto>(
     raw_reader: R,
     options: &crate::Options,
@@ -84,37 +140,81 @@ pub fn superblock<R: ReadAt, C: Crypto, M: MetadataCrypto>(
     metadata_crypto: M,
 ) -> Result<crate::SuperBlock<R, C, M>, Error> {
     let mut reader = InnerReader::new(raw_reader, metadata_crypto);
+    reader.set_cache_capacity(options.block_cache);
+    let diagnostics = Diagnostics::default();
+    let mut fs = load_superblock(reader, options, &diagnostics, crypto)?;
+
+    if !options.replay_journal
+        || !fs
+            .info
+            .incompatible_features
+            .contains(IncompatibleFeature::RECOVER)
+    {
+        return Ok(fs);
+    }
+
+    // The journal is found through the stale metadata. Everything is then loaded again, as
+    // the superblock and group descriptors are likely to be in the journal, too.
+    let journal = fs
+        .journal()?
+        .ok_or_else(|| assumption_failed("filesystem needs recovery, but has no journal"))?;
+    let overlay =
+        crate::journal::Overlay::replay(&journal, |logged| Ok(fs.read_logged_block(logged)?))
+            .with_context(|| anyhow!("failed to replay journal"))?;
+
+    let crate::SuperBlock {
+        mut inner, crypto, ..
+    } = fs;
+    inner.overlay = overlay;
+
+    // Drop the mismatches found in the stale metadata. The replay may have fixed them, and any
+    // it didn't fix are found again when everything is reloaded. Keep the journal's own
+    // mismatches, as the journal isn't read again.
+    diagnostics.retain(|found| {
+        matches!(
+            found.structure,
+            Structure::JournalSuperblock
+                | Structure::JournalBlock
+                | Structure::JournalData
+                | Structure::JournalCommit
+        )
+    });
+
+    let mut fs = load_superblock(inner, options, &diagnostics, crypto)?;
+    // as the kernel would, once it's replayed
+    fs.info
+        .incompatible_features
+        .remove(IncompatibleFeature::RECOVER);
+    Ok(fs)
+}
+
+fn load_superblock<R: ReadAt, C: Crypto, M: MetadataCrypto>(
+    mut reader: InnerReader<R, M>,
+    options: &crate::Options,
+    diagnostics: &Diagnostics,
+    crypto: C,
+) -> Result<crate::SuperBlock<R, C, M>, Error> {
     let mut entire_superblock = [0u8; 1024];
     reader.read_exact_at(1024, &mut entire_superblock)?;
 
     let mut inner = io::Cursor::new(&mut entire_superblock[..]);
 
     // <a cut -c 9- | fgrep ' s_' | fgrep -v ERR_ | while read ty nam comment; do printf "let %s =\n  inner.read_%s::<LittleEndian>()?; %s\n" $(echo $nam | tr -d ';') $(echo $ty | sed 's/__le/u/; s/__//') $comment; done
-    //    let s_inodes_count =
-    inner.read_u32::<LittleEndian>()?; /* Inodes count */
+    let s_inodes_count = inner.read_u32::<LittleEndian>()?; /* Inodes count */
     let s_blocks_count_lo = inner.read_u32::<LittleEndian>()?; /* Blocks count */
-    //    let s_r_blocks_count_lo =
-    inner.read_u32::<LittleEndian>()?; /* Reserved blocks count */
-    //    let s_free_blocks_count_lo =
-    inner.read_u32::<LittleEndian>()?; /* Free blocks count */
-    //    let s_free_inodes_count =
-    inner.read_u32::<LittleEndian>()?; /* Free inodes count */
+    let s_r_blocks_count_lo = inner.read_u32::<LittleEndian>()?; /* Reserved blocks count */
+    let s_free_blocks_count_lo = inner.read_u32::<LittleEndian>()?; /* Free blocks count */
+    let s_free_inodes_count = inner.read_u32::<LittleEndian>()?; /* Free inodes count */
     let s_first_data_block = inner.read_u32::<LittleEndian>()?; /* First Data Block */
     let s_log_block_size = inner.read_u32::<LittleEndian>()?; /* Block size */
-    //    let s_log_cluster_size =
-    inner.read_u32::<LittleEndian>()?; /* Allocation cluster size */
+    let s_log_cluster_size = inner.read_u32::<LittleEndian>()?; /* Allocation cluster size */
     let s_blocks_per_group = inner.read_u32::<LittleEndian>()?; /* # Blocks per group */
-    //    let s_clusters_per_group =
-    inner.read_u32::<LittleEndian>()?; /* # Clusters per group */
+    let s_clusters_per_group = inner.read_u32::<LittleEndian>()?; /* # Clusters per group */
     let s_inodes_per_group = inner.read_u32::<LittleEndian>()?; /* # Inodes per group */
-    //    let s_mtime =
-    inner.read_u32::<LittleEndian>()?; /* Mount time */
-    //    let s_wtime =
-    inner.read_u32::<LittleEndian>()?; /* Write time */
-    //    let s_mnt_count =
-    inner.read_u16::<LittleEndian>()?; /* Mount count */
-    //    let s_max_mnt_count =
-    inner.read_u16::<LittleEndian>()?; /* Maximal mount count */
+    let s_mtime = inner.read_u32::<LittleEndian>()?; /* Mount time */
+    let s_wtime = inner.read_u32::<LittleEndian>()?; /* Write time */
+    let s_mnt_count = inner.read_u16::<LittleEndian>()?; /* Mount count */
+    let s_max_mnt_count = inner.read_i16::<LittleEndian>()?; /* Maximal mount count */
     let s_magic = inner.read_u16::<LittleEndian>()?; /* Magic signature */
 
     ensure!(
@@ -123,14 +223,10 @@ pub fn superblock<R: ReadAt, C: Crypto, M: MetadataCrypto>(
     );
 
     let s_state = inner.read_u16::<LittleEndian>()?; /* File system state */
-    //    let s_errors =
-    inner.read_u16::<LittleEndian>()?; /* Behaviour when detecting errors */
-    //    let s_minor_rev_level =
-    inner.read_u16::<LittleEndian>()?; /* minor revision level */
-    //    let s_lastcheck =
-    inner.read_u32::<LittleEndian>()?; /* time of last check */
-    //    let s_checkinterval =
-    inner.read_u32::<LittleEndian>()?; /* max. time between checks */
+    let s_errors = inner.read_u16::<LittleEndian>()?; /* Behaviour when detecting errors */
+    let s_minor_rev_level = inner.read_u16::<LittleEndian>()?; /* minor revision level */
+    let s_lastcheck = inner.read_u32::<LittleEndian>()?; /* time of last check */
+    let s_checkinterval = inner.read_u32::<LittleEndian>()?; /* max. time between checks */
     let s_creator_os = inner.read_u32::<LittleEndian>()?; /* OS */
 
     ensure!(
@@ -142,21 +238,15 @@ pub fn superblock<R: ReadAt, C: Crypto, M: MetadataCrypto>(
     );
 
     let s_rev_level = inner.read_u32::<LittleEndian>()?; /* Revision level */
-    //    let s_def_resuid =
-    inner.read_u16::<LittleEndian>()?; /* Default uid for reserved blocks */
-    //    let s_def_resgid =
-    inner.read_u16::<LittleEndian>()?; /* Default gid for reserved blocks */
-    //    let s_first_ino =
-    inner.read_u32::<LittleEndian>()?; /* First non-reserved inode */
+    let s_def_resuid = inner.read_u16::<LittleEndian>()?; /* Default uid for reserved blocks */
+    let s_def_resgid = inner.read_u16::<LittleEndian>()?; /* Default gid for reserved blocks */
+    let s_first_ino = inner.read_u32::<LittleEndian>()?; /* First non-reserved inode */
     let s_inode_size = inner.read_u16::<LittleEndian>()?; /* size of inode structure */
-    //    let s_block_group_nr =
-    inner.read_u16::<LittleEndian>()?; /* block group # of this superblock */
+    let s_block_group_nr = inner.read_u16::<LittleEndian>()?; /* block group # of this superblock */
     let s_feature_compat = inner.read_u32::<LittleEndian>()?; /* compatible feature set */
 
     let compatible_features = CompatibleFeature::from_bits_truncate(s_feature_compat);
 
-    let load_xattrs = compatible_features.contains(CompatibleFeature::EXT_ATTR);
-
     let s_feature_incompat = inner.read_u32::<LittleEndian>()?; /* incompatible feature set */
 
     let incompatible_features =
@@ -170,10 +260,13 @@ pub fn superblock<R: ReadAt, C: Crypto, M: MetadataCrypto>(
     let supported_incompatible_features = IncompatibleFeature::FILETYPE
         | IncompatibleFeature::EXTENTS
         | IncompatibleFeature::FLEX_BG
+        | IncompatibleFeature::META_BG
         | IncompatibleFeature::RECOVER
         | IncompatibleFeature::SIXTY_FOUR_BIT
         | IncompatibleFeature::ENCRYPT
-        | IncompatibleFeature::CASEFOLD;
+        | IncompatibleFeature::CSUM_SEED
+        | IncompatibleFeature::CASEFOLD
+        | IncompatibleFeature::INLINE_DATA;
 
     if incompatible_features.intersects(!supported_incompatible_features) {
         return Err(parse_error(format!(
@@ -209,62 +302,111 @@ pub fn superblock<R: ReadAt, C: Crypto, M: MetadataCrypto>(
     inner.read_exact(&mut s_volume_name)?; /* volume name */
     let mut s_last_mounted = [0u8; 64];
     inner.read_exact(&mut s_last_mounted)?; /* directory where last mounted */
-    //    let s_algorithm_usage_bitmap =
-    inner.read_u32::<LittleEndian>()?; /* For compression */
-    //    let s_prealloc_blocks =
-    inner.read_u8()?; /* Nr of blocks to try to preallocate*/
-    //    let s_prealloc_dir_blocks =
-    inner.read_u8()?; /* Nr to preallocate for dirs */
-    //    let s_reserved_gdt_blocks =
-    inner.read_u16::<LittleEndian>()?; /* Per group desc for online growth */
+    let s_algorithm_usage_bitmap = inner.read_u32::<LittleEndian>()?; /* For compression */
+    let s_prealloc_blocks = inner.read_u8()?; /* Nr of blocks to try to preallocate*/
+    let s_prealloc_dir_blocks = inner.read_u8()?; /* Nr to preallocate for dirs */
+    let s_reserved_gdt_blocks = inner.read_u16::<LittleEndian>()?; /* Per group desc for online growth */
     let mut s_journal_uuid = [0u8; 16];
     inner.read_exact(&mut s_journal_uuid)?; /* uuid of journal superblock */
-    //    let s_journal_inum =
-    inner.read_u32::<LittleEndian>()?; /* inode number of journal file */
-    //    let s_journal_dev =
-    inner.read_u32::<LittleEndian>()?; /* device number of journal file */
-    //    let s_last_orphan =
-    inner.read_u32::<LittleEndian>()?; /* start of list of inodes to delete */
-    let mut s_hash_seed = [0u8; 4 * 4];
-    inner.read_exact(&mut s_hash_seed)?; /* HTREE hash seed */
-    //    let s_def_hash_version =
-    inner.read_u8()?; /* Default hash version to use */
-    //    let s_jnl_backup_type =
-    inner.read_u8()?;
+    let s_journal_inum = inner.read_u32::<LittleEndian>()?; /* inode number of journal file */
+    let s_journal_dev = inner.read_u32::<LittleEndian>()?; /* device number of journal file */
+    let s_last_orphan = inner.read_u32::<LittleEndian>()?; /* start of list of inodes to delete */
+    let mut s_hash_seed = [0u32; 4];
+    inner.read_u32_into::<LittleEndian>(&mut s_hash_seed)?; /* HTREE hash seed */
+    let s_def_hash_version = inner.read_u8()?; /* Default hash version to use */
+    let s_jnl_backup_type = inner.read_u8()?;
     let s_desc_size = inner.read_u16::<LittleEndian>()?; /* size of group descriptor */
-    //    let s_default_mount_opts =
-    inner.read_u32::<LittleEndian>()?;
-    //    let s_first_meta_bg =
-    inner.read_u32::<LittleEndian>()?; /* First metablock block group */
-    //    let s_mkfs_time =
-    inner.read_u32::<LittleEndian>()?; /* When the filesystem was created */
-    let mut s_jnl_blocks = [0; 17 * 4];
-    inner.read_exact(&mut s_jnl_blocks)?; /* Backup of the journal inode */
-    let s_blocks_count_hi = if long_structs {
-        Some(inner.read_u32::<LittleEndian>()?) /* Blocks count */
-    } else {
-        None
+    let s_default_mount_opts = inner.read_u32::<LittleEndian>()?;
+    let s_first_meta_bg = inner.read_u32::<LittleEndian>()?; /* First metablock block group */
+    let s_mkfs_time = inner.read_u32::<LittleEndian>()?; /* When the filesystem was created */
+    let mut s_jnl_blocks = [0u32; 17];
+    inner.read_u32_into::<LittleEndian>(&mut s_jnl_blocks)?; /* Backup of the journal inode */
+
+    // the high halves are only meaningful with the 64bit feature, but are always present
+    let s_blocks_count_hi = inner.read_u32::<LittleEndian>()?; /* Blocks count */
+    let s_r_blocks_count_hi = inner.read_u32::<LittleEndian>()?; /* Reserved blocks count */
+    let s_free_blocks_count_hi = inner.read_u32::<LittleEndian>()?; /* Free blocks count */
+    let s_min_extra_isize = inner.read_u16::<LittleEndian>()?; /* All inodes have at least # bytes */
+    let s_want_extra_isize = inner.read_u16::<LittleEndian>()?; /* New inodes should reserve # bytes */
+    let s_flags = inner.read_u32::<LittleEndian>()?; /* Miscellaneous flags */
+    let s_raid_stride = inner.read_u16::<LittleEndian>()?; /* RAID stride */
+    let s_mmp_update_interval = inner.read_u16::<LittleEndian>()?; /* # seconds to wait in MMP checking */
+    let s_mmp_block = inner.read_u64::<LittleEndian>()?; /* Block for multi-mount protection */
+    let s_raid_stripe_width = inner.read_u32::<LittleEndian>()?; /* blocks on all data disks (N*stride)*/
+    let s_log_groups_per_flex = inner.read_u8()?; /* FLEX_BG group size */
+    let s_checksum_type = inner.read_u8()?; /* metadata checksum algorithm used */
+    //    let s_encryption_level =
+    inner.read_u8()?; /* versioning level for encryption */
+    //    let s_reserved_pad =
+    inner.read_u8()?; /* Padding to next 32bits */
+    let s_kbytes_written = inner.read_u64::<LittleEndian>()?; /* nr of lifetime kilobytes written */
+    //    let s_snapshot_inum =
+    inner.read_u32::<LittleEndian>()?; /* Inode number of active snapshot */
+    //    let s_snapshot_id =
+    inner.read_u32::<LittleEndian>()?; /* sequential ID of active snapshot */
+    //    let s_snapshot_r_blocks_count =
+    inner.read_u64::<LittleEndian>()?; /* reserved blocks for active snapshot's future use */
+    //    let s_snapshot_list =
+    inner.read_u32::<LittleEndian>()?; /* inode number of the head of the on-disk snapshot list */
+    let s_error_count = inner.read_u32::<LittleEndian>()?; /* number of fs errors */
+    let s_first_error_time = inner.read_u32::<LittleEndian>()?; /* first time an error happened */
+    //    let s_first_error_ino =
+    inner.read_u32::<LittleEndian>()?; /* inode involved in first error */
+    //    let s_first_error_block =
+    inner.read_u64::<LittleEndian>()?; /* block involved of first error */
+    let mut s_first_error_func = [0u8; 32];
+    inner.read_exact(&mut s_first_error_func)?; /* function where the error happened */
+    //    let s_first_error_line =
+    inner.read_u32::<LittleEndian>()?; /* line number where error happened */
+    let s_last_error_time = inner.read_u32::<LittleEndian>()?; /* most recent time of an error */
+    //    let s_last_error_ino =
+    inner.read_u32::<LittleEndian>()?; /* inode involved in last error */
+    //    let s_last_error_line =
+    inner.read_u32::<LittleEndian>()?; /* line number where error happened */
+    //    let s_last_error_block =
+    inner.read_u64::<LittleEndian>()?; /* block involved of last error */
+    let mut s_last_error_func = [0u8; 32];
+    inner.read_exact(&mut s_last_error_func)?; /* function where the error happened */
+    let mut s_mount_opts = [0u8; 64];
+    inner.read_exact(&mut s_mount_opts)?;
+    let s_usr_quota_inum = inner.read_u32::<LittleEndian>()?; /* inode for tracking user quota */
+    let s_grp_quota_inum = inner.read_u32::<LittleEndian>()?; /* inode for tracking group quota */
+    let s_overhead_clusters = inner.read_u32::<LittleEndian>()?; /* overhead blocks/clusters in fs */
+    let mut s_backup_bgs = [0u32; 2];
+    inner.read_u32_into::<LittleEndian>(&mut s_backup_bgs)?; /* groups with sparse_super2 SBs */
+    let mut s_encrypt_algos = [0u8; 4];
+    inner.read_exact(&mut s_encrypt_algos)?; /* Encryption algorithms in use  */
+    let mut s_encrypt_pw_salt = [0u8; 16];
+    inner.read_exact(&mut s_encrypt_pw_salt)?; /* Salt used for string2key algorithm */
+    let s_lpf_ino = inner.read_u32::<LittleEndian>()?; /* Location of the lost+found inode */
+    let s_prj_quota_inum = inner.read_u32::<LittleEndian>()?; /* inode for tracking project quota */
+    let s_checksum_seed = inner.read_u32::<LittleEndian>()?; /* crc32c(uuid) if csum_seed set */
+    let s_wtime_hi = inner.read_u8()?;
+    let s_mtime_hi = inner.read_u8()?;
+    let s_mkfs_time_hi = inner.read_u8()?;
+    let s_lastcheck_hi = inner.read_u8()?;
+    let s_first_error_time_hi = inner.read_u8()?;
+    let s_last_error_time_hi = inner.read_u8()?;
+    //    let s_first_error_errcode =
+    inner.read_u8()?;
+    //    let s_last_error_errcode =
+    inner.read_u8()?;
+    let s_encoding = inner.read_u16::<LittleEndian>()?; /* Filename charset encoding */
+    let s_encoding_flags = inner.read_u16::<LittleEndian>()?; /* Filename charset encoding flags */
+    let s_orphan_file_inum = inner.read_u32::<LittleEndian>()?; /* Inode for tracking orphan inodes */
+
+    let encoding = match s_encoding {
+        0 => None,
+        1 => Some(FilenameEncoding::Utf8_12_1),
+        other => Some(FilenameEncoding::Unknown(other)),
     };
-    ////    let s_r_blocks_count_hi =
-    //        if !long_structs { None } else {
-    //            Some(inner.read_u32::<LittleEndian>()?) /* Reserved blocks count */
-    //        };
-    ////    let s_free_blocks_count_hi =
-    //        if !long_structs { None } else {
-    //            Some(inner.read_u32::<LittleEndian>()?) /* Free blocks count */
-    //        };
-    ////    let s_min_extra_isize =
-    //        if !long_structs { None } else {
-    //            Some(inner.read_u16::<LittleEndian>()?) /* All inodes have at least # bytes */
-    //        };
-    ////    let s_want_extra_isize =
-    //        if !long_structs { None } else {
-    //            Some(inner.read_u16::<LittleEndian>()?) /* New inodes should reserve # bytes */
-    //        };
-    ////    let s_flags =
-    //        if !long_structs { None } else {
-    //            Some(inner.read_u32::<LittleEndian>()?) /* Miscellaneous flags */
-    //        };
+
+    if incompatible_features.contains(IncompatibleFeature::CASEFOLD) {
+        ensure!(
+            Some(FilenameEncoding::Utf8_12_1) == encoding,
+            unsupported_feature(format!("filename encoding {:?}", encoding))
+        );
+    }
 
     // TODO: check s_checksum_type == 1 (crc32c)
 
@@ -272,27 +414,26 @@ pub fn superblock<R: ReadAt, C: Crypto, M: MetadataCrypto>(
         inner.seek(io::SeekFrom::End(-4))?;
         let s_checksum = inner.read_u32::<LittleEndian>()?;
         let expected = ext4_style_crc32c_le(!0, &inner.into_inner()[..(1024 - 4)]);
-        ensure!(
-            s_checksum == expected,
-            assumption_failed(format!(
-                "superblock reports checksums supported, but didn't match: {:x} != {:x}",
-                s_checksum, expected
-            ))
-        );
+        if s_checksum != expected {
+            let diagnostic = Diagnostic::new(Structure::Superblock, s_checksum, expected);
+            diagnostics.record(options.verify_superblock_checksum, diagnostic)?;
+        }
     }
 
-    {
-        const S_STATE_UNMOUNTED_CLEANLY: u16 = 0b01;
-        const S_STATE_ERRORS_DETECTED: u16 = 0b10;
-
-        if s_state & S_STATE_UNMOUNTED_CLEANLY == 0 || s_state & S_STATE_ERRORS_DETECTED != 0 {
-            if cfg!(feature = "verify-clean-state") {
-                return Err(parse_error(format!(
-                    "filesystem is not in a clean state: {:b}",
-                    s_state
-                )));
-            }
-        }
+    let state = FilesystemState::from_bits_truncate(s_state);
+
+    if !state.contains(FilesystemState::VALID) && !options.accept_unclean {
+        return Err(parse_error(format!(
+            "filesystem is not in a clean state: {:b}",
+            s_state
+        )));
+    }
+
+    if state.contains(FilesystemState::ERROR) && !options.accept_errors {
+        return Err(parse_error(format!(
+            "filesystem has recorded errors: {:b}",
+            s_state
+        )));
     }
 
     if 0 == s_inodes_per_group {
@@ -317,54 +458,162 @@ pub fn superblock<R: ReadAt, C: Crypto, M: MetadataCrypto>(
         unsupported_feature(format!("rev level {}", s_rev_level))
     );
 
-    let group_table_pos = if 1024 == block_size {
-        // for 1k blocks, the table is in the third block, after:
-        1024   // boot sector
-            + 1024 // superblock
+    let blocks_count = u64::from(s_blocks_count_lo)
+        | if long_structs {
+            u64::from(s_blocks_count_hi) << 32
+        } else {
+            0
+        };
+
+    let uuid_checksum = if has_checksums {
+        Some(
+            if incompatible_features.contains(IncompatibleFeature::CSUM_SEED) {
+                s_checksum_seed
+            } else {
+                ext4_style_crc32c_le(!0, &uuid)
+            },
+        )
     } else {
-        // for other blocks, the boot sector is in the first 1k of the first block,
-        // followed by the superblock (also in first block), and the group table is afterwards
-        block_size
+        None
     };
 
-    let blocks_count = (u64::from(s_blocks_count_lo)
-        + (u64::from(s_blocks_count_hi.unwrap_or(0)) << 32)
-        - u64::from(s_first_data_block)
-        + u64::from(s_blocks_per_group)
-        - 1)
-        / u64::from(s_blocks_per_group);
-
-    let mut raw_groups = vec![0u8; (blocks_count * block_size as u64) as usize];
-    reader.read_at(u64::from(group_table_pos), &mut raw_groups)?;
-    let mut grouper = Cursor::new(&mut raw_groups);
+    let high_half = |hi: u32| {
+        if long_structs {
+            u64::from(hi) << 32
+        } else {
+            0
+        }
+    };
 
-    let groups = crate::block_groups::BlockGroups::new(
-        &mut grouper,
+    let info = crate::SuperBlockInfo {
+        inodes_count: s_inodes_count,
         blocks_count,
-        s_desc_size,
-        s_inodes_per_group,
+        reserved_blocks_count: u64::from(s_r_blocks_count_lo) | high_half(s_r_blocks_count_hi),
+        free_blocks_count: u64::from(s_free_blocks_count_lo) | high_half(s_free_blocks_count_hi),
+        free_inodes_count: s_free_inodes_count,
+        first_data_block: s_first_data_block,
         block_size,
-        s_inode_size,
-        long_structs,
-    )?;
-
-    let uuid_checksum = if has_checksums {
-        // TODO: check s_checksum_seed
-        Some(ext4_style_crc32c_le(!0, &uuid))
-    } else {
-        None
+        cluster_size: 1024u64.checked_shl(s_log_cluster_size).ok_or_else(|| {
+            parse_error(format!(
+                "unexpected cluster size: 2^{}",
+                u64::from(s_log_cluster_size) + 10
+            ))
+        })?,
+        blocks_per_group: s_blocks_per_group,
+        clusters_per_group: s_clusters_per_group,
+        inodes_per_group: s_inodes_per_group,
+        mount_time: superblock_time(s_mtime, s_mtime_hi),
+        write_time: superblock_time(s_wtime, s_wtime_hi),
+        mount_count: s_mnt_count,
+        max_mount_count: s_max_mnt_count,
+        state,
+        errors: match s_errors {
+            1 => ErrorPolicy::Continue,
+            2 => ErrorPolicy::RemountReadOnly,
+            3 => ErrorPolicy::Panic,
+            other => ErrorPolicy::Unknown(other),
+        },
+        minor_rev_level: s_minor_rev_level,
+        last_check_time: superblock_time(s_lastcheck, s_lastcheck_hi),
+        check_interval: s_checkinterval,
+        creator_os: s_creator_os,
+        rev_level: s_rev_level,
+        default_reserved_uid: s_def_resuid,
+        default_reserved_gid: s_def_resgid,
+        first_inode: s_first_ino,
+        inode_size: s_inode_size,
+        block_group_number: s_block_group_nr,
+        compatible_features,
+        incompatible_features,
+        compatible_features_read_only,
+        uuid,
+        volume_name: nul_terminated(&s_volume_name),
+        last_mounted: nul_terminated(&s_last_mounted),
+        algorithm_usage_bitmap: s_algorithm_usage_bitmap,
+        prealloc_blocks: s_prealloc_blocks,
+        prealloc_dir_blocks: s_prealloc_dir_blocks,
+        reserved_gdt_blocks: s_reserved_gdt_blocks,
+        journal_uuid: s_journal_uuid,
+        journal_inode: s_journal_inum,
+        journal_device: s_journal_dev,
+        last_orphan: s_last_orphan,
+        hash_seed: s_hash_seed,
+        default_hash_version: s_def_hash_version,
+        journal_backup_type: s_jnl_backup_type,
+        desc_size: s_desc_size,
+        default_mount_options: s_default_mount_opts,
+        first_meta_bg: s_first_meta_bg,
+        mkfs_time: superblock_time(s_mkfs_time, s_mkfs_time_hi),
+        journal_blocks: s_jnl_blocks,
+        min_extra_isize: s_min_extra_isize,
+        want_extra_isize: s_want_extra_isize,
+        flags: SuperBlockFlags::from_bits_truncate(s_flags),
+        raid_stride: s_raid_stride,
+        mmp_update_interval: s_mmp_update_interval,
+        mmp_block: s_mmp_block,
+        raid_stripe_width: s_raid_stripe_width,
+        log_groups_per_flex: s_log_groups_per_flex,
+        checksum_type: s_checksum_type,
+        kbytes_written: s_kbytes_written,
+        error_count: s_error_count,
+        first_error_time: optional_time(s_first_error_time, s_first_error_time_hi),
+        first_error_function: nul_terminated(&s_first_error_func),
+        last_error_time: optional_time(s_last_error_time, s_last_error_time_hi),
+        last_error_function: nul_terminated(&s_last_error_func),
+        mount_options: nul_terminated(&s_mount_opts),
+        user_quota_inode: s_usr_quota_inum,
+        group_quota_inode: s_grp_quota_inum,
+        project_quota_inode: s_prj_quota_inum,
+        overhead_clusters: s_overhead_clusters,
+        backup_block_groups: s_backup_bgs,
+        encryption_algorithms: s_encrypt_algos,
+        encryption_password_salt: s_encrypt_pw_salt,
+        lost_and_found_inode: s_lpf_ino,
+        checksum_seed: s_checksum_seed,
+        encoding,
+        encoding_flags: EncodingFlags::from_bits_truncate(s_encoding_flags),
+        orphan_file_inode: s_orphan_file_inum,
     };
 
+    let groups = crate::block_groups::BlockGroups::new(
+        &mut reader,
+        &info,
+        uuid_checksum,
+        options,
+        diagnostics,
+    )?;
+
     Ok(crate::SuperBlock {
         inner: reader,
-        load_xattrs,
-        uuid,
         uuid_checksum,
         groups,
         crypto,
+        info,
+        options: *options,
+        diagnostics: diagnostics.clone(),
     })
 }
 
+fn superblock_time(lo: u32, hi: u8) -> Time {
+    Time {
+        epoch_secs: i64::from(lo) | (i64::from(hi) << 32),
+        nanos: None,
+    }
+}
+
+fn optional_time(lo: u32, hi: u8) -> Option<Time> {
+    if 0 == lo && 0 == hi {
+        None
+    } else {
+        Some(superblock_time(lo, hi))
+    }
+}
+
+fn nul_terminated(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| 0 == b).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
 pub struct ParsedInode {
     pub stat: crate::Stat,
     pub flags: crate::InodeFlags,
@@ -373,13 +622,15 @@ pub struct ParsedInode {
 }
 
 pub fn inode<F>(
-    mut data: Vec<u8>,
+    data: &[u8],
     load_block: F,
     uuid_checksum: Option<u32>,
+    options: &crate::Options,
+    diagnostics: &Diagnostics,
     number: u32,
 ) -> Result<ParsedInode, Error>
 where
-    F: FnOnce(u64) -> Result<Vec<u8>, Error>,
+    F: FnOnce(u64) -> Result<Block, Error>,
 {
     ensure!(
         data.len() >= INODE_BASE_LEN,
@@ -419,7 +670,7 @@ where
     } else {
         read_le16(&data[0x80..0x82])
     };
-    let inode_end = INODE_BASE_LEN + usize::try_from(i_extra_isize)?;
+    let inode_end = INODE_BASE_LEN + usize::from(i_extra_isize);
 
     ensure!(
         inode_end <= data.len(),
@@ -465,44 +716,39 @@ where
     let mut checksum_prefix = None;
 
     if let Some(uuid_checksum) = uuid_checksum {
-        data[0x7C] = 0;
-        data[0x7D] = 0;
-
         let mut bytes = [0u8; 8];
         LittleEndian::write_u32(&mut bytes[0..4], number);
         LittleEndian::write_u32(&mut bytes[4..8], i_generation);
 
         let checksum_prefix_crc32c_le = ext4_style_crc32c_le(uuid_checksum, &bytes);
 
-        if i_checksum_hi.is_some() {
-            data[0x82] = 0;
-            data[0x83] = 0;
-        }
-
-        let computed = ext4_style_crc32c_le(checksum_prefix_crc32c_le, &data);
+        // the checksum covers the inode as if the checksum itself were zeros
+        let computed = if i_checksum_hi.is_some() {
+            crc32c_zeroing(checksum_prefix_crc32c_le, data, &[(0x7C, 2), (0x82, 2)])
+        } else {
+            crc32c_zeroing(checksum_prefix_crc32c_le, data, &[(0x7C, 2)])
+        };
         checksum_prefix = Some(checksum_prefix_crc32c_le);
 
         if let Some(high) = i_checksum_hi {
             let expected = u32::from(l_i_checksum_lo) | (u32::from(high) << 16);
 
             if computed != expected {
-                if cfg!(feature = "verify-checksums") {
-                    bail!(assumption_failed(format!(
-                        "full checksum mismatch: on-disc: {:08x} computed: {:08x}",
-                        expected, computed
-                    )))
-                }
+                let diagnostic =
+                    Diagnostic::new(Structure::Inode, expected, computed).inode(number);
+                diagnostics.record(options.verify_inode_checksums, diagnostic)?;
             }
         } else {
             let short_computed = u16::try_from(computed & 0xFFFF).map_err(map_lib_error_to_io)?;
 
             if short_computed != l_i_checksum_lo {
-                if cfg!(feature = "verify-checksums") {
-                    bail!(assumption_failed(format!(
-                        "short checksum mismatch: on-disc: {:04x} computed: {:04x}",
-                        l_i_checksum_lo, short_computed
-                    )))
-                }
+                let diagnostic = Diagnostic::new(
+                    Structure::Inode,
+                    u32::from(l_i_checksum_lo),
+                    u32::from(short_computed),
+                )
+                .inode(number);
+                diagnostics.record(options.verify_inode_checksums, diagnostic)?;
             }
         }
     }
@@ -518,8 +764,23 @@ where
     if 0 != i_file_acl_lo || 0 != l_i_file_acl_high {
         let block = u64::from(i_file_acl_lo) | (u64::from(l_i_file_acl_high) << 32);
 
-        xattr_block(&mut xattrs, load_block(block)?, uuid_checksum, block)
-            .with_context(|| anyhow!("loading xattr block {}", block))?
+        let mismatch = |on_disc, computed| {
+            let diagnostic = Diagnostic::new(Structure::XattrBlock, on_disc, computed)
+                .inode(number)
+                .block(block);
+            diagnostics.record(options.verify_xattr_checksums, diagnostic)
+        };
+        xattr_block(
+            &mut xattrs,
+            &load_block(block)?,
+            uuid_checksum,
+            block,
+            mismatch,
+        )
+        .locate(|| {
+            let message = format!("loading xattr block {}", block);
+            (Location::block(block), message)
+        })?
     }
 
     let stat = crate::Stat {
@@ -548,12 +809,16 @@ where
     })
 }
 
-fn xattr_block(
+fn xattr_block<F>(
     xattrs: &mut HashMap<String, Vec<u8>>,
-    mut data: Vec<u8>,
+    data: &[u8],
     uuid_checksum: Option<u32>,
     block_number: u64,
-) -> Result<(), Error> {
+    mismatch: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(u32, u32) -> Result<(), Error>,
+{
     ensure!(
         data.len() > 0x20,
         assumption_failed("xattr block is way too short")
@@ -571,23 +836,14 @@ fn xattr_block(
     // [some reserved fields]
 
     if let Some(uuid_checksum) = uuid_checksum {
-        data[0x10] = 0;
-        data[0x11] = 0;
-        data[0x12] = 0;
-        data[0x13] = 0;
-
         let mut bytes = [0u8; 8];
         LittleEndian::write_u64(&mut bytes[0..8], block_number);
 
         let base = ext4_style_crc32c_le(uuid_checksum, &bytes);
-        let computed = ext4_style_crc32c_le(base, &data);
-        ensure!(
-            x_checksum == computed,
-            assumption_failed(format!(
-                "xattr block checksum invalid: on-disk: {:08x}, computed: {:08x}",
-                x_checksum, computed
-            ))
-        );
+        let computed = crc32c_zeroing(base, data, &[(0x10, 4)]);
+        if x_checksum != computed {
+            mismatch(x_checksum, computed)?;
+        }
     }
 
     ensure!(
@@ -598,7 +854,20 @@ fn xattr_block(
         ))
     );
 
-    read_xattrs(xattrs, &data[0x20..], &data[..])
+    read_xattrs(xattrs, &data[0x20..], data)
+}
+
+/// The checksum of `data`, as if the `zeroed` fields, as (offset, length), in order, were
+/// zeros, without copying it.
+fn crc32c_zeroing(seed: u32, data: &[u8], zeroed: &[(usize, usize)]) -> u32 {
+    let mut crc = seed;
+    let mut done = 0;
+    for &(offset, len) in zeroed {
+        crc = ext4_style_crc32c_le(crc, &data[done..offset]);
+        crc = ext4_style_crc32c_le(crc, &[0; 4][..len]);
+        done = offset + len;
+    }
+    ext4_style_crc32c_le(crc, &data[done..])
 }
 
 fn read_xattrs(
@@ -623,7 +892,7 @@ fn read_xattrs(
         let e_value_size = read_le32(&reading[0x08..0x0C]);
         //        let e_hash              = read_le32(&reading[0x0C..0x10]);
 
-        let end_of_name = 0x10 + usize::try_from(e_name_len)?;
+        let end_of_name = 0x10 + usize::from(e_name_len);
 
         ensure!(
             reading.len() > end_of_name,
@@ -652,7 +921,7 @@ fn read_xattrs(
             std::str::from_utf8(name_suffix).with_context(|| anyhow!("name is invalid utf-8"))?
         );
 
-        let start = usize::try_from(e_value_offset)?;
+        let start = usize::from(e_value_offset);
         let end = start + usize::try_from(e_value_size)?;
 
         ensure!(
@@ -679,6 +948,11 @@ pub fn ext4_style_crc32c_le(seed: u32, buf: &[u8]) -> u32 {
     crc::crc32::update(seed ^ (!0), &crc::crc32::CASTAGNOLI_TABLE, buf) ^ (!0u32)
 }
 
+/// The kernel's `crc16`, as used by `GDT_CSUM`, which also chains without any inversion.
+pub fn ext4_style_crc16(seed: u16, buf: &[u8]) -> u16 {
+    !crc::crc16::update(!seed, &crc::crc16::USB_TABLE, buf)
+}
+
 #[cfg(test)]
 mod tests {
     use super::ext4_style_crc32c_le;
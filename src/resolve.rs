@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+
+use anyhow::ensure;
+
+use crate::error::{Locate, Location};
+use crate::{not_found, Crypto, Enhanced, Error, FileType, Inode, MetadataCrypto};
+use crate::{ReadAt, SuperBlock};
+
+/// How [`SuperBlock::resolve`] interprets a path.
+#[derive(Clone, Copy, Debug)]
+pub struct Resolve {
+    /// The directory which absolute paths, and symbolic links, start at, and `..` can't leave,
+    /// like after `chroot`. Relative paths start here, too.
+    pub root: u32,
+    /// Follow a symbolic link which is the last component, like `stat`, rather than returning
+    /// the link itself, like `lstat`. Links before the last component are always followed.
+    pub follow: bool,
+    /// How many symbolic links to follow, in total, before deciding they form a loop.
+    pub max_links: u32,
+}
+
+impl Default for Resolve {
+    fn default() -> Self {
+        Resolve {
+            root: 2,
+            follow: true,
+            // as Linux's MAXSYMLINKS
+            max_links: 40,
+        }
+    }
+}
+
+impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
+    /// Find what `path` refers to, following every symbolic link.
+    pub fn stat<P: AsRef<[u8]>>(&mut self, path: P) -> Result<Inode, Error> {
+        self.resolve(path, &Resolve::default())
+    }
+
+    /// Find what `path` refers to, following symbolic links, except for the last component.
+    pub fn lstat<P: AsRef<[u8]>>(&mut self, path: P) -> Result<Inode, Error> {
+        let options = Resolve {
+            follow: false,
+            ..Resolve::default()
+        };
+        self.resolve(path, &options)
+    }
+
+    /// Find what `path` refers to, with `.`, `..` and symbolic links meaning what they would to
+    /// the kernel. Unlike [`resolve_path`](SuperBlock::resolve_path), which takes every
+    /// component literally. Like names, the path is bytes.
+    pub fn resolve<P: AsRef<[u8]>>(&mut self, path: P, options: &Resolve) -> Result<Inode, Error> {
+        let path = path.as_ref();
+        Ok(self.resolve_from(path, options).locate(|| {
+            let path = String::from_utf8_lossy(path);
+            (Location::path(&path), format!("resolving '{}'", path))
+        })?)
+    }
+
+    fn resolve_from(&mut self, path: &[u8], options: &Resolve) -> Result<Inode, anyhow::Error> {
+        let root = self.read_inode(options.root)?;
+        ensure!(
+            FileType::Directory == root.stat.extracted_type,
+            not_found(format!("root <{}> isn't a directory", options.root))
+        );
+
+        // the directories we're in, from the root down, so `..` can go back up them
+        let mut stack = vec![root];
+        let mut remaining = components(path);
+        let mut links = 0;
+
+        while let Some(name) = remaining.pop_front() {
+            let dir = stack.last().expect("the root is never removed");
+            ensure!(
+                FileType::Directory == dir.stat.extracted_type,
+                not_found(format!(
+                    "<{}> isn't a directory, before {}",
+                    dir.number,
+                    String::from_utf8_lossy(&name)
+                ))
+            );
+
+            match name.as_slice() {
+                b"." => continue,
+                b".." => {
+                    if stack.len() > 1 {
+                        stack.pop();
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+
+            let entry = self.dir_entry_named(dir, &name)?;
+            let child = self.read_inode(entry.inode)?;
+
+            let last = remaining.is_empty();
+            if FileType::SymbolicLink != child.stat.extracted_type || (last && !options.follow) {
+                stack.push(child);
+                continue;
+            }
+
+            links += 1;
+            if links > options.max_links {
+                return Err(Error::SymlinkLoop {
+                    location: Location::inode(child.number),
+                }
+                .into());
+            }
+
+            let target = match child.enhance(&mut self.inner, &self.crypto)? {
+                Enhanced::SymbolicLink(target) => target,
+                other => unreachable!("symbolic links are enhanced as such: {:?}", other),
+            };
+            ensure!(
+                !target.is_empty(),
+                not_found(format!(
+                    "symbolic link {} is empty",
+                    String::from_utf8_lossy(&name)
+                ))
+            );
+
+            if target.starts_with(b"/") {
+                stack.truncate(1);
+            }
+            for part in components(&target).into_iter().rev() {
+                remaining.push_front(part);
+            }
+        }
+
+        Ok(stack.pop().expect("the root is never removed"))
+    }
+}
+
+/// A trailing slash means the last component has to be a directory, which following it with
+/// `.` checks, after following it, if it's a link.
+fn components(path: &[u8]) -> VecDeque<Vec<u8>> {
+    let mut parts = path
+        .split(|&c| b'/' == c)
+        .filter(|part| !part.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect::<VecDeque<_>>();
+
+    if path.ends_with(b"/") && !parts.is_empty() {
+        parts.push_back(b".".to_vec());
+    }
+
+    parts
+}
@@ -0,0 +1,101 @@
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::extents::Layout;
+use crate::{Crypto, Error, Inode, MetadataCrypto, ReadAt, SuperBlock};
+
+/// A [`SuperBlock`] which can be cloned, and shared between threads, if its reader can be.
+///
+/// Files opened through it own their reader, so any number can be open at once. Reads take
+/// turns with the underlying reader, which is behind a lock.
+pub struct SharedSuperBlock<R: ReadAt, C: Crypto, M: MetadataCrypto> {
+    fs: Arc<Mutex<SuperBlock<R, C, M>>>,
+}
+
+/// The data of a file, from a [`SharedSuperBlock`]. Reads lock the filesystem only for as long
+/// as they take.
+pub struct FileReader<R: ReadAt, C: Crypto, M: MetadataCrypto> {
+    fs: Arc<Mutex<SuperBlock<R, C, M>>>,
+    layout: Layout,
+    pos: u64,
+}
+
+impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SharedSuperBlock<R, C, M> {
+    pub fn new(fs: SuperBlock<R, C, M>) -> Self {
+        SharedSuperBlock {
+            fs: Arc::new(Mutex::new(fs)),
+        }
+    }
+
+    /// The filesystem, for anything else, like resolving paths or walking. Other threads wait
+    /// for it, including their reads.
+    pub fn lock(&self) -> MutexGuard<'_, SuperBlock<R, C, M>> {
+        lock(&self.fs)
+    }
+
+    /// Read the data from an inode, like [`SuperBlock::open`], but without borrowing anything.
+    pub fn open(&self, inode: &Inode) -> Result<FileReader<R, C, M>, Error> {
+        let layout = inode.layout(&mut self.lock().inner)?;
+        Ok(FileReader {
+            fs: self.fs.clone(),
+            layout,
+            pos: 0,
+        })
+    }
+}
+
+impl<R: ReadAt, C: Crypto, M: MetadataCrypto> FileReader<R, C, M> {
+    /// Read from `pos` in the file, like `Read::read`, but without moving, or needing to own,
+    /// the position.
+    pub fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut fs = lock(&self.fs);
+        let fs = &mut *fs;
+        self.layout.read_at(&mut fs.inner, &fs.crypto, pos, buf)
+    }
+
+    /// Like [`read_at`](Self::read_at), but fill the whole buffer, or fail.
+    pub fn read_exact_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut fs = lock(&self.fs);
+        let fs = &mut *fs;
+        self.layout
+            .read_exact_at(&mut fs.inner, &fs.crypto, pos, buf)
+    }
+}
+
+impl<R: ReadAt, C: Crypto, M: MetadataCrypto> Clone for SharedSuperBlock<R, C, M> {
+    fn clone(&self) -> Self {
+        SharedSuperBlock {
+            fs: self.fs.clone(),
+        }
+    }
+}
+
+impl<R: ReadAt, C: Crypto, M: MetadataCrypto> From<SuperBlock<R, C, M>>
+    for SharedSuperBlock<R, C, M>
+{
+    fn from(fs: SuperBlock<R, C, M>) -> Self {
+        SharedSuperBlock::new(fs)
+    }
+}
+
+impl<R: ReadAt, C: Crypto, M: MetadataCrypto> io::Read for FileReader<R, C, M> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = FileReader::read_at(self, self.pos, buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: ReadAt, C: Crypto, M: MetadataCrypto> io::Seek for FileReader<R, C, M> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.pos = self.layout.seek(self.pos, pos)?;
+        Ok(self.pos)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // a panic part way through a read leaves nothing half-changed that matters
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,286 @@
+use std::cmp::Ordering;
+use std::collections::{HashSet, VecDeque};
+
+use crate::error::{Locate, Location};
+use crate::{assumption_failed, Crypto, DirEntry, Error, FileType, Inode, MetadataCrypto};
+use crate::{ReadAt, SuperBlock};
+
+/// Which entries a [`Walker`] visits first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    /// Everything in a directory, before moving on to its next sibling.
+    DepthFirst,
+    /// Everything at one depth, before anything deeper.
+    BreadthFirst,
+}
+
+/// What a [`Walker`] does after yielding an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnError {
+    /// Finish.
+    Stop,
+    /// Carry on with everything else; the entry, or directory, which failed is skipped.
+    Continue,
+}
+
+/// Iterates over every entry below a directory, yielding its path, entry and inode.
+///
+/// The path is for display; names which aren't UTF-8 are only exact in the entry.
+///
+/// Made with [`SuperBlock::walker`]. The directory itself is yielded first, at depth zero.
+/// Directories are only listed once the iterator moves past them, so
+/// [`skip_current_dir`](Walker::skip_current_dir) can avoid reading them at all.
+pub struct Walker<'a, R: ReadAt, C: Crypto, M: MetadataCrypto> {
+    fs: &'a mut SuperBlock<R, C, M>,
+    order: Order,
+    on_error: OnError,
+    max_depth: Option<usize>,
+    sort: Option<Box<Compare<'a>>>,
+
+    pending: VecDeque<Pending>,
+    /// The directory yielded last, which hasn't been listed yet.
+    unlisted: Option<Listing>,
+    /// Whether the last entry yielded was a directory, even one which won't be listed.
+    yielded_directory: bool,
+    /// The directory the last entry was found in.
+    current: Option<u64>,
+    /// The current depth, of the last entry yielded.
+    depth: usize,
+    /// Directory inodes which have been listed, or are queued to be. A directory can only be in
+    /// one place, so seeing one again means the tree is corrupt.
+    seen: HashSet<u32>,
+    /// Hand out ids to directories, so their remaining entries can be found.
+    next_id: u64,
+    stopped: bool,
+}
+
+type Compare<'a> = dyn FnMut(&DirEntry, &DirEntry) -> Ordering + 'a;
+
+/// An entry waiting to be yielded.
+struct Pending {
+    path: String,
+    entry: DirEntry,
+    depth: usize,
+    /// The id of the directory it's in.
+    parent: u64,
+}
+
+/// A directory which will be listed.
+struct Listing {
+    path: String,
+    inode: Inode,
+    depth: usize,
+}
+
+impl<R: ReadAt, C: Crypto, M: MetadataCrypto> SuperBlock<R, C, M> {
+    /// Iterate over `inode`, and everything below it, naming it `path`. Depth-first, in
+    /// directory order, stopping at the first error, unless configured otherwise.
+    pub fn walker(&mut self, inode: Inode, path: &str) -> Walker<'_, R, C, M> {
+        let entry = DirEntry {
+            inode: inode.number,
+            file_type: inode.stat.extracted_type,
+            name: path
+                .rsplit('/')
+                .find(|part| !part.is_empty())
+                .unwrap_or("/")
+                .as_bytes()
+                .to_vec(),
+        };
+
+        let mut pending = VecDeque::new();
+        pending.push_back(Pending {
+            path: path.to_string(),
+            entry,
+            depth: 0,
+            parent: 0,
+        });
+
+        Walker {
+            fs: self,
+            order: Order::DepthFirst,
+            on_error: OnError::Stop,
+            max_depth: None,
+            sort: None,
+            pending,
+            unlisted: None,
+            yielded_directory: false,
+            current: None,
+            depth: 0,
+            seen: HashSet::new(),
+            next_id: 1,
+            stopped: false,
+        }
+    }
+}
+
+impl<'a, R: ReadAt, C: Crypto, M: MetadataCrypto> Walker<'a, R, C, M> {
+    pub fn order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    /// Don't list directories at `depth`, or deeper; they're still yielded. Zero only yields
+    /// the starting directory.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Visit each directory's entries in this order, instead of the order they're stored in.
+    pub fn sort_by<F>(mut self, compare: F) -> Self
+    where
+        F: FnMut(&DirEntry, &DirEntry) -> Ordering + 'a,
+    {
+        self.sort = Some(Box::new(compare));
+        self
+    }
+
+    /// Visit each directory's entries in byte order of their names.
+    pub fn sort_by_name(self) -> Self {
+        self.sort_by(|a, b| a.name.cmp(&b.name))
+    }
+
+    /// Don't descend into the directory which was just yielded; or, if it wasn't a directory,
+    /// don't yield anything more from the directory it was in.
+    pub fn skip_current_dir(&mut self) {
+        if self.yielded_directory {
+            self.unlisted = None;
+            return;
+        }
+
+        if let Some(current) = self.current {
+            self.pending.retain(|pending| pending.parent != current);
+        }
+    }
+
+    /// The depth of the entry which was just yielded; its children are one deeper.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The filesystem, to read files, or anything else, part way through the walk.
+    pub fn superblock(&mut self) -> &mut SuperBlock<R, C, M> {
+        self.fs
+    }
+
+    /// Read the directory yielded last, queueing its entries.
+    fn list(&mut self, listing: Listing) -> Result<(), Error> {
+        let Listing { path, inode, depth } = listing;
+        let mut entries = inode
+            .read_directory(&mut self.fs.inner, &self.fs.crypto)
+            .locate(|| {
+                let mut location = Location::path(&path);
+                location.inode = Some(inode.number);
+                (location, format!("listing '{}'", path))
+            })?;
+
+        entries.retain(|entry| b"." != &entry.name[..] && b".." != &entry.name[..]);
+        if let Some(sort) = &mut self.sort {
+            entries.sort_by(|a, b| sort(a, b));
+        }
+
+        let parent = self.next_id;
+        self.next_id += 1;
+
+        let children = entries.into_iter().map(|entry| Pending {
+            path: join(&path, &entry.name_lossy()),
+            entry,
+            depth: depth + 1,
+            parent,
+        });
+
+        match self.order {
+            Order::DepthFirst => {
+                let children = children.collect::<Vec<_>>();
+                for child in children.into_iter().rev() {
+                    self.pending.push_front(child);
+                }
+            }
+            Order::BreadthFirst => self.pending.extend(children),
+        }
+
+        Ok(())
+    }
+
+    fn visit(&mut self, pending: Pending) -> Result<(String, DirEntry, Inode), Error> {
+        let Pending {
+            path,
+            mut entry,
+            depth,
+            parent,
+        } = pending;
+        self.current = Some(parent);
+        self.depth = depth;
+
+        let inode = self.fs.read_inode(entry.inode).locate(|| {
+            let mut location = Location::path(&path);
+            location.inode = Some(entry.inode);
+            (location, format!("loading '{}'", path))
+        })?;
+
+        // the directory entry may not have said
+        if FileType::Unknown == entry.file_type {
+            entry.file_type = inode.stat.extracted_type;
+        }
+
+        self.yielded_directory = FileType::Directory == inode.stat.extracted_type;
+        let listable =
+            self.yielded_directory && !matches!(self.max_depth, Some(max) if depth >= max);
+
+        if listable {
+            if !self.seen.insert(inode.number) {
+                let cycle: Result<(), _> = Err(assumption_failed(format!(
+                    "directory <{}> appears more than once",
+                    inode.number
+                )));
+                cycle.locate(|| (Location::path(&path), "walking".to_string()))?;
+            }
+
+            self.unlisted = Some(Listing {
+                path: path.clone(),
+                inode: inode.clone(),
+                depth,
+            });
+        }
+
+        Ok((path, entry, inode))
+    }
+}
+
+impl<'a, R: ReadAt, C: Crypto, M: MetadataCrypto> Iterator for Walker<'a, R, C, M> {
+    type Item = Result<(String, DirEntry, Inode), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stopped {
+            return None;
+        }
+
+        self.yielded_directory = false;
+        let result = match self.unlisted.take().map(|listing| self.list(listing)) {
+            Some(Err(error)) => Err(error),
+            _ => match self.pending.pop_front() {
+                Some(pending) => self.visit(pending),
+                None => return None,
+            },
+        };
+
+        if result.is_err() && OnError::Stop == self.on_error {
+            self.stopped = true;
+        }
+
+        Some(result)
+    }
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.ends_with('/') {
+        format!("{}{}", path, name)
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
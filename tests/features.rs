@@ -0,0 +1,1667 @@
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek};
+use std::ops::Range;
+use std::process::Stdio;
+use std::rc::Rc;
+
+use anyhow::Result;
+use ext4::{NoneCrypto, SuperBlock};
+use tempfile::TempDir;
+
+type Fs = SuperBlock<fs::File, NoneCrypto, NoneCrypto>;
+
+/// The content `img-*.sh` writes with its `pattern` function.
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 7 + 3) % 251) as u8).collect()
+}
+
+fn load(fs: &mut Fs, path: &str) -> Result<ext4::Inode> {
+    let entry = fs.resolve_path(path)?;
+    Ok(fs.load_inode(entry.inode)?)
+}
+
+fn read(fs: &mut Fs, path: &str) -> Result<Vec<u8>> {
+    let inode = load(fs, path)?;
+    let mut buf = Vec::new();
+    fs.open(&inode)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn list(fs: &mut Fs, path: &str) -> Result<Vec<String>> {
+    let inode = load(fs, path)?;
+    match fs.enhance(&inode)? {
+        ext4::Enhanced::Directory(entries) => Ok(entries
+            .iter()
+            .map(|e| e.name_lossy().into_owned())
+            .collect()),
+        other => panic!("{} isn't a directory: {:?}", path, other),
+    }
+}
+
+#[test]
+fn block_map() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("block-map.img")?;
+
+    assert_eq!(pattern(5000), read(&mut fs, "direct")?);
+    assert_eq!(pattern(300_000), read(&mut fs, "double-indirect")?);
+
+    let hole = 70 * 1024 * 1024;
+    let sparse = read(&mut fs, "triple-indirect")?;
+    assert_eq!(hole + 5000, sparse.len());
+    assert!(sparse[..hole].iter().all(|&b| 0 == b));
+    assert_eq!(pattern(5000), &sparse[hole..]);
+
+    let mut names = list(&mut fs, "big-directory")?;
+    assert_eq!(202, names.len());
+    names.sort();
+    assert_eq!(".", names[0]);
+    assert_eq!("file-number-99", names[201]);
+
+    let target = "long-target/".repeat(10);
+    let link = load(&mut fs, "long-symlink")?;
+    match fs.enhance(&link)? {
+        ext4::Enhanced::SymbolicLink(found) => assert_eq!(target.as_bytes(), &found[..]),
+        other => panic!("unexpected symlink: {:?}", other),
+    }
+
+    // written after the filesystem was converted to use extents
+    assert_eq!(
+        &include_bytes!("../scripts/generate-images/img-block-map.sh")[..],
+        &read(&mut fs, "with-extents")?[..]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn inline_data() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("inline-data.img")?;
+
+    assert!(read(&mut fs, "empty-file")?.is_empty());
+    assert_eq!(pattern(10), read(&mut fs, "in-core")?);
+    assert_eq!(pattern(100), read(&mut fs, "in-xattr")?);
+    assert_eq!(pattern(5000), read(&mut fs, "too-big")?);
+
+    let mut names = list(&mut fs, "tiny-directory")?;
+    names.sort();
+    assert_eq!(vec![".", "..", "child"], names);
+    assert_eq!(2, list(&mut fs, "tiny-directory/child")?.len());
+
+    let names = list(&mut fs, "huge-directory")?;
+    assert_eq!(102, names.len());
+
+    let target = "long-target/".repeat(7);
+    let link = load(&mut fs, "long-symlink")?;
+    match fs.enhance(&link)? {
+        ext4::Enhanced::SymbolicLink(found) => assert_eq!(target.as_bytes(), &found[..]),
+        other => panic!("unexpected symlink: {:?}", other),
+    }
+
+    // every other inode in the filesystem is readable, too
+    let root = fs.root()?;
+    fs.walk(&root, "", &mut |_, _, _, _| Ok(true))?;
+
+    Ok(())
+}
+
+#[test]
+fn htree() -> Result<()> {
+    let assets = open_assets()?;
+    for name in &[
+        "htree-legacy.img",
+        "htree-half_md4-unsigned.img",
+        "htree-tea.img",
+    ] {
+        let mut fs = assets.open(name)?;
+        assert_eq!(6002, list(&mut fs, "big-directory")?.len());
+
+        for i in 1..=3000 {
+            for name in &[
+                format!("file-with-a-longer-name-{}", i),
+                format!("ünïcödé-{}", i),
+            ] {
+                let path = format!("big-directory/{}", name);
+                let entry = fs.resolve_path(&path)?;
+                assert_eq!(Some(name.as_str()), entry.name_str());
+            }
+        }
+
+        assert!(fs
+            .resolve_path("big-directory/file-with-a-longer-name-0")
+            .is_err());
+        assert!(fs.resolve_path("big-directory/ünïcödé-3001").is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn htree_reads_little() -> Result<()> {
+    let assets = open_assets()?;
+    let file = fs::File::open(assets.tempdir.path().join("htree-tea.img"))?;
+    let reads = Rc::new(Cell::new(0));
+    let mut fs = SuperBlock::new(CountingReader {
+        inner: file,
+        reads: reads.clone(),
+    })?;
+
+    // the directory is 180+ blocks; the lookup needs the root, a node and a leaf
+    fs.resolve_path("big-directory")?;
+    let before = reads.get();
+    fs.resolve_path("big-directory/ünïcödé-1234")?;
+    let used = reads.get() - before;
+    assert!(used < 20, "{} reads", used);
+
+    Ok(())
+}
+
+#[test]
+fn block_cache() -> Result<()> {
+    let assets = open_assets()?;
+    let walk = |pages| -> Result<(usize, usize, ext4::CacheStats)> {
+        let file = fs::File::open(assets.tempdir.path().join("htree-tea.img"))?;
+        let reads = Rc::new(Cell::new(0));
+        let options = ext4::Options {
+            block_cache: pages,
+            ..strict()
+        };
+        let mut fs = SuperBlock::new_with_options(
+            CountingReader {
+                inner: file,
+                reads: reads.clone(),
+            },
+            &options,
+        )?;
+
+        let mut names = 0;
+        for _ in 0..2 {
+            let root = fs.root()?;
+            for item in fs.walker(root, "/") {
+                item?;
+                names += 1;
+            }
+        }
+        Ok((names, reads.get(), fs.cache_stats()))
+    };
+
+    let (names, uncached, stats) = walk(0)?;
+    assert_eq!(ext4::CacheStats::default(), stats);
+
+    let (same, cached, stats) = walk(4096)?;
+    assert_eq!(names, same);
+    assert!(cached * 4 < uncached, "{} reads, vs. {}", cached, uncached);
+    assert!(stats.hits > stats.misses, "{:?}", stats);
+
+    // too small to hold everything
+    let (same, _, stats) = walk(4)?;
+    assert_eq!(names, same);
+    assert_eq!(4, stats.pages);
+
+    // file contents go around the cache, so are still read in as few goes as the disc allows
+    let read = |pages| -> Result<(usize, ext4::CacheStats, ext4::CacheStats)> {
+        let file = fs::File::open(assets.tempdir.path().join("block-map.img"))?;
+        let reads = Rc::new(Cell::new(0));
+        let options = ext4::Options {
+            block_cache: pages,
+            ..strict()
+        };
+        let mut fs = SuperBlock::new_with_options(
+            CountingReader {
+                inner: file,
+                reads: reads.clone(),
+            },
+            &options,
+        )?;
+
+        let inode = fs.resolve_path("double-indirect")?.inode;
+        let inode = fs.load_inode(inode)?;
+        let before = fs.cache_stats();
+        let mut reader = fs.open(&inode)?;
+        let mut data = vec![0u8; 300_000];
+        let start = reads.get();
+        reader.read_exact_at(0, &mut data)?;
+        let used = reads.get() - start;
+        Ok((used, before, fs.cache_stats()))
+    };
+
+    let (uncached, _, _) = read(0)?;
+    let (cached, before, after) = read(4096)?;
+    assert_eq!(uncached, cached);
+    assert!(cached < 10, "{} reads", cached);
+    // only the indirect blocks, not the 74 pages of data
+    assert!(after.pages - before.pages < 10, "{:?}", after);
+
+    Ok(())
+}
+
+#[test]
+fn short_source() -> Result<()> {
+    let assets = open_assets()?;
+    let path = assets.tempdir.path().join("block-map.img");
+    let mut fs = assets.open("block-map.img")?;
+    let inode = load(&mut fs, "double-indirect")?;
+    let last = fs.extent_map(&inode)?.mappings.pop().expect("mapped");
+    let physical = last.physical.expect("mapped");
+
+    // cut the image part way through a page of the file's last run
+    let cut = (physical + last.len / 2) * 1024 + 100;
+    let there = (last.logical + last.len / 2) * 1024 + 100;
+    let image = fs::read(&path)?;
+    fs::write(&path, &image[..cut as usize])?;
+
+    for pages in &[0, 4096] {
+        let options = ext4::Options {
+            block_cache: *pages,
+            ..strict()
+        };
+        let mut fs = assets.open_with("block-map.img", options)?;
+
+        let mut reader = fs.open(&inode)?;
+        let mut all = vec![0xffu8; 300_000];
+        let error = reader.read_exact_at(0, &mut all).expect_err("short");
+        assert_eq!(io::ErrorKind::UnexpectedEof, error.kind());
+
+        // what's there is read, then the rest is an error, rather than whatever was lying around
+        let mut data = Vec::new();
+        let error = reader.read_to_end(&mut data).expect_err("short");
+        assert_eq!(io::ErrorKind::UnexpectedEof, error.kind());
+        assert_eq!(there as usize, data.len());
+        assert!(data
+            .iter()
+            .enumerate()
+            .all(|(i, &byte)| byte == ((i * 7 + 3) % 251) as u8));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn walker() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("block-map.img")?;
+    let root = fs.root()?;
+
+    let mut walked = 0;
+    fs.walk(&root, "", &mut |_, _, _, _| {
+        walked += 1;
+        Ok(true)
+    })?;
+
+    let paths = fs
+        .walker(root.clone(), "/")
+        .sort_by_name()
+        .map(|item| item.map(|(path, _, _)| path))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(walked, paths.len());
+    assert_eq!("/", paths[0]);
+    assert_eq!("/big-directory", paths[1]);
+    assert_eq!("/big-directory/file-number-1", paths[2]);
+    assert_eq!("/big-directory/file-number-10", paths[3]);
+    assert_eq!("/direct", paths[202]);
+
+    let mut walker = fs
+        .walker(root.clone(), "/")
+        .order(ext4::Order::BreadthFirst);
+    let mut depths = Vec::new();
+    while let Some(item) = walker.next() {
+        let (path, entry, inode) = item?;
+        assert_eq!(entry.inode, inode.number);
+        assert!(path.ends_with(&*entry.name_lossy()));
+        depths.push(walker.depth());
+    }
+    assert_eq!(walked, depths.len());
+    assert!(depths.windows(2).all(|pair| pair[0] <= pair[1]));
+    drop(walker);
+
+    let mut walker = fs.walker(root.clone(), "/").sort_by_name();
+    let mut paths = Vec::new();
+    while let Some(item) = walker.next() {
+        let (path, _, _) = item?;
+        if path.ends_with("file-number-1") {
+            walker.skip_current_dir();
+        }
+        if path == "/lost+found" {
+            walker.skip_current_dir();
+        }
+        paths.push(path);
+    }
+    assert_eq!("/big-directory/file-number-1", paths[2]);
+    assert_eq!("/direct", paths[3]);
+    assert!(paths.contains(&"/lost+found".to_string()));
+    drop(walker);
+
+    let shallow = fs
+        .walker(root.clone(), "/")
+        .max_depth(1)
+        .map(|item| item.map(|(path, _, _)| path))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(walked - 200, shallow.len());
+
+    // the directories at the maximum depth aren't listed, so skipping one leaves its siblings
+    let mut walker = fs.walker(root, "/").max_depth(1).sort_by_name();
+    let mut paths = Vec::new();
+    while let Some(item) = walker.next() {
+        let (path, _, _) = item?;
+        if path == "/big-directory" {
+            walker.skip_current_dir();
+        }
+        paths.push(path);
+    }
+    assert_eq!(shallow.len(), paths.len());
+    assert_eq!("/direct", paths[2]);
+
+    Ok(())
+}
+
+#[test]
+fn walker_deep() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("deep.img")?;
+    let deep = load(&mut fs, "deep")?;
+
+    let mut walker = fs.walker(deep, "deep");
+    let mut deepest = (0, String::new());
+    while let Some(item) = walker.next() {
+        let (path, _, _) = item?;
+        if walker.depth() > deepest.0 {
+            deepest = (walker.depth(), path);
+        }
+    }
+    assert_eq!(3001, deepest.0);
+    assert!(deepest.1.ends_with("/d/d/bottom"), "{}", deepest.1);
+
+    Ok(())
+}
+
+#[test]
+fn walker_cycle() -> Result<()> {
+    let assets = open_assets()?;
+    let path = assets.tempdir.path().join("deep.img");
+
+    // point "loop/ancestor" back at the root, as a directory, breaking the block's checksum
+    let mut fs = assets.open("deep.img")?;
+    let directory = load(&mut fs, "loop")?;
+    let block = fs.extent_map(&directory)?.mappings[0]
+        .physical
+        .expect("mapped");
+    let start = block as usize * 1024;
+    let mut image = fs::read(&path)?;
+    let name = start
+        + image[start..start + 1024]
+            .windows(8)
+            .position(|name| b"ancestor" == name)
+            .expect("present");
+    image[name - 8..name - 4].copy_from_slice(&2u32.to_le_bytes());
+    image[name - 1] = 2;
+    fs::write(&path, &image)?;
+    let lenient = ext4::Options {
+        verify_directory_checksums: false,
+        ..strict()
+    };
+
+    let mut fs = assets.open_with("deep.img", lenient)?;
+    let root = fs.root()?;
+    let failure = |items: &[Result<String, ext4::Error>]| {
+        items
+            .iter()
+            .position(|item| match item {
+                Err(ext4::Error::Corrupt { reason, .. }) => reason.contains("more than once"),
+                _ => false,
+            })
+            .expect("the cycle is reported")
+    };
+
+    // stopping at the cycle, rather than going round it
+    let stopped = fs
+        .walker(root.clone(), "/")
+        .max_depth(3)
+        .sort_by_name()
+        .map(|item| item.map(|(path, _, _)| path))
+        .collect::<Vec<_>>();
+    assert_eq!(stopped.len() - 1, failure(&stopped));
+
+    // ..or carrying on with everything else
+    let continued = fs
+        .walker(root, "/")
+        .max_depth(3)
+        .sort_by_name()
+        .on_error(ext4::OnError::Continue)
+        .map(|item| item.map(|(path, _, _)| path))
+        .collect::<Vec<_>>();
+    let at = failure(&continued);
+    assert_eq!(failure(&stopped), at);
+    assert_eq!(1, continued.iter().filter(|item| item.is_err()).count());
+    assert_eq!("/loop/sibling", continued[at + 1].as_ref().expect("ok"));
+
+    Ok(())
+}
+
+#[test]
+fn symlinks() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("symlinks.img")?;
+
+    let contents = |fs: &mut Fs, inode: &ext4::Inode| -> Result<String> {
+        let mut buf = String::new();
+        fs.open(inode)?.read_to_string(&mut buf)?;
+        Ok(buf)
+    };
+
+    let java = fs.stat("/usr/bin/java")?;
+    assert_eq!("java\n", contents(&mut fs, &java)?);
+    assert_eq!(java.number, fs.stat("opt/java/bin/java")?.number);
+    assert_eq!(
+        java.number,
+        fs.stat("/usr/./bin/../../etc/alternatives/java")?.number
+    );
+
+    let library = fs.stat("/lib/libc.so")?;
+    assert_eq!("library\n", contents(&mut fs, &library)?);
+    assert_eq!(library.number, fs.stat("libc-link")?.number);
+    assert_eq!(library.number, fs.stat("/lib/../lib/libc.so")?.number);
+
+    // `..` after a link is relative to where the link went
+    let usr = fs.stat("/usr")?;
+    assert_eq!(usr.number, fs.stat("/lib/..")?.number);
+    assert_eq!(java.number, fs.stat("/lib/../bin/java")?.number);
+
+    let link = fs.lstat("/usr/bin/java")?;
+    assert_eq!(ext4::FileType::SymbolicLink, link.stat.extracted_type);
+    assert_eq!(fs.resolve_path("usr/bin/java")?.inode, link.number);
+    assert_eq!(
+        ext4::FileType::Directory,
+        fs.lstat("/lib/")?.stat.extracted_type
+    );
+    assert!(fs.stat("/usr/lib/libc.so/").is_err());
+    assert!(fs.stat("/dangling").is_err());
+    assert!(fs.lstat("/dangling").is_ok());
+
+    match fs.stat("/loop-a/anything") {
+        Err(ext4::Error::SymlinkLoop { location }) => {
+            assert_eq!(Some("/loop-a/anything"), location.path.as_deref())
+        }
+        other => panic!("{:?}", other.map(|inode| inode.number)),
+    }
+    assert!(fs.lstat("/loop-a").is_ok());
+
+    let jail = ext4::Resolve {
+        root: fs.stat("/jail")?.number,
+        ..Default::default()
+    };
+    let inside = fs.stat("/jail/file")?;
+    for path in &[
+        "/file",
+        "file",
+        "../file",
+        "escape/file",
+        "up/file",
+        "/up/../escape/file",
+    ] {
+        assert_eq!(inside.number, fs.resolve(path, &jail)?.number, "{}", path);
+    }
+    let outside = fs.stat("/jail/../file")?;
+    assert_eq!("outside\n", contents(&mut fs, &outside)?);
+
+    Ok(())
+}
+
+#[test]
+fn names() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("names.img")?;
+
+    let mut names = list(&mut fs, "odd-names")?;
+    names.sort();
+    assert_eq!(vec![".", "..", "caf\u{fffd}", "link", "plain"], names);
+
+    let latin1 = b"odd-names/caf\xe9";
+    let entry = fs.resolve_path(&latin1[..])?;
+    assert_eq!(b"caf\xe9", &entry.name[..]);
+    assert_eq!(None, entry.name_str());
+    assert_eq!("caf\u{fffd}", entry.name_lossy());
+    #[cfg(unix)]
+    assert_eq!(
+        <OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(b"caf\xe9"),
+        entry.name_os()
+    );
+    assert!(fs.resolve_path("odd-names/caf\u{e9}").is_err());
+
+    let link = fs.lstat("odd-names/link")?;
+    match fs.enhance(&link)? {
+        ext4::Enhanced::SymbolicLink(target) => assert_eq!(b"caf\xe9", &target[..]),
+        other => panic!("unexpected symlink: {:?}", other),
+    }
+    assert_eq!(entry.inode, fs.stat("odd-names/link")?.number);
+    assert_eq!(entry.inode, fs.stat(&latin1[..])?.number);
+
+    // clear one entry's type, as without the filetype feature, and make another's nonsense
+    let path = assets.tempdir.path().join("names.img");
+    let directory = load(&mut fs, "odd-names")?;
+    let map = fs.extent_map(&directory)?;
+    let block = map.mappings[0].physical.expect("mapped");
+    let block_size = map.block_size as usize;
+    let start = block as usize * block_size;
+    let mut image = fs::read(&path)?;
+    for (name, file_type) in &[(&b"plain"[..], 0), (&b"link"[..], 9)] {
+        let at = start
+            + image[start..start + block_size]
+                .windows(name.len())
+                .position(|found| *name == found)
+                .expect("present");
+        image[at - 1] = *file_type;
+    }
+    fs::write(&path, &image)?;
+    let lenient = ext4::Options {
+        verify_directory_checksums: false,
+        ..strict()
+    };
+
+    // ..which doesn't stop the directory being read, and the inode knows
+    let mut fs = assets.open_with("names.img", lenient)?;
+    let plain = fs.resolve_path("odd-names/plain")?;
+    assert_eq!(ext4::FileType::Unknown, plain.file_type);
+    assert_eq!(5, list(&mut fs, "odd-names")?.len());
+    let directory = load(&mut fs, "odd-names")?;
+    let types = fs
+        .walker(directory, "odd-names")
+        .map(|item| item.map(|(_, entry, inode)| (entry.file_type, inode.stat.extracted_type)))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(4, types.len());
+    assert!(types.iter().all(|(hint, actual)| hint == actual));
+
+    Ok(())
+}
+
+#[test]
+fn shared() -> Result<()> {
+    fn shareable<T: Send + Sync + 'static>(_: &T) {}
+
+    let assets = open_assets()?;
+    let fs = ext4::SharedSuperBlock::new(assets.open("block-map.img")?);
+    shareable(&fs);
+
+    let mut readers = Vec::new();
+    for (path, len) in &[
+        ("direct", 5000),
+        ("double-indirect", 300_000),
+        ("direct", 5000),
+    ] {
+        let inode = load(&mut fs.lock(), path)?;
+        readers.push((fs.open(&inode)?, *len));
+    }
+
+    let threads = readers
+        .into_iter()
+        .map(|(mut reader, len)| {
+            std::thread::spawn(move || -> io::Result<()> {
+                let mut buf = Vec::new();
+                reader.read_to_end(&mut buf)?;
+                assert_eq!(pattern(len), buf);
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // the filesystem is still usable, while they read
+    let other = fs.clone();
+    assert_eq!(202, list(&mut other.lock(), "big-directory")?.len());
+
+    for thread in threads {
+        thread.join().expect("reader panicked")?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn positional() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("block-map.img")?;
+    let expected = pattern(300_000);
+
+    let inode = load(&mut fs, "double-indirect")?;
+    let mut reader = fs.open(&inode)?;
+    let mut buf = [0u8; 5000];
+    reader.read_exact_at(123_456, &mut buf)?;
+    assert_eq!(&expected[123_456..128_456], &buf[..]);
+    assert!(reader.read_exact_at(299_000, &mut buf).is_err());
+    assert_eq!(0, reader.read_at(300_000, &mut buf)?);
+
+    // the position is where it was
+    reader.read_exact(&mut buf[..10])?;
+    assert_eq!(&expected[..10], &buf[..10]);
+
+    assert_eq!(299_990, reader.seek(io::SeekFrom::End(-10))?);
+    assert_eq!(299_995, reader.seek(io::SeekFrom::Current(5))?);
+    assert!(reader.seek(io::SeekFrom::Current(-300_000)).is_err());
+    assert_eq!(400_000, reader.seek(io::SeekFrom::Start(400_000))?);
+    assert_eq!(0, reader.read(&mut buf)?);
+
+    let fs = ext4::SharedSuperBlock::new(fs);
+    let reader = fs.open(&inode)?;
+    reader.read_exact_at(200_000, &mut buf)?;
+    assert_eq!(&expected[200_000..205_000], &buf[..]);
+
+    // an image in a file in an image
+    let mut fs = assets.open("nested.img")?;
+    let inode = load(&mut fs, "inner.img")?;
+    let mut inner = SuperBlock::new(fs.open(&inode)?)?;
+    let inode = inner.stat("file")?;
+    let mut contents = String::new();
+    inner.open(&inode)?.read_to_string(&mut contents)?;
+    assert_eq!("nested\n", contents);
+
+    Ok(())
+}
+
+#[test]
+fn casefold() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("casefold.img")?;
+    assert_eq!(Some(ext4::FilenameEncoding::Utf8_12_1), fs.info().encoding);
+    assert!(fs
+        .info()
+        .encoding_flags
+        .contains(ext4::EncodingFlags::STRICT_MODE));
+
+    // the name on disk is preserved
+    assert_eq!(
+        b"readme",
+        &fs.resolve_path("small-directory/README")?.name[..]
+    );
+    assert_eq!(
+        "Ünïcödé-Straße".as_bytes(),
+        &fs.resolve_path("small-directory/üNÏCÖDÉ-STRASSE")?.name[..]
+    );
+
+    // ..and the same through the index
+    for i in 1..=3000 {
+        let entry = fs.resolve_path(format!("big-directory/fILE-nUMBER-{}", i))?;
+        assert_eq!(format!("File-Number-{}", i).into_bytes(), entry.name);
+    }
+    assert!(fs.resolve_path("big-directory/file-number-3001").is_err());
+
+    // U+2C2F is newer than the kernel's tables, which don't fold it; nor index it as we would
+    for directory in &["small-directory", "big-directory"] {
+        let path = format!("{}/Ⱟ-Glagolitic", directory);
+        assert_eq!("Ⱟ-Glagolitic".as_bytes(), &fs.resolve_path(path)?.name[..]);
+        assert!(fs
+            .resolve_path(format!("{}/ⱟ-Glagolitic", directory))
+            .is_err());
+    }
+
+    assert!(fs.resolve_path("case-sensitive/readme").is_ok());
+    assert!(fs.resolve_path("case-sensitive/README").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn casefold_strict_mode() -> Result<()> {
+    let assets = open_assets()?;
+    let path = assets.tempdir.path().join("casefold.img");
+
+    // strict mode stops invalid names being created, so looking one up fails straight away
+    let mut fs = assets.open("casefold.img")?;
+    match fs.resolve_path(b"small-directory/R\xffADME") {
+        Err(ext4::Error::NotFound { reason, .. }) => {
+            assert!(reason.contains("UTF-8"), "{}", reason)
+        }
+        other => panic!("{:?}", other.map(|entry| entry.inode)),
+    }
+
+    // make "readme" invalid, in the small directory's only block, breaking its checksum
+    let directory = load(&mut fs, "small-directory")?;
+    let block = fs.extent_map(&directory)?.mappings[0]
+        .physical
+        .expect("mapped");
+    let start = block as usize * 1024;
+    let mut image = fs::read(&path)?;
+    let name = image[start..start + 1024]
+        .windows(6)
+        .position(|name| b"readme" == name)
+        .expect("present");
+    image[start + name + 1] = 0xff;
+    fs::write(&path, &image)?;
+    let lenient = ext4::Options {
+        verify_directory_checksums: false,
+        ..strict()
+    };
+
+    // ..which is reported when it's compared
+    let mut fs = assets.open_with("casefold.img", lenient)?;
+    match fs.resolve_path("small-directory/README") {
+        Err(ext4::Error::Corrupt { reason, .. }) => assert!(reason.contains("UTF-8"), "{}", reason),
+        other => panic!("{:?}", other.map(|entry| entry.inode)),
+    }
+
+    // outside strict mode, invalid names are just bytes, which have to match exactly
+    assert_eq!(1, image[1024 + 0x27E], "s_encoding_flags");
+    image[1024 + 0x27E] = 0;
+    fs::write(&path, &image)?;
+    let options = ext4::Options {
+        verify_superblock_checksum: false,
+        ..lenient
+    };
+    let mut fs = assets.open_with("casefold.img", options)?;
+    assert!(!fs
+        .info()
+        .encoding_flags
+        .contains(ext4::EncodingFlags::STRICT_MODE));
+    assert!(fs.resolve_path(b"small-directory/r\xffadme").is_ok());
+    assert!(fs.resolve_path(b"small-directory/R\xffADME").is_err());
+    assert!(fs.resolve_path("small-directory/üNÏCÖDÉ-STRASSE").is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn unwritten() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("unwritten.img")?;
+
+    assert_eq!(vec![0u8; 40960], read(&mut fs, "prealloc")?);
+
+    let mut expected = pattern(40960);
+    expected.resize(61440, 0);
+    assert_eq!(expected, read(&mut fs, "mixed")?);
+
+    let inode = load(&mut fs, "mixed")?;
+    let mut reader = fs.open(&inode)?;
+    let extents: Vec<_> = reader
+        .extents()?
+        .iter()
+        .map(|e| (e.part, e.len, e.unwritten))
+        .collect();
+    assert_eq!(vec![(0, 40, false), (40, 20, true)], extents);
+
+    Ok(())
+}
+
+#[test]
+fn extent_map() -> Result<()> {
+    let assets = open_assets()?;
+
+    let mut fs = assets.open("unwritten.img")?;
+    let inode = load(&mut fs, "mixed")?;
+    let map = fs.extent_map(&inode)?;
+    assert_eq!(1024, map.block_size);
+    assert!(!map.inline);
+    assert!(map.tree_blocks.is_empty());
+    let runs: Vec<_> = map
+        .mappings
+        .iter()
+        .map(|m| (m.logical, m.len, m.physical.is_some(), m.unwritten))
+        .collect();
+    assert_eq!(vec![(0, 40, true, false), (40, 20, true, true)], runs);
+
+    // a single, double and triple indirect block lead to the data, after the hole
+    let mut fs = assets.open("block-map.img")?;
+    let inode = load(&mut fs, "triple-indirect")?;
+    let map = fs.extent_map(&inode)?;
+    assert_eq!(3, map.tree_blocks.len());
+    let hole = 70 * 1024;
+    assert_eq!(2, map.mappings.len());
+    assert_eq!((0, hole, None), {
+        let m = &map.mappings[0];
+        (m.logical, m.len, m.physical)
+    });
+    assert_eq!((hole, 5), (map.mappings[1].logical, map.mappings[1].len));
+    let physical = map.mappings[1].physical.expect("mapped");
+    assert!(fs.is_block_allocated(physical)?);
+    for block in &map.tree_blocks {
+        assert!(fs.is_block_allocated(*block)?);
+    }
+
+    // the root, in the inode, indexes four leaves, which between them hold every extent
+    let mut fs = assets.open("fragmented.img")?;
+    let inode = load(&mut fs, "fragmented")?;
+    let map = fs.extent_map(&inode)?;
+    let image = fs::read(assets.tempdir.path().join("fragmented.img"))?;
+    let mut extents = 0;
+    let mut firsts = Vec::new();
+    for block in &map.tree_blocks {
+        assert!(fs.is_block_allocated(*block)?);
+        let node = &image[*block as usize * 1024..][..1024];
+        assert_eq!([0x0a, 0xf3], node[0..2], "magic");
+        assert_eq!([0, 0], node[6..8], "depth");
+        extents += u16::from_le_bytes([node[2], node[3]]);
+        firsts.push(u32::from_le_bytes([node[12], node[13], node[14], node[15]]));
+    }
+    assert_eq!(4, map.tree_blocks.len());
+    assert_eq!(300, extents);
+    assert_eq!(0, firsts[0]);
+    assert!(firsts.windows(2).all(|pair| pair[0] < pair[1]));
+    for mapping in &map.mappings {
+        if let Some(physical) = mapping.physical {
+            let data = physical..physical + mapping.len;
+            assert!(map.tree_blocks.iter().all(|block| !data.contains(block)));
+        }
+    }
+
+    let mut fs = assets.open("inline-data.img")?;
+    let inode = load(&mut fs, "in-core")?;
+    let map = fs.extent_map(&inode)?;
+    assert!(map.inline);
+    assert!(map.mappings.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn fragmented() -> Result<()> {
+    let assets = open_assets()?;
+    let file = fs::File::open(assets.tempdir.path().join("fragmented.img"))?;
+    let reads = Rc::new(Cell::new(0));
+    let mut fs = SuperBlock::new(CountingReader {
+        inner: file,
+        reads: reads.clone(),
+    })?;
+
+    let inode = fs.resolve_path("fragmented")?.inode;
+    let inode = fs.load_inode(inode)?;
+    let map = fs.extent_map(&inode)?;
+    assert_eq!(4, map.tree_blocks.len());
+    assert_eq!(
+        300,
+        map.mappings.iter().filter(|m| m.physical.is_some()).count()
+    );
+
+    // opening, then reading the end, only needs the leaf which covers it
+    let before = reads.get();
+    let mut reader = fs.open(&inode)?;
+    let mut end = [0u8; 10];
+    reader.read_exact_at(598 * 1024, &mut end)?;
+    assert_eq!(b"block 0598", &end);
+    let used = reads.get() - before;
+    assert!(used <= 2, "{} reads", used);
+
+    let mut data = Vec::new();
+    fs.open(&inode)?.read_to_end(&mut data)?;
+    assert_eq!(598 * 1024 + 10, data.len());
+    for (i, block) in data.chunks(1024).enumerate() {
+        let mut expected = if i % 2 == 0 {
+            format!("block {:04}", i).into_bytes()
+        } else {
+            Vec::new()
+        };
+        expected.resize(block.len(), 0);
+        assert_eq!(expected, block, "block {}", i);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn memory_image() -> Result<()> {
+    /// Every path, with the data of every regular file.
+    fn everything<R: ext4::ReadAt>(
+        fs: &mut SuperBlock<R, NoneCrypto, NoneCrypto>,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let root = fs.root()?;
+        let mut found = Vec::new();
+        let mut walker = fs.walker(root, "/");
+        while let Some(item) = walker.next() {
+            let (path, _, inode) = item?;
+            let mut data = Vec::new();
+            if ext4::FileType::RegularFile == inode.stat.extracted_type {
+                walker.superblock().open(&inode)?.read_to_end(&mut data)?;
+            }
+            found.push((path, data));
+        }
+        Ok(found)
+    }
+
+    let assets = open_assets()?;
+    for name in &[
+        "csum-seed.img",
+        "htree-tea.img",
+        "inline-data.img",
+        "block-map.img",
+        "fragmented.img",
+        "journal-replay.img",
+    ] {
+        let path = assets.tempdir.path().join(name);
+        let options = ext4::Options {
+            // replayed blocks can't be shared, so are copied in
+            replay_journal: true,
+            ..strict()
+        };
+        let mut on_disc = SuperBlock::new_with_options(fs::File::open(&path)?, &options)?;
+        let data = fs::read(&path)?;
+        let image = ext4::MemoryImage::new(data.clone());
+        let mut in_memory = SuperBlock::new_with_options(image, &options)?;
+        let mut borrowed = SuperBlock::from_bytes(&data, &options)?;
+
+        let expected = everything(&mut on_disc)?;
+        assert!(expected.len() > 1, "{}", name);
+        assert_eq!(expected, everything(&mut in_memory)?, "{}", name);
+        assert_eq!(expected, everything(&mut borrowed)?, "{}", name);
+        assert_eq!(on_disc.replayed_blocks(), in_memory.replayed_blocks());
+    }
+
+    // found through the index, in blocks straight from memory
+    let image = ext4::MemoryImage::new(fs::read(assets.tempdir.path().join("htree-tea.img"))?);
+    let mut fs = SuperBlock::new_with_options(image, &strict())?;
+    fs.resolve_path("big-directory/ünïcödé-1234")?;
+
+    Ok(())
+}
+
+#[test]
+fn meta_bg() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("meta-bg.img")?;
+    assert!(fs
+        .info()
+        .incompatible_features
+        .contains(ext4::IncompatibleFeature::META_BG));
+    assert_eq!(1, fs.info().first_meta_bg);
+
+    for i in 1..=400 {
+        let path = format!("directory/file-{}", i);
+        assert_eq!(format!("file {}\n", i).into_bytes(), read(&mut fs, &path)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn group_checksums() -> Result<()> {
+    let assets = open_assets()?;
+    for name in &["gdt-csum.img", "csum-seed.img"] {
+        let mut fs = assets.open(name)?;
+        assert_eq!(8, fs.block_group_count());
+
+        for i in 1..=200 {
+            let path = format!("directory/file-{}", i);
+            assert_eq!(format!("file {}\n", i).into_bytes(), read(&mut fs, &path)?);
+        }
+
+        // the reserved inodes, at least
+        let inodes = fs.inode_bitmap(0)?.expect("group 0 is in use");
+        assert_eq!(128 / 8, inodes.len());
+        assert_eq!([0xff, 0xff], inodes[..2]);
+        assert_eq!(None, fs.inode_bitmap(5)?);
+
+        let mut initialised = 0;
+        for group in 0..fs.block_group_count() {
+            if fs.block_bitmap(group)?.is_some() {
+                initialised += 1;
+            }
+        }
+        assert!(initialised > 0 && initialised < 8, "{}", initialised);
+        assert!(fs.inode_bitmap(8).is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "verify-checksums")]
+fn group_checksum_mismatches() -> Result<()> {
+    let assets = open_assets()?;
+    for name in &["gdt-csum.img", "csum-seed.img"] {
+        let path = assets.tempdir.path().join(name);
+        let original = fs::read(&path)?;
+
+        // 1k blocks: the descriptors are in block 2, so this is group 1's free inode count
+        let mut image = original.clone();
+        image[2048 + 64 + 0x0E] ^= 1;
+        fs::write(&path, &image)?;
+        assert!(assets.open(name).is_err());
+
+        let mut image = original.clone();
+        let inode_bitmap = u32::from_le_bytes(image[2048 + 4..2048 + 8].try_into()?);
+        image[inode_bitmap as usize * 1024 + 8] ^= 1;
+        fs::write(&path, &image)?;
+        let mut fs = assets.open(name)?;
+        if "csum-seed.img" == *name {
+            assert!(fs.inode_bitmap(0).is_err());
+        } else {
+            // only metadata_csum covers the bitmaps
+            assert!(fs.inode_bitmap(0).is_ok());
+        }
+        assert!(fs.block_bitmap(0).is_ok());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn lazy_group_descriptors() -> Result<()> {
+    let assets = open_assets()?;
+    let path = assets.tempdir.path().join("meta-bg.img");
+    let mut image = fs::read(&path)?;
+
+    // group 17's free inode count, in the second meta group's descriptors, at the start of
+    // group 16, which has no superblock backup
+    let descriptors = 1 + 16 * 1024;
+    image[descriptors * 1024 + 64 + 0x0E] ^= 1;
+    fs::write(&path, &image)?;
+
+    // nothing reads the broken block until something needs that meta group
+    let mut fs = assets.open("meta-bg.img")?;
+    assert_eq!(64, fs.block_group_count());
+    assert!(fs.root().is_ok());
+    assert!(fs.inode_bitmap(15).is_ok());
+    assert!(fs.inode_bitmap(32).is_ok());
+    assert!(fs.inode_bitmap(17).is_err());
+    assert!(fs.inode_bitmap(16).is_err());
+    assert!(fs.inode_bitmap(64).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn runtime_verification() -> Result<()> {
+    let assets = open_assets()?;
+    let path = assets.tempdir.path().join("csum-seed.img");
+    let original = fs::read(&path)?;
+
+    // group 1's free inode count, as above
+    let mut image = original.clone();
+    image[2048 + 64 + 0x0E] ^= 1;
+    fs::write(&path, &image)?;
+    assert!(assets.open("csum-seed.img").is_err());
+    let options = ext4::Options {
+        verify_group_descriptor_checksums: false,
+        ..strict()
+    };
+    assets.open_with("csum-seed.img", options)?;
+
+    // the root's mtime, in group 0's inode table
+    let mut image = original.clone();
+    let inode_table = u32::from_le_bytes(image[2048 + 8..2048 + 12].try_into()?);
+    image[inode_table as usize * 1024 + 256 + 0x10] ^= 1;
+    fs::write(&path, &image)?;
+    assert!(assets.open("csum-seed.img")?.root().is_err());
+    let options = ext4::Options {
+        verify_inode_checksums: false,
+        ..strict()
+    };
+    assert_eq!(
+        b"file 1\n".to_vec(),
+        read(
+            &mut assets.open_with("csum-seed.img", options)?,
+            "directory/file-1"
+        )?
+    );
+
+    // the superblock's state: not cleanly unmounted, and with errors; the checksum is now wrong
+    for (state, unclean, errors) in &[(0u8, true, false), (3u8, false, true)] {
+        let mut image = original.clone();
+        image[1024 + 0x3A] = *state;
+        fs::write(&path, &image)?;
+        let lenient = ext4::Options {
+            verify_superblock_checksum: false,
+            ..strict()
+        };
+        assert!(assets.open_with("csum-seed.img", lenient).is_err());
+        let options = ext4::Options {
+            accept_unclean: *unclean,
+            accept_errors: *errors,
+            ..lenient
+        };
+        assets.open_with("csum-seed.img", options)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn diagnostics() -> Result<()> {
+    let assets = open_assets()?;
+    let path = assets.tempdir.path().join("csum-seed.img");
+    let mut image = fs::read(&path)?;
+
+    // group 1's descriptor, and the root inode, as above
+    image[2048 + 64 + 0x0E] ^= 1;
+    let inode_table = u32::from_le_bytes(image[2048 + 8..2048 + 12].try_into()?);
+    image[inode_table as usize * 1024 + 256 + 0x10] ^= 1;
+    fs::write(&path, &image)?;
+
+    let lenient = ext4::Options {
+        verify_group_descriptor_checksums: false,
+        verify_inode_checksums: false,
+        ..strict()
+    };
+    let mut fs = assets.open_with("csum-seed.img", lenient)?;
+    let found = fs.diagnostics();
+    assert_eq!(1, found.len(), "{:?}", found);
+    assert_eq!(ext4::Structure::GroupDescriptor, found[0].structure);
+    assert_eq!((Some(1), Some(2)), (found[0].group, found[0].block));
+
+    assert_eq!(b"file 1\n".to_vec(), read(&mut fs, "directory/file-1")?);
+    let found = fs.take_diagnostics();
+    assert_eq!(2, found.len(), "{:?}", found);
+    assert_eq!(ext4::Structure::Inode, found[1].structure);
+    assert_eq!(Some(2), found[1].inode);
+    match found[1].problem {
+        ext4::Problem::ChecksumMismatch { on_disc, computed } => assert_ne!(on_disc, computed),
+        ref other => panic!("{:?}", other),
+    }
+    assert!(fs.diagnostics().is_empty());
+
+    // the root is read for every path, but each mismatch is only recorded once
+    for _ in 0..3 {
+        read(&mut fs, "directory/file-1")?;
+    }
+    let found = fs.diagnostics();
+    assert_eq!(1, found.len(), "{:?}", found);
+    assert_eq!(ext4::Structure::Inode, found[0].structure);
+
+    // verified checks are recorded, too
+    let mut fs = assets.open_with(
+        "csum-seed.img",
+        ext4::Options {
+            verify_inode_checksums: true,
+            ..lenient
+        },
+    )?;
+    assert!(fs.root().is_err());
+    assert_eq!(2, fs.diagnostics().len());
+
+    Ok(())
+}
+
+#[test]
+fn inconsistencies() -> Result<()> {
+    let assets = open_assets()?;
+    let inconsistent = |found: &[ext4::Diagnostic], structure| {
+        assert_eq!(1, found.len(), "{:?}", found);
+        assert_eq!(structure, found[0].structure);
+        match &found[0].problem {
+            ext4::Problem::Inconsistent { reason } => assert!(!reason.is_empty()),
+            other => panic!("{:?}", other),
+        }
+    };
+
+    // a record in the middle of a directory's first block which claims to be too short
+    let mut fs = assets.open("block-map.img")?;
+    let expected = list(&mut fs, "big-directory")?;
+    let directory = load(&mut fs, "big-directory")?;
+    let block = fs.extent_map(&directory)?.mappings[0]
+        .physical
+        .expect("mapped");
+    let path = assets.tempdir.path().join("block-map.img");
+    let mut image = fs::read(&path)?;
+    let start = block as usize * 1024;
+    let at = start
+        + image[start..start + 1024]
+            .windows(b"file-number-1".len())
+            .position(|found| b"file-number-1" == found)
+            .expect("present")
+        - 8;
+    image[at + 4..at + 6].copy_from_slice(&4u16.to_le_bytes());
+    fs::write(&path, &image)?;
+
+    let lenient = ext4::Options {
+        verify_directory_checksums: false,
+        ..strict()
+    };
+    let mut fs = assets.open_with("block-map.img", lenient)?;
+    let names = list(&mut fs, "big-directory")?;
+    // the rest of the block is lost, but the blocks after it are still read
+    assert!(names.len() < expected.len());
+    assert!(names.len() > 2 + 1);
+    assert!(names.iter().all(|name| expected.contains(name)));
+    let found = fs.diagnostics();
+    inconsistent(&found, ext4::Structure::DirectoryBlock);
+    assert_eq!(Some(directory.number), found[0].inode);
+    assert_eq!(Some((at - start) as u64), found[0].offset);
+    let mut fs = assets.open("block-map.img")?;
+    match list(&mut fs, "big-directory").map_err(|e| e.downcast::<ext4::Error>()) {
+        Err(Ok(ext4::Error::Corrupt { .. })) => (),
+        other => panic!("{:?}", other.map(|names| names.len())),
+    }
+
+    // the second of the fragmented file's leaves, which then reads as a hole
+    let mut fs = assets.open("fragmented.img")?;
+    let expected = read(&mut fs, "fragmented")?;
+    let inode = load(&mut fs, "fragmented")?;
+    let leaves = fs.extent_map(&inode)?.tree_blocks;
+    let path = assets.tempdir.path().join("fragmented.img");
+    let mut image = fs::read(&path)?;
+    let leaf = |n: usize| leaves[n] as usize * 1024;
+    let first = |image: &[u8], n: usize| {
+        u32::from_le_bytes(image[leaf(n) + 12..leaf(n) + 16].try_into().unwrap()) as usize
+    };
+    let hole = first(&image, 1) * 1024..first(&image, 2) * 1024;
+    image[leaf(1)] ^= 0xff;
+    fs::write(&path, &image)?;
+
+    let lenient = ext4::Options {
+        verify_extent_checksums: false,
+        ..strict()
+    };
+    let mut fs = assets.open_with("fragmented.img", lenient)?;
+    let found = read(&mut fs, "fragmented")?;
+    assert_eq!(expected.len(), found.len());
+    assert!(found[hole.clone()].iter().all(|&b| 0 == b));
+    assert_eq!(expected[..hole.start], found[..hole.start]);
+    assert_eq!(expected[hole.end..], found[hole.end..]);
+    let found = fs.diagnostics();
+    inconsistent(&found, ext4::Structure::ExtentBlock);
+    assert_eq!(
+        (Some(inode.number), Some(leaves[1])),
+        (found[0].inode, found[0].block)
+    );
+    let mut fs = assets.open("fragmented.img")?;
+    assert!(read(&mut fs, "fragmented").is_err());
+
+    // group 1's inode table, past the end; group 0, with the root, is still fine
+    let path = assets.tempdir.path().join("csum-seed.img");
+    let mut image = fs::read(&path)?;
+    image[2048 + 64 + 8..2048 + 64 + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&path, &image)?;
+
+    let lenient = ext4::Options {
+        verify_group_descriptor_checksums: false,
+        ..strict()
+    };
+    let mut fs = assets.open_with("csum-seed.img", lenient)?;
+    let found = fs
+        .take_diagnostics()
+        .into_iter()
+        .filter(|diagnostic| matches!(diagnostic.problem, ext4::Problem::Inconsistent { .. }))
+        .collect::<Vec<_>>();
+    inconsistent(&found, ext4::Structure::GroupDescriptor);
+    assert_eq!((Some(1), Some(2)), (found[0].group, found[0].block));
+    assert_eq!(b"file 1\n".to_vec(), read(&mut fs, "directory/file-1")?);
+    // verified, the descriptor's checksum, which covers the pointer, fails first
+    assert!(assets.open("csum-seed.img").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn errors() -> Result<()> {
+    let assets = open_assets()?;
+    let mut fs = assets.open("csum-seed.img")?;
+
+    match fs.resolve_path("directory/missing") {
+        Err(ext4::Error::NotFound { location, .. }) => {
+            assert_eq!(Some("directory/missing"), location.path.as_deref());
+        }
+        other => panic!("{:?}", other.map(|entry| entry.inode)),
+    }
+
+    let error = fs.is_inode_allocated(0).expect_err("no inode zero");
+    assert_eq!(io::ErrorKind::NotFound, io::Error::from(error).kind());
+
+    // the root's mtime, as above
+    let path = assets.tempdir.path().join("csum-seed.img");
+    let mut image = fs::read(&path)?;
+    let inode_table = u32::from_le_bytes(image[2048 + 8..2048 + 12].try_into()?);
+    image[inode_table as usize * 1024 + 256 + 0x10] ^= 1;
+    fs::write(&path, &image)?;
+
+    let error = match assets.open("csum-seed.img")?.root() {
+        Ok(_) => panic!("corrupt"),
+        Err(error) => error,
+    };
+    match &error {
+        ext4::Error::ChecksumMismatch {
+            diagnostic,
+            location,
+        } => {
+            assert_eq!(ext4::Structure::Inode, diagnostic.structure);
+            assert_eq!(Some(2), location.inode);
+        }
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(io::ErrorKind::InvalidData, io::Error::from(error).kind());
+
+    Ok(())
+}
+
+#[test]
+fn allocation() -> Result<()> {
+    let assets = open_assets()?;
+    for name in &[
+        "gdt-csum.img",
+        "csum-seed.img",
+        "meta-bg.img",
+        "block-map.img",
+        "inline-data.img",
+    ] {
+        let mut fs = assets.open(name)?;
+        let mut used_inodes = 0;
+        let mut used_blocks = 0;
+        for group in 0..fs.block_group_count() {
+            used_inodes += total(fs.inode_allocation(group)?.allocated());
+
+            let blocks = fs.block_allocation(group)?;
+            let used = total(blocks.allocated());
+            assert_eq!(total(Some(blocks.range())), used + total(blocks.free()));
+            used_blocks += used;
+        }
+
+        // mke2fs and debugfs keep the summary counts exact
+        let info = fs.info();
+        assert_eq!(
+            u64::from(info.inodes_count - info.free_inodes_count),
+            used_inodes,
+            "{}",
+            name
+        );
+        assert_eq!(
+            info.blocks_count - u64::from(info.first_data_block) - info.free_blocks_count,
+            used_blocks,
+            "{}",
+            name
+        );
+
+        let root = fs.root()?;
+        fs.walk(&root, "", &mut |fs, path, inode, _| {
+            assert!(fs.is_inode_allocated(inode.number)?, "{}: {}", name, path);
+            Ok(true)
+        })?;
+        assert!(!fs.is_inode_allocated(fs.info().inodes_count)?);
+        assert!(fs.is_inode_allocated(0).is_err());
+        assert!(fs.is_block_allocated(0)?);
+        assert!(fs.is_block_allocated(fs.info().blocks_count).is_err());
+    }
+
+    // group 1 was never used, but has superblock and descriptor backups
+    let mut fs = assets.open("gdt-csum.img")?;
+    assert_eq!(None, fs.block_bitmap(1)?);
+    let group = fs.block_allocation(1)?;
+    assert_eq!(1025..2049, group.range());
+    let used: Vec<_> = group.allocated().collect();
+    assert_eq!(1, used.len(), "{:?}", used);
+    assert_eq!(1025, used[0].start);
+    assert!(fs.is_block_allocated(1025)?);
+    assert!(!fs.is_block_allocated(2000)?);
+
+    Ok(())
+}
+
+fn total<I: IntoIterator<Item = Range<u64>>>(ranges: I) -> u64 {
+    ranges.into_iter().map(|r| r.end - r.start).sum()
+}
+
+#[test]
+fn journal() -> Result<()> {
+    let assets = open_assets()?;
+    for name in &["journal-v3.img", "journal-v2.img", "journal-plain.img"] {
+        let mut fs = assets.open(name)?;
+        let inode = load(&mut fs, "file")?;
+        let file_block = fs.open(&inode)?.extents()?[0].start;
+
+        let journal = fs.journal()?.expect("created with a journal");
+        assert_eq!((1, 1), (journal.start, journal.sequence));
+
+        // the third transaction never committed
+        let found: Vec<_> = journal
+            .transactions
+            .iter()
+            .map(|t| (t.sequence, t.start))
+            .collect();
+        assert_eq!(vec![(1, 1), (2, 6)], found, "{}", name);
+
+        let first = &journal.transactions[0];
+        let found: Vec<_> = first
+            .blocks
+            .iter()
+            .map(|b| (b.target, b.journal_block, b.escaped))
+            .collect();
+        assert_eq!(
+            vec![(file_block, 2, false), (8000, 3, true), (8001, 4, false)],
+            found
+        );
+        assert_eq!(
+            b"new contents\n",
+            &fs.read_logged_block(&first.blocks[0])?[..13]
+        );
+        let escaped = fs.read_logged_block(&first.blocks[1])?;
+        assert_eq!([0xc0, 0x3b, 0x39, 0x98], escaped[..4]);
+        assert_eq!(pattern(1020), &escaped[4..]);
+        assert_eq!(pattern(1024), fs.read_logged_block(&first.blocks[2])?);
+
+        let second = &journal.transactions[1];
+        assert!(second.blocks.is_empty());
+        assert_eq!(vec![8001], second.revoked);
+    }
+
+    let mut fs = assets.open("journal-v3.img")?;
+    let features = fs.journal()?.expect("journalled").incompatible_features;
+    assert!(features.contains(
+        ext4::JournalIncompatibleFeature::CSUM_V3
+            | ext4::JournalIncompatibleFeature::SIXTY_FOUR_BIT
+    ));
+    let mut fs = assets.open("journal-plain.img")?;
+    let features = fs.journal()?.expect("journalled").incompatible_features;
+    assert_eq!(ext4::JournalIncompatibleFeature::REVOKE, features);
+
+    // cleanly unmounted: there's nothing in the log
+    let mut fs = assets.open("gdt-csum.img")?;
+    let journal = fs.journal()?.expect("created with a journal");
+    assert_eq!(0, journal.start);
+    assert!(journal.transactions.is_empty());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "verify-checksums")]
+fn journal_checksum_mismatches() -> Result<()> {
+    let assets = open_assets()?;
+    let path = assets.tempdir.path().join("journal-v3.img");
+    let original = fs::read(&path)?;
+    let blocks = assets
+        .open("journal-v3.img")?
+        .journal()?
+        .expect("journalled")
+        .transactions
+        .remove(0)
+        .blocks;
+    assert!(blocks.iter().all(|logged| !logged.corrupt));
+
+    // a copy of the file's block which doesn't match its tag is skipped, even when verifying,
+    // and the rest of the transaction is still replayed
+    let mut image = original.clone();
+    image[blocks[0].location as usize * 1024 + 100] ^= 1;
+    fs::write(&path, &image)?;
+    let mut fs = assets.open("journal-v3.img")?;
+    let journal = fs.journal()?.expect("journalled");
+    let corrupt: Vec<_> = journal.transactions[0]
+        .blocks
+        .iter()
+        .map(|logged| logged.corrupt)
+        .collect();
+    assert_eq!(vec![true, false, false], corrupt);
+    let found = fs.diagnostics();
+    assert_eq!(1, found.len(), "{:?}", found);
+    assert_eq!(ext4::Structure::JournalData, found[0].structure);
+    assert_eq!(Some(blocks[0].location), found[0].block);
+
+    let mut fs = assets.replay("journal-v3.img")?;
+    assert_eq!(b"old contents\n".to_vec(), read(&mut fs, "file")?);
+    assert_eq!(1, fs.replayed_blocks());
+
+    // a commit block which doesn't match ends the log; it comes straight after the last block
+    let mut image = original;
+    image[(blocks[2].location as usize + 1) * 1024 + 0x38] ^= 1;
+    fs::write(&path, &image)?;
+    let mut fs = assets.open("journal-v3.img")?;
+    let journal = fs.journal()?.expect("journalled");
+    assert!(journal.transactions.is_empty());
+    let found = fs.diagnostics();
+    assert_eq!(1, found.len(), "{:?}", found);
+    assert_eq!(ext4::Structure::JournalCommit, found[0].structure);
+
+    // ..even when the journal's checksums aren't being verified, so nothing is replayed
+    let unverified = ext4::Options {
+        verify_journal_checksums: false,
+        ..strict()
+    };
+    let mut fs = assets.open_with("journal-v3.img", unverified)?;
+    assert!(fs.journal()?.expect("journalled").transactions.is_empty());
+    let mut fs = assets.open_with(
+        "journal-v3.img",
+        ext4::Options {
+            replay_journal: true,
+            ..unverified
+        },
+    )?;
+    assert_eq!(b"old contents\n".to_vec(), read(&mut fs, "file")?);
+    assert_eq!(0, fs.replayed_blocks());
+
+    Ok(())
+}
+
+#[test]
+fn journal_replay() -> Result<()> {
+    let assets = open_assets()?;
+    for name in &["journal-v3.img", "journal-v2.img", "journal-plain.img"] {
+        // by default, the journal is ignored
+        let mut fs = assets.open(name)?;
+        assert_eq!(b"old contents\n".to_vec(), read(&mut fs, "file")?);
+        assert_eq!(0, fs.replayed_blocks());
+
+        let mut fs = assets.replay(name)?;
+        assert_eq!(b"new contents\n".to_vec(), read(&mut fs, "file")?);
+        // the third block was revoked
+        assert_eq!(2, fs.replayed_blocks());
+    }
+
+    let recover = ext4::IncompatibleFeature::RECOVER;
+    let mut stale = assets.open("journal-replay.img")?;
+    assert!(stale.info().incompatible_features.contains(recover));
+    assert_eq!(b"old contents\n".to_vec(), read(&mut stale, "file")?);
+    assert!(stale.resolve_path("new-directory").is_err());
+
+    let mut fs = assets.replay("journal-replay.img")?;
+    assert!(!fs.info().incompatible_features.contains(recover));
+    assert_eq!(
+        &include_bytes!("../scripts/generate-images/img-journal.sh")[..],
+        &read(&mut fs, "new-directory/added")?[..]
+    );
+    assert!(fs.resolve_path("file").is_err());
+    let mut names = list(&mut fs, "/")?;
+    names.sort();
+    assert_eq!(vec![".", "..", "lost+found", "new-directory"], names);
+
+    // the superblock and bitmaps come from the journal, too: two inodes in, one out
+    assert_eq!(
+        stale.info().free_inodes_count - 1,
+        fs.info().free_inodes_count
+    );
+    let added = fs.resolve_path("new-directory/added")?.inode;
+    assert!(fs.is_inode_allocated(added)?);
+    assert!(!stale.is_inode_allocated(added)?);
+    let removed = stale.resolve_path("file")?.inode;
+    assert!(!fs.is_inode_allocated(removed)?);
+    assert!(fs.diagnostics().is_empty());
+
+    // the superblock's volume name, on disc; the journal's copy is intact
+    let path = assets.tempdir.path().join("journal-replay.img");
+    let mut image = fs::read(&path)?;
+    image[1024 + 0x78] ^= 1;
+    fs::write(&path, &image)?;
+    let options = ext4::Options {
+        checksums: ext4::Checksums::Enabled,
+        verify_superblock_checksum: false,
+        ..Default::default()
+    };
+    let stale = assets.open_with("journal-replay.img", options)?;
+    assert_eq!(1, stale.diagnostics().len());
+    let options = ext4::Options {
+        replay_journal: true,
+        ..options
+    };
+    let fs = assets.open_with("journal-replay.img", options)?;
+    assert!(fs.diagnostics().is_empty(), "{:?}", fs.diagnostics());
+
+    Ok(())
+}
+
+struct CountingReader {
+    inner: fs::File,
+    reads: Rc<Cell<usize>>,
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.set(self.reads.get() + 1);
+        self.inner.read(buf)
+    }
+}
+
+impl io::Seek for CountingReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+struct Assets {
+    tempdir: TempDir,
+}
+
+/// Every check on, whatever the crate's features.
+fn strict() -> ext4::Options {
+    ext4::Options {
+        checksums: ext4::Checksums::Enabled,
+        verify_superblock_checksum: true,
+        verify_group_descriptor_checksums: true,
+        verify_bitmap_checksums: true,
+        verify_inode_checksums: true,
+        verify_extent_checksums: true,
+        verify_directory_checksums: true,
+        verify_xattr_checksums: true,
+        verify_journal_checksums: true,
+        accept_unclean: false,
+        accept_errors: false,
+        ..Default::default()
+    }
+}
+
+fn open_assets() -> Result<Assets> {
+    let tempdir = TempDir::new()?;
+    let mut tar = std::process::Command::new("tar")
+        .args([
+            OsStr::new("-C"),
+            tempdir.path().as_os_str(),
+            OsStr::new("-xz"),
+        ])
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    io::copy(
+        &mut io::Cursor::new(&include_bytes!("../scripts/generate-images/features.tgz")[..]),
+        &mut tar.stdin.as_mut().expect("configured above"),
+    )?;
+
+    assert!(tar.wait()?.success());
+
+    Ok(Assets { tempdir })
+}
+
+impl Assets {
+    fn open(&self, name: &str) -> Result<Fs> {
+        self.open_with(name, strict())
+    }
+
+    fn open_with(&self, name: &str, options: ext4::Options) -> Result<Fs> {
+        let file = fs::File::open(self.tempdir.path().join(name))?;
+        Ok(SuperBlock::new_with_options(file, &options)?)
+    }
+
+    fn replay(&self, name: &str) -> Result<Fs> {
+        let options = ext4::Options {
+            checksums: ext4::Checksums::Enabled,
+            replay_journal: true,
+            ..Default::default()
+        };
+        let file = fs::File::open(self.tempdir.path().join(name))?;
+        Ok(SuperBlock::new_with_options(file, &options)?)
+    }
+}
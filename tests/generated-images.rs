@@ -22,18 +22,15 @@ fn calculate_position_after_seek(
     let new_offset = match position {
         SeekFrom::Current(offset) => current_offset
             .checked_add_signed(offset)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Numeric overflow"))?,
+            .ok_or_else(|| io::Error::other("Numeric overflow"))?,
         SeekFrom::End(offset) => total_size
             .checked_add_signed(offset)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Numeric overflow"))?,
+            .ok_or_else(|| io::Error::other("Numeric overflow"))?,
         SeekFrom::Start(offset) => offset,
     };
 
     if new_offset > total_size {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Out of sub-stream bounds",
-        ));
+        return Err(io::Error::other("Out of sub-stream bounds"));
     }
 
     Ok(new_offset)
@@ -81,7 +78,7 @@ where
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if self.current_offset > self.size {
-            return Err(io::Error::new(io::ErrorKind::Other, "End of stream"));
+            return Err(io::Error::other("End of stream"));
         }
         let size_to_read = std::cmp::min((self.size - self.current_offset) as usize, buf.len());
 
@@ -113,6 +110,18 @@ fn all_types() -> Result<()> {
 
             let part_reader = StreamSlice::new(&mut img, part.first_byte, part.len)?;
             let mut superblock = ext4::SuperBlock::new(part_reader).unwrap();
+
+            let info = superblock.info();
+            assert_eq!(256, info.inode_size);
+            assert_eq!(ext4::ErrorPolicy::Continue, info.errors);
+            assert!(info.state.contains(ext4::FilesystemState::VALID));
+            assert!(info
+                .compatible_features
+                .contains(ext4::CompatibleFeature::EXT_ATTR));
+            assert!(info.free_blocks_count < info.blocks_count);
+            assert!(info.free_inodes_count < info.inodes_count);
+            assert_eq!(superblock.get_uuid(), &info.uuid);
+
             let root = superblock.root().unwrap();
             superblock
                 .walk(&root, "", &mut |fs, path, inode, enhanced| {
@@ -170,7 +179,7 @@ struct Assets {
 fn open_assets() -> Result<Assets> {
     let tempdir = TempDir::new()?;
     let mut tar = std::process::Command::new("tar")
-        .args(&[
+        .args([
             OsStr::new("-C"),
             tempdir.path().as_os_str(),
             OsStr::new("-xz"),